@@ -0,0 +1,456 @@
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use transaction_processor::{error, ClientOrder, DisputeOverdraft, InputFormat, OutputFormat, Precision, RejectFormat, Rounding, Transaction};
+
+///Command line arguments for a run of the transaction-processor. Arguments are parsed by hand
+/// as there are only a handful of them.
+#[derive(Debug, Default, PartialEq)]
+pub struct Args {
+    ///Path to the input file. None or "-" reads from stdin.
+    pub input: Option<OsString>,
+    ///Format of the input. If None, it is guessed from the file extension.
+    pub input_format: Option<InputFormat>,
+    ///Path to write client balances to. None or "-" writes to stdout.
+    pub output: Option<OsString>,
+    ///Format of the client balances written on completion.
+    pub output_format: OutputFormat,
+    ///Order in which client balances are written.
+    pub client_order: ClientOrder,
+    ///Path to write a report of refused records to. None disables the report.
+    pub rejects: Option<OsString>,
+    ///Format of the rejects report. If None, it is guessed from the file extension.
+    pub rejects_format: Option<RejectFormat>,
+    ///Path to a snapshot to continue from. None starts with no clients and an empty ledger.
+    pub load_state: Option<OsString>,
+    ///Path to save a snapshot to once all transactions are applied. None disables the snapshot.
+    pub save_state: Option<OsString>,
+    ///Path to a write-ahead log to record transactions in and recover from. None disables it.
+    pub wal: Option<OsString>,
+    ///Number of worker threads to shard clients across. 1 or less runs serially.
+    pub workers: usize,
+    ///Whether withdrawals may be disputed as well as deposits.
+    pub dispute_withdrawals: bool,
+    ///Whether resolved transactions may be disputed again.
+    pub allow_redisputes: bool,
+    ///Precision of amounts in currencies without their own precision.
+    pub precision: Precision,
+    ///Precision of amounts by currency code.
+    pub currency_precision: BTreeMap<String, Precision>,
+    ///Path to a fee schedule. None charges no fees.
+    pub fees: Option<OsString>,
+    ///Path to a config of client settings, such as overdraft limits. None gives every client the
+    /// default settings.
+    pub client_config: Option<OsString>,
+    ///What happens when a dispute would take a client below its overdraft limit.
+    pub dispute_overdraft: DisputeOverdraft,
+    ///Path to a set of velocity and fraud rules. None checks no rules.
+    pub rules: Option<OsString>,
+    ///Path to write a report of transactions flagged by rules to. None disables the report.
+    pub flags: Option<OsString>,
+    ///Whether to serve transactions over HTTP instead of reading an input.
+    pub serve: bool,
+    ///Address to listen on when serving. None uses the default address.
+    pub listen: Option<String>,
+}
+
+impl Args {
+    ///Parses the given arguments, excluding the program name. Flags may appear anywhere, and
+    /// at most one input path is accepted. A first argument of `serve` selects server mode,
+    /// which takes no input path.
+    pub fn parse<I: IntoIterator<Item = OsString>>(args: I) -> Result<Args, error::Error> {
+        let mut parsed = Args::default();
+        //Currencies without a rounding mode use the run's, which may come later
+        let mut currency_precision = Vec::new();
+        let mut args = args.into_iter().peekable();
+        if args.peek().is_some_and(|arg| arg == "serve") {
+            args.next();
+            parsed.serve = true;
+        }
+        while let Some(arg) = args.next() {
+            match arg.to_str() {
+                Some("--input-format") => {
+                    let format = Self::flag_value(&mut args, "--input-format")?;
+                    parsed.input_format = Some(format.parse()?);
+                }
+                Some("--output") => {
+                    parsed.output = Some(Self::flag_os_value(&mut args, "--output")?);
+                }
+                Some("--output-format") => {
+                    let format = Self::flag_value(&mut args, "--output-format")?;
+                    parsed.output_format = format.parse()?;
+                }
+                Some("--rejects") => {
+                    parsed.rejects = Some(Self::flag_os_value(&mut args, "--rejects")?);
+                }
+                Some("--rejects-format") => {
+                    let format = Self::flag_value(&mut args, "--rejects-format")?;
+                    parsed.rejects_format = Some(format.parse()?);
+                }
+                Some("--load-state") => {
+                    parsed.load_state = Some(Self::flag_os_value(&mut args, "--load-state")?);
+                }
+                Some("--save-state") => {
+                    parsed.save_state = Some(Self::flag_os_value(&mut args, "--save-state")?);
+                }
+                Some("--wal") => {
+                    parsed.wal = Some(Self::flag_os_value(&mut args, "--wal")?);
+                }
+                Some("--workers") => {
+                    let workers = Self::flag_value(&mut args, "--workers")?;
+                    parsed.workers = match workers.parse() {
+                        Ok(workers) if workers > 0 => workers,
+                        _ => return Err(error::Error::InvalidArgument(format!("Invalid value for --workers: {}", workers))),
+                    };
+                }
+                Some("--dispute-withdrawals") => {
+                    parsed.dispute_withdrawals = true;
+                }
+                Some("--allow-redisputes") => {
+                    parsed.allow_redisputes = true;
+                }
+                Some("--scale") => {
+                    let scale = Self::flag_value(&mut args, "--scale")?;
+                    parsed.precision.scale = Self::parse_scale(&scale, "--scale")?;
+                }
+                Some("--rounding") => {
+                    let rounding = Self::flag_value(&mut args, "--rounding")?;
+                    parsed.precision.rounding = rounding.parse()?;
+                }
+                Some("--currency-precision") => {
+                    let value = Self::flag_value(&mut args, "--currency-precision")?;
+                    currency_precision.push(Self::parse_currency_precision(&value)?);
+                }
+                Some("--fees") => {
+                    parsed.fees = Some(Self::flag_os_value(&mut args, "--fees")?);
+                }
+                Some("--client-config") => {
+                    parsed.client_config = Some(Self::flag_os_value(&mut args, "--client-config")?);
+                }
+                Some("--dispute-overdraft") => {
+                    let policy = Self::flag_value(&mut args, "--dispute-overdraft")?;
+                    parsed.dispute_overdraft = policy.parse()?;
+                }
+                Some("--rules") => {
+                    parsed.rules = Some(Self::flag_os_value(&mut args, "--rules")?);
+                }
+                Some("--flags") => {
+                    parsed.flags = Some(Self::flag_os_value(&mut args, "--flags")?);
+                }
+                Some("--listen") => {
+                    parsed.listen = Some(Self::flag_value(&mut args, "--listen")?);
+                }
+                Some("--client-order") => {
+                    let order = Self::flag_value(&mut args, "--client-order")?;
+                    parsed.client_order = order.parse()?;
+                }
+                _ => {
+                    if parsed.input.is_some() {
+                        return Err(error::Error::InvalidArgument(format!("Unexpected argument: {}", arg.to_string_lossy())));
+                    }
+                    parsed.input = Some(arg);
+                }
+            }
+        }
+        for (currency, scale, rounding) in currency_precision {
+            let rounding = rounding.unwrap_or(parsed.precision.rounding);
+            parsed.currency_precision.insert(currency, Precision { scale, rounding });
+        }
+        if parsed.workers > 1 && parsed.wal.is_some() {
+            return Err(error::Error::InvalidArgument(String::from("--wal can't be combined with --workers")));
+        }
+        //The snapshot is saved before the log is removed, so a crash in between would load the new
+        //snapshot and replay the log on top of it, applying every transaction twice
+        if let (Some(_), Some(load_state), Some(save_state)) = (&parsed.wal, &parsed.load_state, &parsed.save_state) {
+            if Self::same_file(load_state, save_state) {
+                return Err(error::Error::InvalidArgument(String::from(
+                    "--wal can't be used when --load-state and --save-state are the same file",
+                )));
+            }
+        }
+        if parsed.workers > 1 && parsed.flags.is_some() {
+            return Err(error::Error::InvalidArgument(String::from("--flags can't be combined with --workers")));
+        }
+        if parsed.serve {
+            if let Some(input) = &parsed.input {
+                return Err(error::Error::InvalidArgument(format!("Unexpected argument: {}", input.to_string_lossy())));
+            }
+            if parsed.output.is_some() || parsed.save_state.is_some() || parsed.flags.is_some() || parsed.workers > 1 {
+                return Err(error::Error::InvalidArgument(String::from(
+                    "--output, --save-state, --flags and --workers can't be combined with serve",
+                )));
+            }
+        } else if parsed.listen.is_some() {
+            return Err(error::Error::InvalidArgument(String::from("--listen can only be used with serve")));
+        }
+        Ok(parsed)
+    }
+
+    ///Whether two paths name the same file, which may not exist yet.
+    fn same_file(first: &OsString, second: &OsString) -> bool {
+        Self::resolve(Path::new(first)) == Self::resolve(Path::new(second))
+    }
+
+    ///Resolves a path through its directory, as the file itself may not exist yet.
+    fn resolve(file_path: &Path) -> PathBuf {
+        if let Ok(resolved) = fs::canonicalize(file_path) {
+            return resolved;
+        }
+        let directory = match file_path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        match (fs::canonicalize(directory), file_path.file_name()) {
+            (Ok(directory), Some(file_name)) => directory.join(file_name),
+            _ => file_path.to_path_buf(),
+        }
+    }
+
+    fn parse_scale(scale: &str, flag: &str) -> Result<u32, error::Error> {
+        match scale.parse() {
+            Ok(scale) if scale <= Precision::MAX_SCALE => Ok(scale),
+            _ => Err(error::Error::InvalidArgument(format!("Invalid value for {}: {}", flag, scale))),
+        }
+    }
+
+    ///Parses `CODE:SCALE` or `CODE:SCALE:MODE`, such as `JPY:0:reject`.
+    fn parse_currency_precision(value: &str) -> Result<(String, u32, Option<Rounding>), error::Error> {
+        let invalid = || error::Error::InvalidArgument(format!("Invalid value for --currency-precision: {}", value));
+        let mut parts = value.split(':');
+        let mut currency = parts.next().map(String::from);
+        Transaction::validate_currency(&mut currency).map_err(|_| invalid())?;
+        let currency = currency.ok_or_else(invalid)?;
+        let scale = Self::parse_scale(parts.next().ok_or_else(invalid)?, "--currency-precision")?;
+        let rounding = parts.next().map(str::parse).transpose()?;
+        if parts.next().is_some() {
+            return Err(invalid());
+        }
+        Ok((currency, scale, rounding))
+    }
+
+    fn flag_os_value<I: Iterator<Item = OsString>>(args: &mut I, flag: &str) -> Result<OsString, error::Error> {
+        args.next()
+            .ok_or_else(|| error::Error::InvalidArgument(format!("Missing value for {}", flag)))
+    }
+
+    fn flag_value<I: Iterator<Item = OsString>>(args: &mut I, flag: &str) -> Result<String, error::Error> {
+        Self::flag_os_value(args, flag)?
+            .into_string()
+            .map_err(|value| error::Error::InvalidArgument(format!("Invalid value for {}: {}", flag, value.to_string_lossy())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cli::Args;
+    use std::ffi::OsString;
+    use transaction_processor::{ClientOrder, DisputeOverdraft, InputFormat, OutputFormat, Precision, RejectFormat, Rounding};
+
+    fn parse(args: &[&str]) -> Result<Args, transaction_processor::Error> {
+        Args::parse(args.iter().map(OsString::from))
+    }
+
+    #[test]
+    fn no_args() {
+        assert_eq!(parse(&[]).unwrap(), Args::default());
+    }
+
+    #[test]
+    fn input_and_format() {
+        let args = parse(&["--input-format", "jsonl", "input.txt"]).unwrap();
+        assert_eq!(args.input, Some(OsString::from("input.txt")));
+        assert_eq!(args.input_format, Some(InputFormat::JsonLines));
+    }
+
+    #[test]
+    fn output_path() {
+        let args = parse(&["--output", "balances.csv", "input.csv"]).unwrap();
+        assert_eq!(args.output, Some(OsString::from("balances.csv")));
+        assert_eq!(args.input, Some(OsString::from("input.csv")));
+    }
+
+    #[test]
+    fn output_format() {
+        let args = parse(&["input.csv", "--output-format", "json"]).unwrap();
+        assert_eq!(args.output_format, OutputFormat::Json);
+        assert_eq!(args.client_order, ClientOrder::Ascending);
+    }
+
+    #[test]
+    fn client_order() {
+        let args = parse(&["--client-order", "first-seen"]).unwrap();
+        assert_eq!(args.client_order, ClientOrder::FirstSeen);
+        assert!(parse(&["--client-order", "random"]).is_err());
+    }
+
+    #[test]
+    fn rejects_report() {
+        let args = parse(&["--rejects", "rejects.txt", "--rejects-format", "jsonl"]).unwrap();
+        assert_eq!(args.rejects, Some(OsString::from("rejects.txt")));
+        assert_eq!(args.rejects_format, Some(RejectFormat::JsonLines));
+    }
+
+    #[test]
+    fn state_paths() {
+        let args = parse(&["--load-state", "yesterday.json", "today.csv", "--save-state", "today.json"]).unwrap();
+        assert_eq!(args.load_state, Some(OsString::from("yesterday.json")));
+        assert_eq!(args.save_state, Some(OsString::from("today.json")));
+        assert_eq!(args.input, Some(OsString::from("today.csv")));
+    }
+
+    #[test]
+    fn write_ahead_log() {
+        let args = parse(&["--wal", "run.wal", "input.csv"]).unwrap();
+        assert_eq!(args.wal, Some(OsString::from("run.wal")));
+        assert!(parse(&["input.csv", "--wal"]).is_err());
+    }
+
+    #[test]
+    fn write_ahead_log_refuses_state_saved_over_itself() {
+        //A crash after saving state over the loaded snapshot and before removing the log would
+        //replay the log on top of the new snapshot
+        assert!(parse(&[
+            "--wal",
+            "run.wal",
+            "--load-state",
+            "state.json",
+            "--save-state",
+            "state.json",
+            "input.csv"
+        ])
+        .is_err());
+        assert!(parse(&[
+            "--wal",
+            "run.wal",
+            "--load-state",
+            "state.json",
+            "--save-state",
+            "./state.json",
+            "input.csv"
+        ])
+        .is_err());
+        assert!(parse(&[
+            "--wal",
+            "run.wal",
+            "--load-state",
+            "monday.json",
+            "--save-state",
+            "tuesday.json",
+            "input.csv"
+        ])
+        .is_ok());
+        assert!(parse(&["--load-state", "state.json", "--save-state", "state.json", "input.csv"]).is_ok());
+    }
+
+    #[test]
+    fn workers() {
+        assert_eq!(parse(&["--workers", "4", "input.csv"]).unwrap().workers, 4);
+        assert!(parse(&["--workers", "0"]).is_err());
+        assert!(parse(&["--workers", "many"]).is_err());
+        assert!(parse(&["--workers", "4", "--wal", "run.wal"]).is_err());
+    }
+
+    #[test]
+    fn dispute_withdrawals() {
+        assert!(!parse(&["input.csv"]).unwrap().dispute_withdrawals);
+        assert!(parse(&["--dispute-withdrawals", "input.csv"]).unwrap().dispute_withdrawals);
+    }
+
+    #[test]
+    fn allow_redisputes() {
+        assert!(!parse(&["input.csv"]).unwrap().allow_redisputes);
+        assert!(parse(&["input.csv", "--allow-redisputes"]).unwrap().allow_redisputes);
+    }
+
+    #[test]
+    fn precision() {
+        let args = parse(&[
+            "--currency-precision",
+            "jpy:0",
+            "--scale",
+            "2",
+            "--rounding",
+            "half-up",
+            "--currency-precision",
+            "GBP:2:reject",
+        ])
+        .unwrap();
+        assert_eq!(
+            args.precision,
+            Precision {
+                scale: 2,
+                rounding: Rounding::HalfUp
+            }
+        );
+        assert_eq!(
+            args.currency_precision["JPY"],
+            Precision {
+                scale: 0,
+                rounding: Rounding::HalfUp
+            }
+        );
+        assert_eq!(
+            args.currency_precision["GBP"],
+            Precision {
+                scale: 2,
+                rounding: Rounding::Reject
+            }
+        );
+        assert_eq!(parse(&[]).unwrap().precision, Precision::default());
+        assert!(parse(&["--scale", "29"]).is_err());
+        assert!(parse(&["--rounding", "up"]).is_err());
+        assert!(parse(&["--currency-precision", "EUR"]).is_err());
+        assert!(parse(&["--currency-precision", "EURO:2"]).is_err());
+        assert!(parse(&["--currency-precision", "EUR:2:reject:more"]).is_err());
+    }
+
+    #[test]
+    fn fees() {
+        assert_eq!(parse(&["input.csv"]).unwrap().fees, None);
+        let args = parse(&["--fees", "fees.json", "input.csv"]).unwrap();
+        assert_eq!(args.fees, Some(OsString::from("fees.json")));
+        assert!(parse(&["input.csv", "--fees"]).is_err());
+    }
+
+    #[test]
+    fn overdrafts() {
+        let args = parse(&["--client-config", "clients.json", "--dispute-overdraft", "cap", "input.csv"]).unwrap();
+        assert_eq!(args.client_config, Some(OsString::from("clients.json")));
+        assert_eq!(args.dispute_overdraft, DisputeOverdraft::Cap);
+        assert_eq!(parse(&["input.csv"]).unwrap().dispute_overdraft, DisputeOverdraft::Allow);
+        assert!(parse(&["--dispute-overdraft", "ignore"]).is_err());
+    }
+
+    #[test]
+    fn rules() {
+        let args = parse(&["--rules", "rules.json", "--flags", "flags.csv", "input.csv"]).unwrap();
+        assert_eq!(args.rules, Some(OsString::from("rules.json")));
+        assert_eq!(args.flags, Some(OsString::from("flags.csv")));
+        assert_eq!(parse(&["input.csv"]).unwrap().rules, None);
+        assert!(parse(&["--flags", "flags.csv", "--workers", "4"]).is_err());
+        assert!(parse(&["serve", "--flags", "flags.csv"]).is_err());
+    }
+
+    #[test]
+    fn serve() {
+        let args = parse(&["serve", "--listen", "127.0.0.1:9000", "--wal", "serve.wal"]).unwrap();
+        assert!(args.serve);
+        assert_eq!(args.listen, Some(String::from("127.0.0.1:9000")));
+        assert_eq!(args.input, None);
+        assert!(!parse(&["./serve"]).unwrap().serve);
+        assert!(parse(&["serve", "input.csv"]).is_err());
+        assert!(parse(&["serve", "--save-state", "state.json"]).is_err());
+        assert!(parse(&["input.csv", "--listen", "127.0.0.1:9000"]).is_err());
+    }
+
+    #[test]
+    fn missing_flag_value() {
+        assert!(parse(&["input.csv", "--input-format"]).is_err());
+    }
+
+    #[test]
+    fn too_many_inputs() {
+        assert!(parse(&["input.csv", "other.csv"]).is_err());
+    }
+}
@@ -0,0 +1,119 @@
+use crate::error;
+use crate::policy::amounts_as_text;
+use crate::transaction::Transaction;
+use log::debug;
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+///Settings of individual clients, loaded from a json config file such as:
+///
+/// ```json
+/// {
+///     "clients": {
+///         "1": { "overdraft": "100.00" },
+///         "2": { "overdraft": "50", "currency_overdraft": { "EUR": "20" } }
+///     }
+/// }
+/// ```
+///
+/// Clients left out of the config have the default settings, with no overdraft.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientConfig {
+    ///Settings by client id.
+    #[serde(default)]
+    pub clients: BTreeMap<u16, ClientSettings>,
+}
+
+///Settings of a single client.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientSettings {
+    ///How far below zero withdrawals may take available funds, in any currency without its own
+    /// limit.
+    #[serde(default)]
+    pub overdraft: Decimal,
+    ///Overdraft limits by currency code, overriding the client's overdraft.
+    #[serde(default)]
+    pub currency_overdraft: BTreeMap<String, Decimal>,
+}
+
+impl ClientSettings {
+    ///How far below zero available funds may go in a currency.
+    pub fn overdraft(&self, currency: &str) -> Decimal {
+        self.currency_overdraft.get(currency).copied().unwrap_or(self.overdraft)
+    }
+}
+
+impl ClientConfig {
+    ///Settings of a client, the default settings if it isn't in the config.
+    pub fn settings(&self, client: u16) -> ClientSettings {
+        self.clients.get(&client).cloned().unwrap_or_default()
+    }
+
+    ///Reads a client config from any reader, refusing unknown fields, negative limits and invalid
+    /// currency codes. Currency codes are upper cased, and amounts may be given as strings or
+    /// numbers.
+    pub fn from_reader<R: io::Read>(reader: R) -> Result<Self, error::Error> {
+        let value: serde_json::Value = serde_json::from_reader(reader)?;
+        let mut config: Self = serde_json::from_value(amounts_as_text(value, &[]))?;
+        for (client, settings) in config.clients.iter_mut() {
+            let invalid = |message: String| error::Error::InvalidArgument(format!("Invalid settings for client {}: {}", client, message));
+            let mut currency_overdraft = BTreeMap::new();
+            for (currency, limit) in &settings.currency_overdraft {
+                let mut code = Some(currency.clone());
+                Transaction::validate_currency(&mut code).map_err(|err| invalid(err.to_string()))?;
+                currency_overdraft.insert(code.ok_or_else(|| invalid(String::from("blank currency")))?, *limit);
+            }
+            settings.currency_overdraft = currency_overdraft;
+            if settings.overdraft.is_sign_negative() || settings.currency_overdraft.values().any(|limit| limit.is_sign_negative()) {
+                return Err(invalid(String::from("overdraft limits can't be negative")));
+            }
+        }
+        Ok(config)
+    }
+
+    ///Reads a client config from a file.
+    pub fn read(file_path: &Path) -> Result<Self, error::Error> {
+        debug!("Loading client config from {:?}", file_path);
+        Self::from_reader(io::BufReader::new(fs::File::open(file_path)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::client_config::ClientConfig;
+    use crate::error;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn configs_are_read_from_json() {
+        let json = r#"{"clients": {"1": {"overdraft": 100}, "2": {"currency_overdraft": {"eur": "20.5"}}}}"#;
+        let config = ClientConfig::from_reader(json.as_bytes()).unwrap();
+        assert_eq!(config.settings(1).overdraft("USD"), dec!(100));
+        assert_eq!(config.settings(2).overdraft("EUR"), dec!(20.5));
+        assert_eq!(config.settings(2).overdraft("USD"), dec!(0));
+        assert_eq!(config.settings(3).overdraft("USD"), dec!(0));
+        assert_eq!(ClientConfig::from_reader("{}".as_bytes()).unwrap(), ClientConfig::default());
+    }
+
+    #[test]
+    fn invalid_configs_are_refused() {
+        let negative = r#"{"clients": {"1": {"overdraft": "-1"}}}"#;
+        assert!(matches!(
+            ClientConfig::from_reader(negative.as_bytes()),
+            Err(error::Error::InvalidArgument(_))
+        ));
+        let currency = r#"{"clients": {"1": {"currency_overdraft": {"EURO": "1"}}}}"#;
+        assert!(matches!(
+            ClientConfig::from_reader(currency.as_bytes()),
+            Err(error::Error::InvalidArgument(_))
+        ));
+        assert!(ClientConfig::from_reader(r#"{"clients": {"x": {}}}"#.as_bytes()).is_err());
+        assert!(ClientConfig::from_reader(r#"{"clients": {"1": {"limit": "1"}}}"#.as_bytes()).is_err());
+    }
+}
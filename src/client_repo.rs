@@ -1,34 +1,119 @@
+use crate::client_config::ClientConfig;
 use crate::error;
 use crate::models::client::Client;
 use log::debug;
 use std::collections::HashMap;
+use std::str::FromStr;
+
+///Order in which clients are listed from the repo.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ClientOrder {
+    ///Ascending client id, so identical input always produces identical output.
+    #[default]
+    Ascending,
+    ///The order in which clients first appeared in the transaction feed.
+    FirstSeen,
+}
+
+impl FromStr for ClientOrder {
+    type Err = error::Error;
+
+    fn from_str(order: &str) -> Result<Self, Self::Err> {
+        match order {
+            "ascending" => Ok(ClientOrder::Ascending),
+            "first-seen" => Ok(ClientOrder::FirstSeen),
+            _ => Err(error::Error::InvalidArgument(format!("Unknown client order: {}", order))),
+        }
+    }
+}
 
 ///Owns a hash map of clients for time constant lookups, storage, and auditing.
 #[derive(Default, Debug)]
 pub struct ClientRepo {
     ///Map of all client accounts. Returns the client and their data.
     pub clients: HashMap<u16, Client>,
+    ///Client ids in the order they were first added.
+    first_seen: Vec<u16>,
+    config: ClientConfig,
 }
 
 impl ClientRepo {
     ///Creates a new client repo.
     pub fn new() -> Self {
-        Self { clients: HashMap::new() }
+        Self { ..Default::default() }
     }
-    ///Adds a new client if not found, or gets an existing client. If locked, an error is returned.
+    ///Adds a new client if not found, or gets an existing client. If closed or locked, an error is returned.
     pub fn get_client(&mut self, id: u16) -> Result<&mut Client, error::Error> {
-        let client = self.clients.entry(id).or_insert_with(|| Client::new(id));
-        if client.locked {
-            return Err(error::Error::Client(format!("Client {} is locked", client.client)));
+        let client = self.entry(id);
+        if client.closed {
+            Err(error::Error::AccountClosed { client: client.client })
+        } else if client.locked {
+            Err(error::Error::AccountLocked { client: client.client })
         } else {
             debug!("[!] Client {} returned from get_client", client.client);
             Ok(client)
         }
     }
+    ///Adds a new client if not found, or gets an existing client for an administrative
+    /// transaction. Locked clients are returned, but closed clients return an error.
+    pub fn get_client_for_admin(&mut self, id: u16) -> Result<&mut Client, error::Error> {
+        let client = self.entry(id);
+        if client.closed {
+            Err(error::Error::AccountClosed { client: client.client })
+        } else {
+            debug!("[!] Client {} returned from get_client_for_admin", client.client);
+            Ok(client)
+        }
+    }
+    ///Adds the house account if not found, or gets it whatever its status, as fees are credited
+    /// to the house account even while it is locked or closed.
+    pub(crate) fn get_house(&mut self, id: u16) -> &mut Client {
+        self.entry(id)
+    }
+    ///Applies the settings of the config to every client, including clients added later.
+    pub fn configure(&mut self, config: ClientConfig) {
+        for client in self.clients.values_mut() {
+            client.configure(config.settings(client.client));
+        }
+        self.config = config;
+    }
+    ///Adds a client, replacing any client with the same id, such as when restoring a snapshot.
+    /// The client is given its settings from the config.
+    pub(crate) fn insert(&mut self, mut client: Client) {
+        client.configure(self.config.settings(client.client));
+        if !self.clients.contains_key(&client.client) {
+            self.first_seen.push(client.client);
+        }
+        self.clients.insert(client.client, client);
+    }
+    fn entry(&mut self, id: u16) -> &mut Client {
+        let (first_seen, config) = (&mut self.first_seen, &self.config);
+        self.clients.entry(id).or_insert_with(|| {
+            first_seen.push(id);
+            let mut client = Client::new(id);
+            client.configure(config.settings(id));
+            client
+        })
+    }
+    ///Lists all clients in the requested order. Clients added to the map directly rather than
+    /// through get_client have no first seen position, so they are listed last in id order.
+    pub fn ordered_clients(&self, order: ClientOrder) -> Vec<&Client> {
+        let mut clients: Vec<&Client> = self.clients.values().collect();
+        match order {
+            ClientOrder::Ascending => clients.sort_by_key(|client| client.client),
+            ClientOrder::FirstSeen => {
+                let positions: HashMap<u16, usize> = self.first_seen.iter().enumerate().map(|(pos, id)| (*id, pos)).collect();
+                clients.sort_by_key(|client| (positions.get(&client.client).copied().unwrap_or(usize::MAX), client.client))
+            }
+        }
+        clients
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::client_repo::ClientOrder;
+    use crate::error::Error;
     use crate::ClientRepo;
 
     #[test]
@@ -46,4 +131,42 @@ mod tests {
         let result = repo.get_client(1).is_ok();
         assert!(!result);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn closed_client_is_refused_as_closed() {
+        let mut repo = ClientRepo::new();
+        let client = repo.get_client(1).unwrap();
+        client.locked = true;
+        client.closed = true;
+        assert!(matches!(repo.get_client(1), Err(Error::AccountClosed { client: 1 })));
+    }
+
+    #[test]
+    fn admin_gets_locked_client() {
+        let mut repo = ClientRepo::new();
+        repo.get_client(1).unwrap().locked = true;
+        assert!(repo.get_client_for_admin(1).is_ok());
+        repo.get_client_for_admin(1).unwrap().closed = true;
+        assert!(repo.get_client_for_admin(1).is_err());
+    }
+
+    #[test]
+    fn ascending_order() {
+        let mut repo = ClientRepo::new();
+        for id in [3, 1, 2] {
+            repo.get_client(id).unwrap();
+        }
+        let ids: Vec<u16> = repo.ordered_clients(ClientOrder::Ascending).iter().map(|c| c.client).collect();
+        assert_eq!(ids, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn first_seen_order() {
+        let mut repo = ClientRepo::new();
+        for id in [3, 1, 3, 2] {
+            repo.get_client(id).unwrap();
+        }
+        let ids: Vec<u16> = repo.ordered_clients(ClientOrder::FirstSeen).iter().map(|c| c.client).collect();
+        assert_eq!(ids, vec![3, 1, 2]);
+    }
+}
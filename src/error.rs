@@ -1,16 +1,275 @@
+use crate::transaction::{DisputeState, TxTypes};
+use rust_decimal::Decimal;
+use serde::Serialize;
+
 ///Errors specific to transaction-processor. Uses thiserror to hide implementation details.
+/// Transaction and client errors are typed so callers can act on them without matching on
+/// message text.
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
-    #[error("CSV Error")]
+    ///A csv record or header that couldn't be read or parsed.
+    #[error("CSV Error: {0}")]
     Csv(#[from] csv::Error),
+    ///A json line or value that couldn't be read or parsed.
+    #[error("JSON Error: {0}")]
+    Json(#[from] serde_json::Error),
+    ///An unknown or malformed command line argument or option.
     #[error("Invalid Argument: {0}")]
     InvalidArgument(String),
-    ///Any issue with a transaction from formatting to violating transaction rules.
-    #[error("Transaction Error: {0}")]
-    Transaction(String),
-    ///Any issue actioning a clients account, from locked to insufficient funds and more.
-    #[error("Client Error: {0}")]
-    Client(String),
-    #[error("IO Error")]
+    ///A record of a type only the engine can journal, such as a fee.
+    #[error("A {tx_type:?} can't be given as input")]
+    ReservedType {
+        ///Type of the transaction.
+        tx_type: TxTypes,
+    },
+    ///A deposit or withdrawal with a negative amount.
+    #[error("Amount must be a positive number, found {amount}")]
+    NegativeAmount {
+        ///The amount given.
+        amount: Decimal,
+    },
+    ///A deposit or withdrawal with more decimal places than its currency allows, when such
+    /// amounts are refused rather than rounded.
+    #[error("Amount {amount} has more than {scale} decimal places")]
+    TooPrecise {
+        ///The amount given.
+        amount: Decimal,
+        ///Decimal places allowed.
+        scale: u32,
+    },
+    ///A deposit or withdrawal without an amount.
+    #[error("A {tx_type:?} requires an amount")]
+    MissingAmount {
+        ///Type of the transaction.
+        tx_type: TxTypes,
+    },
+    ///A dispute, resolve or chargeback with an amount.
+    #[error("A {tx_type:?} shouldn't have an amount")]
+    UnexpectedAmount {
+        ///Type of the transaction.
+        tx_type: TxTypes,
+    },
+    ///An administrative transaction without an audit reason.
+    #[error("A {tx_type:?} requires a reason")]
+    MissingReason {
+        ///Type of the transaction.
+        tx_type: TxTypes,
+    },
+    ///A currency that isn't a three letter code.
+    #[error("Invalid currency {currency:?}, expected a three letter code")]
+    InvalidCurrency {
+        ///The currency given.
+        currency: String,
+    },
+    ///A dispute related transaction in a different currency to the transaction it references.
+    #[error("Transaction {tx} is in {expected}, not {found}")]
+    CurrencyMismatch {
+        ///The referenced transaction id.
+        tx: u32,
+        ///Currency of the referenced transaction.
+        expected: String,
+        ///Currency given.
+        found: String,
+    },
+    ///A deposit or withdrawal reusing a transaction id already in the ledger.
+    #[error("Duplicate transaction {tx}")]
+    DuplicateTransaction {
+        ///The reused transaction id.
+        tx: u32,
+    },
+    ///A withdrawal larger than the clients available funds.
+    #[error("Insufficient funds for client {client}. {requested} requested, {available} available")]
+    InsufficientFunds {
+        ///Id of the client withdrawing.
+        client: u16,
+        ///Amount of the withdrawal.
+        requested: Decimal,
+        ///Funds available at the time of the withdrawal.
+        available: Decimal,
+    },
+    ///A dispute that would take the client's available funds below its overdraft limit, when
+    /// such disputes are refused.
+    #[error("Disputing transaction {tx} would take client {client} below its overdraft limit of {limit}")]
+    OverLimit {
+        ///Id of the client.
+        client: u16,
+        ///The disputed transaction id.
+        tx: u32,
+        ///Overdraft limit of the client in the currency of the transaction.
+        limit: Decimal,
+    },
+    ///A deposit or withdrawal that breaks a rule with the reject action.
+    #[error("Transaction {tx} of client {client} breaks rule {rule}")]
+    RuleViolation {
+        ///Id of the client.
+        client: u16,
+        ///The refused transaction id.
+        tx: u32,
+        ///Name of the rule broken.
+        rule: String,
+    },
+    ///A deposit or withdrawal that breaks a rule with the lock action, locking the client.
+    #[error("Transaction {tx} breaks rule {rule}, locking client {client}")]
+    RuleLocked {
+        ///Id of the locked client.
+        client: u16,
+        ///The refused transaction id.
+        tx: u32,
+        ///Name of the rule broken.
+        rule: String,
+    },
+    ///Any transaction against a locked account.
+    #[error("Client {client} is locked")]
+    AccountLocked {
+        ///Id of the locked client.
+        client: u16,
+    },
+    ///Any transaction against a closed account, including administrative transactions.
+    #[error("Client {client} is closed")]
+    AccountClosed {
+        ///Id of the closed client.
+        client: u16,
+    },
+    ///An unlock of a client that isn't locked.
+    #[error("Client {client} is not locked")]
+    NotLocked {
+        ///Id of the client.
+        client: u16,
+    },
+    ///A dispute related transaction referencing a transaction not in the ledger.
+    #[error("Trying to action transaction {tx}, but that transaction does not exist")]
+    UnknownTransaction {
+        ///The referenced transaction id.
+        tx: u32,
+    },
+    ///A dispute related transaction referencing a transaction belonging to another client.
+    #[error("Client {client} is trying to action a transaction belonging to client {owner}")]
+    ClientMismatch {
+        ///Id of the client on the dispute related transaction.
+        client: u16,
+        ///Id of the client owning the referenced transaction.
+        owner: u16,
+    },
+    ///A dispute related transaction not allowed from the dispute state of the referenced
+    /// transaction, such as a dispute of a disputed transaction or a resolve of a settled one.
+    #[error("Cannot {tx_type:?} a transaction in the {state:?} state")]
+    InvalidDisputeState {
+        ///Type of the dispute related transaction.
+        tx_type: TxTypes,
+        ///Dispute state of the referenced transaction.
+        state: DisputeState,
+    },
+    ///A dispute related transaction referencing a transaction type that can't be disputed.
+    #[error("Trying to dispute or resolve a {tx_type:?}. Only Deposits, and Withdrawals if the policy allows it, are valid targets.")]
+    NotDisputable {
+        ///Type of the referenced transaction.
+        tx_type: TxTypes,
+    },
+    ///A snapshot written in a format this build can't read.
+    #[error("Unsupported snapshot version {version}, expected {}", crate::snapshot::SNAPSHOT_VERSION)]
+    UnsupportedSnapshot {
+        ///Version of the snapshot.
+        version: u32,
+    },
+    ///A write-ahead log record that can't be read, other than a partially written final record.
+    #[error("Write-ahead log is corrupt at line {line}")]
+    CorruptLog {
+        ///Line of the unreadable record.
+        line: u64,
+    },
+    ///Failure reading input or writing output.
+    #[error("IO Error: {0}")]
     Io(#[from] std::io::Error),
 }
+
+impl Error {
+    ///Machine readable reason a record was refused. Errors that aren't caused by a transaction,
+    /// such as a bad record in the input, are reported as malformed.
+    pub fn reason(&self) -> RejectReason {
+        match self {
+            Error::Csv(_)
+            | Error::Json(_)
+            | Error::InvalidArgument(_)
+            | Error::UnsupportedSnapshot { .. }
+            | Error::CorruptLog { .. }
+            | Error::ReservedType { .. }
+            | Error::Io(_) => RejectReason::Malformed,
+            Error::NegativeAmount { .. } | Error::MissingAmount { .. } | Error::UnexpectedAmount { .. } => RejectReason::InvalidAmount,
+            Error::TooPrecise { .. } => RejectReason::TooPrecise,
+            Error::InvalidCurrency { .. } => RejectReason::InvalidCurrency,
+            Error::CurrencyMismatch { .. } => RejectReason::CurrencyMismatch,
+            Error::DuplicateTransaction { .. } => RejectReason::DuplicateTransaction,
+            Error::InsufficientFunds { .. } => RejectReason::InsufficientFunds,
+            Error::OverLimit { .. } => RejectReason::OverLimit,
+            Error::RuleViolation { .. } => RejectReason::RuleViolation,
+            Error::RuleLocked { .. } => RejectReason::LockedByRule,
+            Error::MissingReason { .. } => RejectReason::MissingReason,
+            Error::AccountLocked { .. } => RejectReason::AccountLocked,
+            Error::AccountClosed { .. } => RejectReason::AccountClosed,
+            Error::NotLocked { .. } => RejectReason::NotLocked,
+            Error::UnknownTransaction { .. } => RejectReason::UnknownTransaction,
+            Error::ClientMismatch { .. } => RejectReason::WrongClient,
+            Error::InvalidDisputeState {
+                state: DisputeState::Disputed,
+                ..
+            } => RejectReason::AlreadyDisputed,
+            Error::InvalidDisputeState {
+                state: DisputeState::ChargedBack,
+                ..
+            } => RejectReason::DisputeClosed,
+            Error::InvalidDisputeState {
+                tx_type: TxTypes::Dispute, ..
+            } => RejectReason::DisputeClosed,
+            Error::InvalidDisputeState { .. } => RejectReason::NotDisputed,
+            Error::NotDisputable { .. } => RejectReason::NotDisputable,
+        }
+    }
+}
+
+///Reason codes for refused records, written to the rejects report so they can be filtered,
+/// counted and reprocessed without parsing error messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RejectReason {
+    ///The record could not be parsed into a transaction.
+    Malformed,
+    ///The amount is negative, missing from a deposit or withdrawal, or present on a dispute.
+    InvalidAmount,
+    ///The amount has more decimal places than its currency allows.
+    TooPrecise,
+    ///The currency isn't a three letter code.
+    InvalidCurrency,
+    ///A dispute related transaction gave a different currency to the transaction it referenced.
+    CurrencyMismatch,
+    ///A deposit or withdrawal reused an existing transaction id.
+    DuplicateTransaction,
+    ///A withdrawal was larger than the available funds.
+    InsufficientFunds,
+    ///A dispute would have taken available funds below the client's overdraft limit.
+    OverLimit,
+    ///A deposit or withdrawal broke a velocity or fraud rule.
+    RuleViolation,
+    ///A deposit or withdrawal broke a velocity or fraud rule that locks the client.
+    LockedByRule,
+    ///An administrative transaction had no audit reason.
+    MissingReason,
+    ///The client account is locked after a chargeback or freeze.
+    AccountLocked,
+    ///The client account is closed.
+    AccountClosed,
+    ///An unlock referenced a client that isn't locked.
+    NotLocked,
+    ///A dispute related transaction referenced a transaction not in the ledger.
+    UnknownTransaction,
+    ///A dispute related transaction referenced another client's transaction.
+    WrongClient,
+    ///A dispute referenced a transaction already under dispute.
+    AlreadyDisputed,
+    ///A resolve or chargeback referenced a transaction not under dispute.
+    NotDisputed,
+    ///A dispute related transaction referenced a transaction that was charged back, or a
+    /// dispute referenced a resolved transaction when re-disputes aren't allowed.
+    DisputeClosed,
+    ///A dispute referenced a transaction type that can't be disputed.
+    NotDisputable,
+}
@@ -0,0 +1,91 @@
+use crate::rejects::Rejection;
+use crate::rules::Flag;
+use crate::transaction::TxTypes;
+use rust_decimal::Decimal;
+use std::fmt;
+use std::sync::mpsc;
+
+///Emitted by the Processor for every record it handles, so callers can build audit trails and
+/// notifications without parsing logs.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    ///The transaction was applied to the client.
+    Applied(Outcome),
+    ///The record was refused, with the reason why.
+    Rejected(Rejection),
+    ///The transaction was applied but broke a rule with the flag action, and is reported for
+    /// review. Follows the Applied event of the transaction, once for each rule broken.
+    Flagged(Flag),
+}
+
+///The result of successfully applying a transaction. Holds the change to each of the client's
+/// balances in the currency moved, along with the balances and lock state afterward.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Outcome {
+    ///Client id of the applied transaction.
+    pub client: u16,
+    ///Transaction id of the applied transaction.
+    pub tx: u32,
+    ///Type of the applied transaction.
+    pub tx_type: TxTypes,
+    ///Currency of the balances. The currency of the original transaction for dispute related
+    /// transactions.
+    pub currency: String,
+    ///Fee charged to the client, already included in the changes to its balances.
+    pub fee: Decimal,
+    ///Change to available funds.
+    pub available_delta: Decimal,
+    ///Change to held funds.
+    pub held_delta: Decimal,
+    ///Change to total funds.
+    pub total_delta: Decimal,
+    ///Available funds after the transaction.
+    pub available: Decimal,
+    ///Held funds after the transaction.
+    pub held: Decimal,
+    ///Total funds after the transaction.
+    pub total: Decimal,
+    ///Whether the client is locked after the transaction.
+    pub locked: bool,
+    ///Names of the rules with the flag action that the transaction broke.
+    pub flags: Vec<String>,
+}
+
+///A callback subscribed to Processor events.
+pub type Subscriber = Box<dyn FnMut(&Event) + Send>;
+
+///Callbacks subscribed to Processor events. Subscribers are called in the order they subscribed.
+#[derive(Default)]
+pub struct Subscribers {
+    callbacks: Vec<Subscriber>,
+}
+
+impl Subscribers {
+    ///Adds a callback called with every event.
+    pub fn subscribe<F: FnMut(&Event) + Send + 'static>(&mut self, callback: F) {
+        self.callbacks.push(Box::new(callback));
+    }
+
+    ///Adds a channel receiving a copy of every event. Events are dropped once the receiver is.
+    pub fn subscribe_channel(&mut self) -> mpsc::Receiver<Event> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribe(move |event| {
+            //A closed channel means the receiver is no longer interested.
+            let _ = sender.send(event.clone());
+        });
+        receiver
+    }
+
+    ///Calls every subscriber with the event.
+    pub fn emit(&mut self, event: &Event) {
+        for callback in self.callbacks.iter_mut() {
+            callback(event);
+        }
+    }
+}
+
+impl fmt::Debug for Subscribers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Subscribers").field("count", &self.callbacks.len()).finish()
+    }
+}
@@ -0,0 +1,252 @@
+use crate::error;
+use crate::policy::{amounts_as_text, Precision};
+use crate::transaction::TxTypes;
+use log::debug;
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use serde_json::Value;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+///Fees charged on deposits and withdrawals, each credited to a house account. Loaded from a json
+/// config file such as:
+///
+/// ```json
+/// {
+///     "house": 0,
+///     "deposit": { "percent": "0.5", "min": "0.10", "max": "5.00" },
+///     "withdrawal": {
+///         "tiers": [
+///             { "up_to": "100", "flat": "1.00" },
+///             { "up_to": "1000", "flat": "1.00", "percent": "0.2" },
+///             { "percent": "0.1" }
+///         ],
+///         "max": "10.00"
+///     }
+/// }
+/// ```
+///
+/// Flat amounts and caps are in units of the transaction's currency. The default schedule
+/// charges nothing.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FeeSchedule {
+    ///Client credited with every fee charged.
+    pub house: u16,
+    ///Fee charged on every deposit, if any.
+    #[serde(default)]
+    pub deposit: Option<FeeRule>,
+    ///Fee charged on every withdrawal, if any.
+    #[serde(default)]
+    pub withdrawal: Option<FeeRule>,
+}
+
+///How the fee of a transaction type is worked out. The fee is a flat amount plus a percentage of
+/// the transaction amount, taken from the first tier covering the amount or from the rule itself
+/// if no tier does, then raised to the minimum and lowered to the maximum.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FeeRule {
+    ///Flat amount charged on every transaction.
+    #[serde(default)]
+    pub flat: Decimal,
+    ///Percentage of the amount charged, such as 1.5 for 1.5%.
+    #[serde(default)]
+    pub percent: Decimal,
+    ///Tiers by amount, in ascending order of their upper bound.
+    #[serde(default)]
+    pub tiers: Vec<FeeTier>,
+    ///Smallest fee charged.
+    #[serde(default)]
+    pub min: Option<Decimal>,
+    ///Largest fee charged.
+    #[serde(default)]
+    pub max: Option<Decimal>,
+}
+
+///Fee charged on amounts up to and including a bound.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FeeTier {
+    ///Largest amount the tier covers, or every amount if not given.
+    #[serde(default)]
+    pub up_to: Option<Decimal>,
+    ///Flat amount charged.
+    #[serde(default)]
+    pub flat: Decimal,
+    ///Percentage of the amount charged.
+    #[serde(default)]
+    pub percent: Decimal,
+}
+
+impl FeeSchedule {
+    ///Whether the schedule charges no fees at all, in which case no house account is kept.
+    pub fn is_empty(&self) -> bool {
+        self.deposit.is_none() && self.withdrawal.is_none()
+    }
+
+    ///Fee charged on a transaction, brought to the precision of its currency. Amounts more
+    /// precise than the currency are rounded to the nearest even value even if the precision
+    /// refuses them, as the fee is worked out by the engine rather than given.
+    pub fn fee(&self, tx_type: TxTypes, amount: Decimal, precision: Precision) -> Decimal {
+        let rule = match tx_type {
+            TxTypes::Deposit => self.deposit.as_ref(),
+            TxTypes::Withdrawal => self.withdrawal.as_ref(),
+            _ => None,
+        };
+        rule.map(|rule| precision.round(rule.fee(amount))).unwrap_or_default()
+    }
+
+    ///Checks that no amounts are negative, each minimum is no larger than its maximum, and tiers
+    /// are in ascending order.
+    pub fn check(&self) -> Result<(), error::Error> {
+        for (tx_type, rule) in [("deposit", &self.deposit), ("withdrawal", &self.withdrawal)] {
+            if let Some(rule) = rule {
+                rule.check()
+                    .map_err(|err| error::Error::InvalidArgument(format!("Invalid {} fee: {}", tx_type, err)))?;
+            }
+        }
+        Ok(())
+    }
+
+    ///Reads a fee schedule from any reader, refusing unknown fields and invalid rules. Amounts
+    /// may be given as strings or numbers.
+    pub fn from_reader<R: io::Read>(reader: R) -> Result<Self, error::Error> {
+        let value: Value = serde_json::from_reader(reader)?;
+        let schedule: Self = serde_json::from_value(amounts_as_text(value, &["house"]))?;
+        schedule.check()?;
+        Ok(schedule)
+    }
+
+    ///Reads a fee schedule from a file.
+    pub fn read(file_path: &Path) -> Result<Self, error::Error> {
+        debug!("Loading fee schedule from {:?}", file_path);
+        Self::from_reader(io::BufReader::new(fs::File::open(file_path)?))
+    }
+}
+
+impl FeeRule {
+    ///Fee charged on an amount, before rounding.
+    pub fn fee(&self, amount: Decimal) -> Decimal {
+        let (flat, percent) = self
+            .tiers
+            .iter()
+            .find(|tier| tier.up_to.is_none_or(|up_to| amount <= up_to))
+            .map(|tier| (tier.flat, tier.percent))
+            .unwrap_or((self.flat, self.percent));
+        let fee = flat + amount * percent / Decimal::ONE_HUNDRED;
+        let fee = self.min.map_or(fee, |min| fee.max(min));
+        self.max.map_or(fee, |max| fee.min(max))
+    }
+
+    fn check(&self) -> Result<(), String> {
+        let amounts = [Some(self.flat), Some(self.percent), self.min, self.max]
+            .into_iter()
+            .chain(self.tiers.iter().flat_map(|tier| [tier.up_to, Some(tier.flat), Some(tier.percent)]));
+        if amounts.flatten().any(|amount| amount.is_sign_negative()) {
+            return Err(String::from("amounts can't be negative"));
+        }
+        if let (Some(min), Some(max)) = (self.min, self.max) {
+            if min > max {
+                return Err(format!("min {} is larger than max {}", min, max));
+            }
+        }
+        let bounds: Vec<Option<Decimal>> = self.tiers.iter().map(|tier| tier.up_to).collect();
+        //Only the last tier may be unbounded, and bounds must increase
+        let ordered = bounds.windows(2).all(|pair| match pair {
+            [Some(lower), Some(upper)] => lower < upper,
+            [Some(_), None] => true,
+            _ => false,
+        });
+        if !ordered {
+            return Err(String::from("tiers must be in ascending order of up_to, with only the last unbounded"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error;
+    use crate::fees::{FeeRule, FeeSchedule, FeeTier};
+    use crate::policy::{Precision, Rounding};
+    use crate::transaction::TxTypes;
+    use rust_decimal::Decimal;
+    use rust_decimal_macros::dec;
+
+    fn tier(up_to: Option<Decimal>, flat: Decimal, percent: Decimal) -> FeeTier {
+        FeeTier { up_to, flat, percent }
+    }
+
+    #[test]
+    fn flat_and_percentage_fees_are_capped() {
+        let rule = FeeRule {
+            flat: dec!(0.25),
+            percent: dec!(1),
+            min: Some(dec!(0.5)),
+            max: Some(dec!(2)),
+            ..Default::default()
+        };
+        assert_eq!(rule.fee(dec!(10)), dec!(0.5));
+        assert_eq!(rule.fee(dec!(100)), dec!(1.25));
+        assert_eq!(rule.fee(dec!(1000)), dec!(2));
+    }
+
+    #[test]
+    fn tiers_cover_amounts_up_to_their_bound() {
+        let rule = FeeRule {
+            flat: dec!(9),
+            tiers: vec![tier(Some(dec!(100)), dec!(1), dec!(0)), tier(Some(dec!(1000)), dec!(0), dec!(2))],
+            ..Default::default()
+        };
+        assert_eq!(rule.fee(dec!(100)), dec!(1));
+        assert_eq!(rule.fee(dec!(500)), dec!(10));
+        //Amounts beyond every tier fall back to the rule itself
+        assert_eq!(rule.fee(dec!(5000)), dec!(9));
+    }
+
+    #[test]
+    fn fees_follow_currency_precision() {
+        let schedule = FeeSchedule {
+            house: 0,
+            withdrawal: Some(FeeRule {
+                percent: dec!(1),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let reject = Precision {
+            scale: 2,
+            rounding: Rounding::Reject,
+        };
+        assert_eq!(schedule.fee(TxTypes::Withdrawal, dec!(12.345), reject), dec!(0.12));
+        assert_eq!(schedule.fee(TxTypes::Deposit, dec!(12.345), reject), dec!(0));
+        assert_eq!(schedule.fee(TxTypes::Dispute, dec!(12.345), reject), dec!(0));
+    }
+
+    #[test]
+    fn schedules_are_read_from_json() {
+        let json = r#"{"house": 9, "withdrawal": {"tiers": [{"up_to": "100", "flat": "1"}, {"percent": 0.5}], "max": "10"}}"#;
+        let schedule = FeeSchedule::from_reader(json.as_bytes()).unwrap();
+        assert_eq!(schedule.house, 9);
+        assert!(schedule.deposit.is_none());
+        assert_eq!(schedule.fee(TxTypes::Withdrawal, dec!(1000), Precision::default()), dec!(5));
+        assert!(FeeSchedule::default().is_empty());
+
+        let unordered = r#"{"house": 0, "deposit": {"tiers": [{"flat": "1"}, {"up_to": "100"}]}}"#;
+        assert!(matches!(
+            FeeSchedule::from_reader(unordered.as_bytes()),
+            Err(error::Error::InvalidArgument(_))
+        ));
+        let negative = r#"{"house": 0, "deposit": {"flat": "-1"}}"#;
+        assert!(matches!(
+            FeeSchedule::from_reader(negative.as_bytes()),
+            Err(error::Error::InvalidArgument(_))
+        ));
+        let inverted = r#"{"house": 0, "deposit": {"min": "2", "max": "1"}}"#;
+        assert!(FeeSchedule::from_reader(inverted.as_bytes()).is_err());
+        assert!(FeeSchedule::from_reader(r#"{"deposit": {}}"#.as_bytes()).is_err());
+        assert!(FeeSchedule::from_reader(r#"{"house": 0, "refund": {}}"#.as_bytes()).is_err());
+    }
+}
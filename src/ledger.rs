@@ -0,0 +1,211 @@
+use crate::transaction::{Transaction, TxTypes};
+use log::trace;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+///A single event in the ledger journal. Deposits and withdrawals reference their own transaction
+/// id, while disputes, resolves and chargebacks reference the transaction they act on.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JournalEntry {
+    ///Position of the entry in the journal, starting at 0.
+    pub sequence: u64,
+    ///Type of the transaction recorded.
+    #[serde(rename = "type")]
+    pub tx_type: TxTypes,
+    ///Client the transaction was applied to.
+    pub client: u16,
+    ///Id of the transaction, or of the original transaction for dispute related entries.
+    pub tx: u32,
+    ///Amount moved by the transaction. Dispute related entries use the original amount, and
+    /// administrative entries move nothing.
+    pub amount: Decimal,
+    ///Currency of the amount moved. Dispute related entries use the currency of the original
+    /// transaction, and administrative entries have none.
+    #[serde(default)]
+    pub currency: Option<String>,
+    ///Audit reason given with administrative transactions.
+    pub reason: Option<String>,
+    ///House account credited with the amount of a fee entry.
+    #[serde(default)]
+    pub house: Option<u16>,
+}
+
+///Record of every applied transaction. Deposits and withdrawals are kept by transaction id so
+/// disputes can find them, and every applied transaction, including disputes, resolves,
+/// chargebacks and administrative transactions, is appended to a journal that is never modified.
+/// The journal allows the full lifecycle of any transaction to be reconstructed after the fact.
+#[derive(Default, Debug)]
+pub struct Ledger {
+    transactions: HashMap<u32, Transaction>,
+    journal: Vec<JournalEntry>,
+    lifecycles: HashMap<u32, Vec<usize>>,
+    held: HashMap<u32, Decimal>,
+}
+
+impl Ledger {
+    ///Creates an empty ledger.
+    pub fn new() -> Self {
+        Self { ..Default::default() }
+    }
+
+    ///Gets a deposit or withdrawal by transaction id.
+    pub fn get(&self, tx_id: u32) -> Option<&Transaction> {
+        self.transactions.get(&tx_id)
+    }
+
+    ///Whether a deposit or withdrawal with the transaction id exists.
+    pub fn contains(&self, tx_id: u32) -> bool {
+        self.transactions.contains_key(&tx_id)
+    }
+
+    ///Deposits and withdrawals, by transaction id.
+    pub fn transactions(&self) -> &HashMap<u32, Transaction> {
+        &self.transactions
+    }
+
+    ///Every applied transaction in the order it was applied.
+    pub fn journal(&self) -> &[JournalEntry] {
+        &self.journal
+    }
+
+    ///Every journal entry for a transaction in the order applied, starting with the deposit or
+    /// withdrawal itself and followed by any disputes, resolves and chargebacks against it.
+    pub fn lifecycle(&self, tx_id: u32) -> Vec<&JournalEntry> {
+        self.lifecycles
+            .get(&tx_id)
+            .map(|entries| entries.iter().map(|index| &self.journal[*index]).collect())
+            .unwrap_or_default()
+    }
+
+    ///Amount held by a dispute of a transaction, if the dispute was capped at the client's
+    /// overdraft limit and held less than the transaction amount.
+    pub fn held(&self, tx_id: u32) -> Option<Decimal> {
+        self.held.get(&tx_id).copied()
+    }
+
+    ///Records the amount a capped dispute held, or clears it once the dispute is settled.
+    pub(crate) fn set_held(&mut self, tx_id: u32, held: Option<Decimal>) {
+        match held {
+            Some(amount) => self.held.insert(tx_id, amount),
+            None => self.held.remove(&tx_id),
+        };
+    }
+
+    ///Rebuilds a ledger from its transactions, the amounts held by capped disputes, and the
+    /// journal, such as from a snapshot.
+    pub(crate) fn restore(transactions: Vec<Transaction>, held: HashMap<u32, Decimal>, journal: Vec<JournalEntry>) -> Self {
+        let mut lifecycles: HashMap<u32, Vec<usize>> = HashMap::new();
+        for (index, entry) in journal.iter().enumerate().filter(|(_, entry)| !entry.tx_type.is_admin()) {
+            lifecycles.entry(entry.tx).or_default().push(index);
+        }
+        Self {
+            transactions: transactions.into_iter().map(|tx| (tx.tx_id, tx)).collect(),
+            journal,
+            lifecycles,
+            held,
+        }
+    }
+
+    pub(crate) fn get_mut(&mut self, tx_id: u32) -> Option<&mut Transaction> {
+        self.transactions.get_mut(&tx_id)
+    }
+
+    ///Stores an applied deposit or withdrawal and journals it.
+    ///# Panics
+    /// Amounts are checked before transactions reach the ledger, so a missing amount means
+    /// screening is failing and the system must panic.
+    pub(crate) fn insert(&mut self, transaction: Transaction) {
+        let amount = transaction.amount.expect("System error, ledger received a transaction with no amount");
+        self.lifecycles.entry(transaction.tx_id).or_default().push(self.journal.len());
+        let currency = Some(transaction.currency().to_owned());
+        self.append(transaction.tx_type, transaction.client, transaction.tx_id, amount, currency, None);
+        self.transactions.insert(transaction.tx_id, transaction);
+    }
+
+    ///Journals an applied dispute, resolve or chargeback against the original transaction, in
+    /// the original transaction's currency.
+    pub(crate) fn record(&mut self, tx_type: TxTypes, client: u16, tx_id: u32, amount: Decimal) {
+        let currency = self.transactions.get(&tx_id).map(|tx| tx.currency().to_owned());
+        self.lifecycles.entry(tx_id).or_default().push(self.journal.len());
+        self.append(tx_type, client, tx_id, amount, currency, None);
+    }
+
+    ///Journals a fee charged to a client on a deposit or withdrawal, as part of that transaction's
+    /// lifecycle. The fee is credited to the house account.
+    pub(crate) fn record_fee(&mut self, client: u16, tx_id: u32, fee: Decimal, currency: &str, house: u16) {
+        self.lifecycles.entry(tx_id).or_default().push(self.journal.len());
+        self.append(TxTypes::Fee, client, tx_id, fee, Some(currency.to_owned()), None);
+        if let Some(entry) = self.journal.last_mut() {
+            entry.house = Some(house);
+        }
+    }
+
+    ///Journals an applied administrative transaction with its audit reason. Administrative
+    /// transactions act on the client rather than a transaction, so they aren't part of any
+    /// transaction's lifecycle.
+    pub(crate) fn record_admin(&mut self, tx_type: TxTypes, client: u16, tx_id: u32, reason: Option<String>) {
+        self.append(tx_type, client, tx_id, Decimal::ZERO, None, reason);
+    }
+
+    fn append(&mut self, tx_type: TxTypes, client: u16, tx_id: u32, amount: Decimal, currency: Option<String>, reason: Option<String>) {
+        let entry = JournalEntry {
+            sequence: self.journal.len() as u64,
+            tx_type,
+            client,
+            tx: tx_id,
+            amount,
+            currency,
+            reason,
+            house: None,
+        };
+        trace!("Journaling {:?}", entry);
+        self.journal.push(entry);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ledger::Ledger;
+    use crate::test_support::transaction;
+    use crate::transaction::TxTypes;
+    use rust_decimal::Decimal;
+
+    #[test]
+    fn lifecycle_follows_original_transaction() {
+        let mut ledger = Ledger::new();
+        ledger.insert(transaction(TxTypes::Deposit, 1, 1, Some(Decimal::ONE)));
+        ledger.insert(transaction(TxTypes::Deposit, 1, 2, Some(Decimal::ONE)));
+        ledger.record(TxTypes::Dispute, 1, 1, Decimal::ONE);
+        ledger.record(TxTypes::Resolve, 1, 1, Decimal::ONE);
+
+        let lifecycle: Vec<(u64, TxTypes)> = ledger.lifecycle(1).iter().map(|entry| (entry.sequence, entry.tx_type)).collect();
+        assert_eq!(lifecycle, vec![(0, TxTypes::Deposit), (2, TxTypes::Dispute), (3, TxTypes::Resolve)]);
+        assert_eq!(ledger.lifecycle(2).len(), 1);
+        assert!(ledger.lifecycle(3).is_empty());
+        assert_eq!(ledger.journal().len(), 4);
+        assert_eq!(ledger.journal()[2].currency.as_deref(), Some("USD"));
+    }
+
+    #[test]
+    fn only_deposits_and_withdrawals_are_stored() {
+        let mut ledger = Ledger::new();
+        ledger.insert(transaction(TxTypes::Deposit, 1, 1, Some(Decimal::ONE)));
+        ledger.record(TxTypes::Dispute, 1, 1, Decimal::ONE);
+        ledger.record_admin(TxTypes::Unlock, 1, 1, Some(String::from("reviewed")));
+        assert!(ledger.contains(1));
+        assert_eq!(ledger.lifecycle(1).len(), 2);
+        assert_eq!(ledger.journal()[2].reason.as_deref(), Some("reviewed"));
+        assert_eq!(ledger.transactions().len(), 1);
+    }
+
+    #[test]
+    fn fees_are_part_of_the_lifecycle() {
+        let mut ledger = Ledger::new();
+        ledger.insert(transaction(TxTypes::Deposit, 1, 1, Some(Decimal::ONE)));
+        ledger.record_fee(1, 1, Decimal::ONE, "USD", 0);
+        let lifecycle: Vec<(TxTypes, Option<u16>)> = ledger.lifecycle(1).iter().map(|entry| (entry.tx_type, entry.house)).collect();
+        assert_eq!(lifecycle, vec![(TxTypes::Deposit, None), (TxTypes::Fee, Some(0))]);
+        assert_eq!(ledger.transactions().len(), 1);
+    }
+}
@@ -0,0 +1,130 @@
+#![warn(missing_docs)]
+/*!
+# Introduction
+The 'transaction-processor' is a toy financial transactions engine designed to explore the
+intricacies of handling five different transaction types:
+
+- Deposits
+- Withdrawals
+- Disputes
+- Resolves
+- Chargebacks
+
+along with administrative unlocks, freezes and closes of client accounts.
+
+This library holds the engine so it can be driven in-process. The transaction-processor binary
+is a thin command line interface over it.
+
+# Usage
+Transactions come from any [`TransactionSource`], such as a csv [`TransactionFeed`] or a
+[`JsonLinesFeed`]. The [`Processor`] owns the client accounts and applies transactions to them,
+reporting each refused record as a [`Rejection`]. State is kept between calls, so transactions
+can also be applied one at a time with [`Processor::apply`] and balances queried in between.
+Callers can subscribe to an [`Event`] for every record handled, either through a callback with
+[`Processor::subscribe`] or a channel with [`Processor::subscribe_channel`].
+Clients hold separate [`Balances`] for every currency they transact in, read with
+[`Client::balances`]. Balances can be written out with the [`writer`] module.
+A [`FeeSchedule`] in the [`Policy`] charges fees on deposits and withdrawals, crediting them to a
+house account, and a [`ClientConfig`] gives clients overdraft limits. A [`RuleSet`] checks
+deposits and withdrawals against velocity and fraud rules before they are applied.
+
+With the `async` feature, enabled by default, a `StreamingProcessor` applies transactions from an
+async stream and can be read from other tasks while it runs.
+
+```
+use rust_decimal::Decimal;
+use transaction_processor::{DisputeState, Processor, RejectReason, Transaction, TransactionFeed, TxTypes, DEFAULT_CURRENCY};
+
+let input = "type,client,tx,amount\n\
+             deposit,1,1,2.0\n\
+             withdrawal,1,2,5.0\n";
+let feed = TransactionFeed::from_reader(String::from("example"), Box::new(input.as_bytes()))?;
+let mut processor = Processor::new();
+let mut rejected = Vec::new();
+processor.handle_transactions(feed, |rejection| {
+    rejected.push(rejection.reason);
+    Ok(())
+})?;
+assert_eq!(rejected, vec![RejectReason::InsufficientFunds]);
+
+let withdrawal = Transaction {
+    tx_type: TxTypes::Withdrawal,
+    client: 1,
+    tx_id: 3,
+    amount: Some(Decimal::new(5, 1)),
+    reason: None,
+    currency: None,
+    state: DisputeState::Settled,
+};
+let outcome = processor.apply(withdrawal)?;
+assert_eq!(outcome.available_delta, Decimal::new(-5, 1));
+assert_eq!(outcome.available, Decimal::new(15, 1));
+assert_eq!(processor.client(1).unwrap().balances(DEFAULT_CURRENCY).total(), Decimal::new(15, 1));
+# Ok::<(), transaction_processor::Error>(())
+```
+
+Details of the rules engine are omitted from this documentation[^note].
+
+[^note]: For detailed discussion on the theory, motivation, and rules around this engine,
+see the README
+*/
+///Settings of individual clients, such as overdraft limits.
+pub mod client_config;
+///Storage and lookup of client accounts.
+pub mod client_repo;
+///Errors and reason codes for refused records.
+pub mod error;
+///Events emitted by the engine for every record handled.
+pub mod events;
+///Fees charged on deposits and withdrawals.
+pub mod fees;
+///Record of every applied transaction.
+pub mod ledger;
+///Clients and transactions.
+pub mod models;
+///Configurable rules of the engine.
+pub mod policy;
+///The engine applying transactions to clients.
+pub mod processor;
+///Reporting of records refused by the engine.
+pub mod rejects;
+///Velocity and fraud rules applied before transactions.
+pub mod rules;
+///Parallel processing sharded by client id.
+pub mod sharded;
+///Saving and loading engine state between runs.
+pub mod snapshot;
+///Async streaming engine for tokio services.
+#[cfg(feature = "async")]
+pub mod streaming;
+///Fixtures shared by the unit tests.
+#[cfg(test)]
+mod test_support;
+///Sources of transactions, such as csv and json lines.
+pub mod transaction_feed;
+///Write-ahead logging and crash recovery.
+pub mod wal;
+///Output of client balances.
+pub mod writer;
+
+use crate::models::transaction;
+
+pub use crate::client_config::{ClientConfig, ClientSettings};
+pub use crate::client_repo::{ClientOrder, ClientRepo};
+pub use crate::error::{Error, RejectReason};
+pub use crate::events::{Event, Outcome};
+pub use crate::fees::{FeeRule, FeeSchedule, FeeTier};
+pub use crate::ledger::{JournalEntry, Ledger};
+pub use crate::models::client::{Balances, Client};
+pub use crate::models::transaction::{DisputeState, Transaction, TxTypes, DEFAULT_CURRENCY};
+pub use crate::policy::{DisputeOverdraft, Policy, Precision, Rounding};
+pub use crate::processor::Processor;
+pub use crate::rejects::{RejectFormat, RejectWriter, Rejection};
+pub use crate::rules::{utc_day, Check, Clock, Flag, Rule, RuleAction, RuleSet};
+pub use crate::sharded::ShardedProcessor;
+pub use crate::snapshot::Snapshot;
+#[cfg(feature = "async")]
+pub use crate::streaming::StreamingProcessor;
+pub use crate::transaction_feed::{open_transaction_source, InputFormat, JsonLinesFeed, ResumeFrom, TransactionFeed, TransactionSource};
+pub use crate::wal::{LogRecord, WriteAheadLog};
+pub use crate::writer::OutputFormat;
@@ -1,14 +1,9 @@
 #![warn(missing_docs)]
 /*!
 # Introduction
-The 'transaction-processor' is a toy financial transactions engine designed to explore the
-intricacies of handling five different transaction types:
-
-- Deposits
-- Withdrawals
-- Disputes
-- Resolves
-- Chargebacks
+Command line interface for the transaction-processor engine. Reads a file or stream of
+transactions, applies them through the engine, and writes the resulting client balances.
+The engine itself lives in the transaction_processor library so it can be embedded elsewhere.
 
 # Input
 transaction-processor takes a .csv file of transactions as input in the following form:
@@ -23,13 +18,100 @@ transaction-processor takes a .csv file of transactions as input in the followin
 
 See the test-inputs directory for sample input.
 
+An optional `currency` column gives the three letter currency code of deposits and withdrawals,
+which are in USD if it is left out. Disputes, resolves and chargebacks use the currency of the
+transaction they reference.
+
+Amounts are rounded to 4 decimal places with banker's rounding by default. `--scale` and
+`--rounding bankers|half-up|truncate|reject` change this for the run, and
+`--currency-precision CODE:SCALE[:MODE]` sets the precision of a single currency.
+
+Transactions may also be given as json lines, one object per line with the same fields. The
+format is chosen from the file extension (.jsonl, .ndjson or .json), or with `--input-format`:
+
+```ignore
+{"type": "deposit", "client": 1, "tx": 1, "amount": 1.0}
+```
+
 # Output
 transaction-processor will output to stdout in csv form as well following this format:
 
-|   client   |available |   held   |  total   |
-|------------|----------|----------|----------|
-| 1          |    3.4   |   1.0503 |   3.4503 |
-| 2          |    2.1   |   0.0    |   2.1    |
+|   client   | currency |available |   held   |  total   |  locked  |   fees   |
+|------------|----------|----------|----------|----------|----------|----------|
+| 1          |   USD    |    3.4   |   1.0503 |   3.4503 |  false   |    0     |
+| 2          |   USD    |    2.1   |   0.0    |   2.1    |  false   |    0     |
+
+The `currency` and `fees` columns are always written, even without currencies or a fee schedule,
+so the output schema doesn't depend on the options used.
+
+Each client is written with one row per currency it holds, along with the fees it has paid.
+
+`--fees <path>` loads a json fee schedule charging deposits and withdrawals a flat amount, a
+percentage or a tiered fee, with optional minimum and maximum caps. Fees are taken from the
+client's available funds and credited to the schedule's house account, which is written with the
+other clients.
+
+`--client-config <path>` loads json settings by client id, giving clients an overdraft limit that
+withdrawals may take available funds down to. `--dispute-overdraft allow|cap|reject` decides
+whether a dispute that would take a client below its limit holds the full amount, holds only what
+the limit allows, or is refused.
+
+`--rules <path>` loads json velocity and fraud rules checked against every deposit and withdrawal
+before it is applied: how many of a client's recent transactions may be withdrawals, the largest
+single amount, the largest daily volume, and withdrawals of most of a recent deposit. A broken rule
+rejects the transaction, locks the client, or flags the transaction for review. `--flags <path>`
+writes flagged transactions to a csv report once the input is handled. Rules only see activity
+from the current run, as it isn't saved with `--save-state`. A run over a file counts its whole
+input as one day's volume, while the server counts volumes by UTC date. `--flags` can't be
+combined with `--workers`.
+
+Use `--output-format json` for a json array or `--output-format jsonl` for one json object per
+line. Amounts are written as strings in json so no precision is lost to floats.
+
+Output goes to stdout unless `--output <path>` is given. Files are written to a temporary file
+and renamed into place, so a crashed run never leaves a partially written file behind.
+
+Records refused by the engine can be written to a report with `--rejects <path>`. Each row holds
+the line number, client, tx id, type, a reason code such as `insufficient_funds`, and a message.
+The report is csv unless the path ends in .jsonl or .ndjson, or `--rejects-format jsonl` is given.
+An existing report is overwritten, so a run recovering from `--wal` starts its report from zero.
+
+Only deposits can be disputed unless `--dispute-withdrawals` is given. A disputed withdrawal
+holds the withdrawn funds, a resolve finalizes the withdrawal and a chargeback credits it back.
+A resolved transaction can't be disputed again unless `--allow-redisputes` is given.
+
+Runs can be chained with `--load-state <path>` and `--save-state <path>`. The saved state holds
+every client and the full ledger, so balances carry over and disputes can reference earlier
+runs' transactions. State is saved atomically once all transactions are applied.
+
+With `--wal <path>`, every transaction is recorded in a write-ahead log before it is applied.
+If a run dies part way, running it again with the same input and flags replays the log and
+resumes the input after the last logged record. Records refused before the crash are reported
+again, so the rejects report of the resumed run is complete. The log is removed once a run completes.
+`--wal` can't be used when `--load-state` and `--save-state` name the same file, as a crash after
+saving the state and before removing the log would replay the log over the new state.
+
+Use `--workers <n>` to apply transactions across n threads, each owning the clients whose id
+falls in its shard. Per-client order is preserved and results are identical to a serial run.
+`--workers` can't be combined with `--wal`.
+
+Clients are written in ascending client id order, so the same input always produces the same
+output. Use `--client-order first-seen` to write them in the order they first appeared instead.
+
+# Serve
+`transaction-processor serve` runs the engine as an HTTP server on 127.0.0.1:8080, or the
+address given with `--listen`, until it is stopped:
+
+- `POST /transactions` applies the csv lines in the body, with or without a header row, or
+  json lines if the content type is `application/json` or `application/x-ndjson`. The response
+  holds the number of transactions applied and the rejections, numbered by line of the body.
+  Bodies over 16 MiB are refused with a 413. If the rejects report or write-ahead log fails part
+  way, the 500 returned still holds the number applied before the failure.
+- `GET /clients` returns every client's balances in ascending client id order.
+- `GET /clients/{id}` returns a single client's balances, in the same form as `GET /clients`.
+
+`--load-state`, `--wal`, `--rejects` and the dispute flags work as they do for a file. With
+`--wal` every transaction is logged, and a restarted server replays them all.
 
 # Usage
 To run the program, run the following:
@@ -37,53 +119,168 @@ To run the program, run the following:
 ```ignore
 $ cargo run --test input-file.csv < output-file.csv
 ```
+If no input file or `-` is given, transactions are read from stdin:
+
+```ignore
+$ zcat input-file.csv.gz | cargo run -- - > output-file.csv
+$ cat input-file.jsonl | cargo run -- --input-format jsonl > output-file.csv
+```
+To run it as a server:
+
+```ignore
+$ cargo run -- serve --listen 127.0.0.1:8080
+$ curl -X POST --data-binary $'deposit,1,1,2.0\nwithdrawal,1,2,1.5\n' localhost:8080/transactions
+$ curl localhost:8080/clients/1
+```
 Details of the rules engine are omitted from this documentation[^note].
 
 [^note]: For detailed discussion on the theory, motivation, and rules around this engine,
 see the README
 
 */
-mod client_repo;
-mod error;
-mod models;
-mod processor;
-mod transaction_feed;
-mod writer;
-
-use crate::models::transaction;
-use crate::processor::Processor;
-use crate::transaction_feed::TransactionFeed;
-use crate::writer::write_client_data;
-use client_repo::ClientRepo;
+mod cli;
+#[cfg(feature = "serve")]
+mod serve;
+
+use crate::cli::Args;
+use env_logger::Env;
 use log::error;
 use std::env;
 use std::ffi::OsString;
-use env_logger::Env;
+use std::io;
+use std::path::Path;
+use std::sync::mpsc;
+use transaction_processor::rules::write_flags;
+use transaction_processor::writer::{write_client_data, write_client_data_to_path};
+use transaction_processor::{
+    error, open_transaction_source, ClientConfig, Event, FeeSchedule, Flag, Policy, Processor, RejectWriter, Rejection, ResumeFrom, RuleSet,
+    ShardedProcessor, Snapshot, WriteAheadLog,
+};
 
-///Gets the first command line argument and returns it, or an error::Error if no arg is found.
-fn get_first_arg() -> Result<OsString, error::Error> {
-    match env::args_os().nth(1) {
-        None => Err(error::Error::InvalidArgument(String::from("No argument found"))),
-        Some(file_path) => Ok(file_path),
+///Parses command line args, excluding the program name.
+fn get_args() -> Result<Args, error::Error> {
+    Args::parse(env::args_os().skip(1))
+}
+
+///Creates a processor continuing from a snapshot, if one was loaded.
+fn processor_from(snapshot: Option<Snapshot>, policy: Policy) -> Processor {
+    match snapshot {
+        Some(snapshot) => Processor::from_snapshot(snapshot, policy),
+        None => Processor::with_policy(policy),
     }
 }
 
-///Gets the input filename from command line args and uses it to instantiate a feed of
-/// Transaction items from the csv input.
-fn get_transaction_feed() -> Result<TransactionFeed, error::Error> {
-    let file_path = match get_first_arg() {
-        Err(err) => {
-            error!("[!] Fatal error parsing command line args: {:?}", err);
+///Replays any records left in a write-ahead log, reporting those refused again, and logs every
+/// transaction to it from now on. Returns the input offset of the last replayed record read from
+/// the input, if there were any.
+fn open_write_ahead_log<F>(processor: &mut Processor, file_path: &Path, on_reject: F) -> Result<Option<u64>, error::Error>
+where
+    F: FnMut(Rejection) -> Result<(), error::Error>,
+{
+    let (wal, records) = WriteAheadLog::open(file_path)?;
+    let offset = processor.recover(records, on_reject)?;
+    processor.set_write_ahead_log(wal);
+    Ok(offset)
+}
+
+///Serves transactions over HTTP until the process is stopped. The write-ahead log is kept, so a
+/// restarted server replays every transaction it was sent.
+#[cfg(feature = "serve")]
+fn serve(listen: Option<String>, wal: Option<OsString>, mut rejects: Option<RejectWriter>, mut processor: Processor) -> Result<(), error::Error> {
+    //The server runs across days, so daily volumes follow the date
+    processor.set_clock(transaction_processor::utc_day);
+    //Rejections are flushed as they happen, as the report is never closed.
+    let mut on_reject = |rejection: Rejection| match rejects.as_mut() {
+        Some(rejects) => rejects.write(&rejection).and_then(|_| rejects.flush()),
+        None => Ok(()),
+    };
+    if let Some(file_path) = wal {
+        if let Err(err) = open_write_ahead_log(&mut processor, Path::new(&file_path), &mut on_reject) {
+            error!("[!] Fatal error opening write-ahead log: {:?}", err);
             return Err(err);
         }
-        Ok(file_path) => file_path,
-    };
-    TransactionFeed::new(file_path)
+    }
+    let listen = listen.as_deref().unwrap_or(serve::DEFAULT_LISTEN);
+    if let Err(err) = serve::serve(&mut processor, listen, on_reject) {
+        error!("[!] Fatal error serving transactions: {:?}", err);
+        return Err(err);
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "serve"))]
+fn serve(_: Option<String>, _: Option<OsString>, _: Option<RejectWriter>, _: Processor) -> Result<(), error::Error> {
+    let err = error::Error::InvalidArgument(String::from("serve isn't supported by this build"));
+    error!("[!] Fatal error parsing command line args: {:?}", err);
+    Err(err)
 }
 
 fn main() -> Result<(), error::Error> {
     env_logger::Builder::from_env(Env::default().default_filter_or("off")).init();
-    let transaction_feed = match get_transaction_feed() {
+    let args = match get_args() {
+        Err(err) => {
+            error!("[!] Fatal error parsing command line args: {:?}", err);
+            return Err(err);
+        }
+        Ok(args) => args,
+    };
+    let mut rejects = match args
+        .rejects
+        .map(|file_path| RejectWriter::create(file_path, args.rejects_format))
+        .transpose()
+    {
+        Err(err) => {
+            error!("[!] Fatal error creating rejects report: {:?}", err);
+            return Err(err);
+        }
+        Ok(rejects) => rejects,
+    };
+
+    let fees = match args.fees.map(|file_path| FeeSchedule::read(Path::new(&file_path))).transpose() {
+        Err(err) => {
+            error!("[!] Fatal error loading fee schedule: {:?}", err);
+            return Err(err);
+        }
+        Ok(fees) => fees.unwrap_or_default(),
+    };
+    let client_config = match args.client_config.map(|file_path| ClientConfig::read(Path::new(&file_path))).transpose() {
+        Err(err) => {
+            error!("[!] Fatal error loading client config: {:?}", err);
+            return Err(err);
+        }
+        Ok(client_config) => client_config.unwrap_or_default(),
+    };
+    let rules = match args.rules.map(|file_path| RuleSet::read(Path::new(&file_path))).transpose() {
+        Err(err) => {
+            error!("[!] Fatal error loading rules: {:?}", err);
+            return Err(err);
+        }
+        Ok(rules) => rules.unwrap_or_default(),
+    };
+    let policy = Policy {
+        withdrawal_disputes: args.dispute_withdrawals,
+        redisputes: args.allow_redisputes,
+        precision: args.precision,
+        currency_precision: args.currency_precision,
+        fees,
+        client_config,
+        dispute_overdraft: args.dispute_overdraft,
+        rules,
+    };
+    let snapshot = match args.load_state.map(|file_path| Snapshot::read(Path::new(&file_path))).transpose() {
+        Err(err) => {
+            error!("[!] Fatal error loading state: {:?}", err);
+            return Err(err);
+        }
+        Ok(snapshot) => snapshot,
+    };
+
+    if args.serve {
+        return serve(args.listen, args.wal, rejects, processor_from(snapshot, policy));
+    }
+
+    //If no filename or "-" is given, transactions are read from stdin.
+    let mut transaction_feed = match open_transaction_source(args.input, args.input_format) {
         Err(err) => {
             error!("[!] Fatal error opening transaction feed: {:?}", err);
             return Err(err);
@@ -91,13 +288,89 @@ fn main() -> Result<(), error::Error> {
         Ok(transaction_feed) => transaction_feed,
     };
 
-    let mut repo = ClientRepo::new();
+    let mut on_reject = |rejection: Rejection| match rejects.as_mut() {
+        Some(rejects) => rejects.write(&rejection),
+        None => Ok(()),
+    };
+    //Flagged transactions are collected as they happen and reported once the input is handled.
+    let mut flagged = None;
+    let handled = if args.workers > 1 {
+        let sharded = match snapshot {
+            Some(snapshot) => ShardedProcessor::from_snapshot(snapshot, args.workers, policy),
+            None => ShardedProcessor::new(args.workers, policy),
+        };
+        sharded.handle_transactions(transaction_feed, &mut on_reject)
+    } else {
+        let mut processor = processor_from(snapshot, policy);
+        if args.flags.is_some() {
+            let (sender, receiver) = mpsc::channel();
+            processor.subscribe(move |event| {
+                if let Event::Flagged(flag) = event {
+                    let _ = sender.send(flag.clone());
+                }
+            });
+            flagged = Some(receiver);
+        }
 
-    Processor::handle_transactions(transaction_feed, &mut repo);
+        //Records left in the write-ahead log by a run that didn't finish are replayed, and the input
+        //is resumed after the last of them read from it.
+        if let Some(file_path) = &args.wal {
+            match open_write_ahead_log(&mut processor, Path::new(file_path), &mut on_reject) {
+                Err(err) => {
+                    error!("[!] Fatal error opening write-ahead log: {:?}", err);
+                    return Err(err);
+                }
+                Ok(Some(offset)) => transaction_feed = Box::new(ResumeFrom::new(transaction_feed, offset)),
+                Ok(None) => {}
+            }
+        }
 
-    if let Err(err) = write_client_data(&repo) {
+        processor.handle_transactions(transaction_feed, &mut on_reject).map(|_| processor)
+    };
+    let flushed = match rejects.as_mut() {
+        Some(rejects) => handled.and_then(|processor| rejects.flush().map(|_| processor)),
+        None => handled,
+    };
+    let mut processor = match flushed {
+        Err(err) => {
+            error!("[!] Fatal error handling transactions: {:?}", err);
+            return Err(err);
+        }
+        Ok(processor) => processor,
+    };
+
+    if let (Some(file_path), Some(flagged)) = (args.flags, flagged) {
+        let flags: Vec<Flag> = flagged.try_iter().collect();
+        if let Err(err) = write_flags(&flags, Path::new(&file_path)) {
+            error!("[!] Fatal error writing flagged transactions: {:?}", err);
+            return Err(err);
+        }
+    }
+
+    if let Some(file_path) = args.save_state {
+        if let Err(err) = processor.snapshot().write(Path::new(&file_path)) {
+            error!("[!] Fatal error saving state: {:?}", err);
+            return Err(err);
+        }
+    }
+
+    let written = match args.output {
+        Some(file_path) if file_path != "-" => {
+            write_client_data_to_path(processor.clients(), args.output_format, args.client_order, Path::new(&file_path))
+        }
+        _ => write_client_data(processor.clients(), args.output_format, args.client_order, io::stdout()),
+    };
+    if let Err(err) = written {
         error!("[!] Fatal error writing transactions: {:?}", err);
         return Err(err);
     }
+
+    //Results are safely written, so the log is no longer needed to recover this run.
+    if let Some(wal) = processor.take_write_ahead_log() {
+        if let Err(err) = wal.remove() {
+            error!("[!] Fatal error removing write-ahead log: {:?}", err);
+            return Err(err);
+        }
+    }
     Ok(())
 }
@@ -1,71 +1,189 @@
+use crate::client_config::ClientSettings;
 use crate::error;
 use log::trace;
 use rust_decimal::prelude::*;
 use serde::Serialize;
+use std::collections::BTreeMap;
 
-///Holds all account details for a client, including funds, allocation, id, and status (locked).
+///Funds a client holds in one currency.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Balances {
+    available: Decimal,
+    held: Decimal,
+    total: Decimal,
+    fees: Decimal,
+}
+
+impl Balances {
+    ///Creates balances from their parts, such as from a snapshot.
+    pub(crate) fn new(available: Decimal, held: Decimal, total: Decimal, fees: Decimal) -> Self {
+        Self {
+            available,
+            held,
+            total,
+            fees,
+        }
+    }
+    ///Funds available for withdrawal
+    pub fn available(&self) -> Decimal {
+        self.available
+    }
+    ///Funds held by disputes
+    pub fn held(&self) -> Decimal {
+        self.held
+    }
+    ///Total funds, available plus held
+    pub fn total(&self) -> Decimal {
+        self.total
+    }
+    ///Fees charged so far, already taken from available and total
+    pub fn fees(&self) -> Decimal {
+        self.fees
+    }
+}
+
+///Holds all account details for a client, including funds in each currency, allocation, id, and
+/// status (locked). Locks apply to the client as a whole, whatever the currency.
 /// These functions are designed to be called on only fully validated transactions. All amounts
 /// are assumed to be valid.
 /// See the readme for rules on how these fields are set and interact.
-#[derive(Default, Debug, Serialize)]
+#[derive(Default, Debug, Clone, Serialize)]
 pub struct Client {
+    ///Unique client identification number
     pub client: u16,
-    available: Decimal,
-    held: Decimal,
-    total: Decimal,
+    balances: BTreeMap<String, Balances>,
+    ///Locked accounts refuse all further transactions until unlocked. Set by a chargeback or
+    /// a freeze.
     pub locked: bool,
+    ///Closed accounts are locked and can never be unlocked.
+    #[serde(skip)]
+    pub closed: bool,
+    #[serde(skip)]
+    settings: ClientSettings,
 }
 
 impl Client {
+    ///Creates an empty, unlocked account for the client id.
     pub fn new(id: u16) -> Self {
         Self {
             client: id,
             ..Default::default()
         }
     }
+    ///Recreates an account from its balances and status, such as from a snapshot.
+    pub(crate) fn from_balances(id: u16, balances: BTreeMap<String, Balances>, locked: bool, closed: bool) -> Self {
+        Self {
+            client: id,
+            balances,
+            locked,
+            closed,
+            ..Default::default()
+        }
+    }
+    ///Applies the client's settings, such as its overdraft limits
+    pub fn configure(&mut self, settings: ClientSettings) {
+        self.settings = settings;
+    }
+    ///How far below zero withdrawals may take available funds in a currency
+    pub fn overdraft(&self, currency: &str) -> Decimal {
+        self.settings.overdraft(currency)
+    }
+    ///Funds held in a currency, zero if the client has never held it
+    pub fn balances(&self, currency: &str) -> Balances {
+        self.balances.get(currency).copied().unwrap_or_default()
+    }
+    ///Funds held in every currency the client has transacted in, in currency order
+    pub fn currencies(&self) -> impl Iterator<Item = (&str, &Balances)> {
+        self.balances.iter().map(|(currency, balances)| (currency.as_str(), balances))
+    }
+    fn wallet(&mut self, currency: &str) -> &mut Balances {
+        self.balances.entry(currency.to_owned()).or_default()
+    }
     ///Add money to the account
-    pub fn deposit(&mut self, amount: Decimal) {
-        self.total += amount;
-        self.available += amount;
+    pub fn deposit(&mut self, currency: &str, amount: Decimal) {
+        let client = self.client;
+        let wallet = self.wallet(currency);
+        wallet.total += amount;
+        wallet.available += amount;
         trace!(
-            "[!] Client {} deposited ${} and has total = ${} and available = ${}.",
-            self.client,
+            "[!] Client {} deposited {} {} and has total = {} and available = {}.",
+            client,
             amount,
-            self.total,
-            self.available
+            currency,
+            wallet.total,
+            wallet.available
         );
     }
-    ///Withdraw money from the account if there are sufficient funds
-    pub fn withdraw(&mut self, amount: Decimal) -> Result<(), error::Error> {
-        if amount <= self.available {
-            self.total -= amount;
-            self.available -= amount;
-            trace!(
-                "[!] Client {} withdrew ${} and has total = ${} and available = ${}.",
-                self.client,
-                amount,
-                self.total,
-                self.available
-            );
+    ///Withdraw money from the account if there are sufficient funds in the currency, allowing
+    /// available funds to go as far below zero as the client's overdraft limit
+    pub fn withdraw(&mut self, currency: &str, amount: Decimal) -> Result<(), error::Error> {
+        self.withdraw_with_fee(currency, amount, Decimal::ZERO)
+    }
+    ///Withdraw money from the account and charge a fee on it, if there are sufficient funds in
+    /// the currency to cover both within the client's overdraft limit
+    pub fn withdraw_with_fee(&mut self, currency: &str, amount: Decimal, fee: Decimal) -> Result<(), error::Error> {
+        let client = self.client;
+        self.check_withdrawal(currency, amount, fee)?;
+        let wallet = self.wallet(currency);
+        wallet.total -= amount;
+        wallet.available -= amount;
+        trace!(
+            "[!] Client {} withdrew {} {} and has total = {} and available = {}.",
+            client,
+            amount,
+            currency,
+            wallet.total,
+            wallet.available
+        );
+        self.charge_fee(currency, fee);
+        Ok(())
+    }
+    ///Check that there are sufficient funds in the currency to withdraw an amount and charge a fee
+    /// on it within the client's overdraft limit, without withdrawing anything
+    pub fn check_withdrawal(&self, currency: &str, amount: Decimal, fee: Decimal) -> Result<(), error::Error> {
+        let available = self.balances(currency).available;
+        if amount + fee <= available + self.overdraft(currency) {
             Ok(())
         } else {
-            Err(error::Error::Client(format!(
-                "Insufficient funds for client {}. \
-                {} requested, {} available",
-                self.client, amount, self.available
-            )))
+            Err(error::Error::InsufficientFunds {
+                client: self.client,
+                requested: amount + fee,
+                available,
+            })
+        }
+    }
+    ///Charge a fee, taking it from available and total funds
+    pub fn charge_fee(&mut self, currency: &str, fee: Decimal) {
+        if fee.is_zero() {
+            return;
         }
+        let client = self.client;
+        let wallet = self.wallet(currency);
+        wallet.total -= fee;
+        wallet.available -= fee;
+        wallet.fees += fee;
+        trace!(
+            "[!] Client {} was charged a fee of {} {} and has total = {} and available = {}.",
+            client,
+            fee,
+            currency,
+            wallet.total,
+            wallet.available
+        );
     }
     ///Hold disputed funds removing them from the available balance
-    pub fn dispute(&mut self, amount: Decimal) {
-        self.available -= amount;
-        self.held += amount;
+    pub fn dispute(&mut self, currency: &str, amount: Decimal) {
+        let client = self.client;
+        let wallet = self.wallet(currency);
+        wallet.available -= amount;
+        wallet.held += amount;
         trace!(
-            "[!] Client {} disputed ${} and has available = ${} and held = ${}.",
-            self.client,
+            "[!] Client {} disputed {} {} and has available = {} and held = {}.",
+            client,
             amount,
-            self.available,
-            self.held
+            currency,
+            wallet.available,
+            wallet.held
         );
     }
     ///Resolve a dispute, releasing the funds from held to available
@@ -73,23 +191,26 @@ impl Client {
     /// If less funds are held than are supposed to be resolved, the application must panic.
     /// This means funds are being leaked somewhere and there is a malfunction. This should be
     /// impossible.
-    pub fn resolve(&mut self, amount: Decimal) {
-        if self.held < amount {
+    pub fn resolve(&mut self, currency: &str, amount: Decimal) {
+        let client = self.client;
+        let wallet = self.wallet(currency);
+        if wallet.held < amount {
             //This should be impossible. The ledger is malfunctioning, so the system can't be trusted
             panic!(
                 "System error on client {}. Trying to resolve but amount: {} \
             is greater than the value of held funds: {}",
-                self.client, amount, self.held
+                client, amount, wallet.held
             )
         }
-        self.available += amount;
-        self.held -= amount;
+        wallet.available += amount;
+        wallet.held -= amount;
         trace!(
-            "[!] Client {} resolved ${} and has available = ${} and held = ${}.",
-            self.client,
+            "[!] Client {} resolved {} {} and has available = {} and held = {}.",
+            client,
             amount,
-            self.available,
-            self.held
+            currency,
+            wallet.available,
+            wallet.held
         );
     }
     ///Charge back a dispute, release funds from held and discharging them (subtract from total)
@@ -97,107 +218,337 @@ impl Client {
     /// If less funds are held than are supposed to be charged back, the application must panic.
     /// This means funds are being leaked somewhere and there is a malfunction. This should be
     /// impossible.
-    pub fn chargeback(&mut self, amount: Decimal) {
-        if self.held < amount {
+    pub fn chargeback(&mut self, currency: &str, amount: Decimal) {
+        let client = self.client;
+        let wallet = self.wallet(currency);
+        if wallet.held < amount {
             //This should be impossible. The ledger is malfunctioning, so the system can't be trusted
             panic!(
                 "System error on client {}. Trying to chargeback but amount: {} \
             is greater than the value of held funds: {}",
-                self.client, amount, self.held
+                client, amount, wallet.held
             )
         } else {
-            self.total -= amount;
-            self.held -= amount;
+            wallet.total -= amount;
+            wallet.held -= amount;
+        }
+        trace!(
+            "[!] Client {} charged back {} {} and has available = {} and held = {}.",
+            client,
+            amount,
+            currency,
+            wallet.available,
+            wallet.held
+        );
+        self.locked = true;
+    }
+    ///Lock the account pending investigation
+    pub fn freeze(&mut self) -> Result<(), error::Error> {
+        if self.locked {
+            Err(error::Error::AccountLocked { client: self.client })
+        } else {
+            self.locked = true;
+            trace!("[!] Client {} frozen.", self.client);
+            Ok(())
+        }
+    }
+    ///Unlock a locked or frozen account
+    pub fn unlock(&mut self) -> Result<(), error::Error> {
+        if self.closed {
+            Err(error::Error::AccountClosed { client: self.client })
+        } else if !self.locked {
+            Err(error::Error::NotLocked { client: self.client })
+        } else {
+            self.locked = false;
+            trace!("[!] Client {} unlocked.", self.client);
+            Ok(())
+        }
+    }
+    ///Permanently close the account, locking it
+    pub fn close(&mut self) -> Result<(), error::Error> {
+        if self.closed {
+            Err(error::Error::AccountClosed { client: self.client })
+        } else {
             self.locked = true;
+            self.closed = true;
+            trace!("[!] Client {} closed.", self.client);
+            Ok(())
+        }
+    }
+    ///Dispute a withdrawal, holding the withdrawn funds pending investigation. The funds are
+    /// returned to held (and total) but are not available until the dispute is settled.
+    pub fn dispute_withdrawal(&mut self, currency: &str, amount: Decimal) {
+        let client = self.client;
+        let wallet = self.wallet(currency);
+        wallet.held += amount;
+        wallet.total += amount;
+        trace!(
+            "[!] Client {} disputed a withdrawal of {} {} and has total = {} and held = {}.",
+            client,
+            amount,
+            currency,
+            wallet.total,
+            wallet.held
+        );
+    }
+    ///Resolve a disputed withdrawal, finalizing the withdrawal by discharging the held funds
+    ///# Panics
+    /// If less funds are held than are supposed to be resolved, the application must panic.
+    /// This means funds are being leaked somewhere and there is a malfunction. This should be
+    /// impossible.
+    pub fn resolve_withdrawal(&mut self, currency: &str, amount: Decimal) {
+        let client = self.client;
+        let wallet = self.wallet(currency);
+        if wallet.held < amount {
+            //This should be impossible. The ledger is malfunctioning, so the system can't be trusted
+            panic!(
+                "System error on client {}. Trying to resolve a withdrawal but amount: {} \
+            is greater than the value of held funds: {}",
+                client, amount, wallet.held
+            )
         }
+        wallet.held -= amount;
+        wallet.total -= amount;
         trace!(
-            "[!] Client {} charged back ${} and has available = ${} and held = ${}.",
-            self.client,
+            "[!] Client {} resolved a withdrawal of {} {} and has total = {} and held = {}.",
+            client,
             amount,
-            self.available,
-            self.held
+            currency,
+            wallet.total,
+            wallet.held
+        );
+    }
+    ///Charge back a disputed withdrawal, crediting the held funds back to available. Unlike a
+    /// deposit chargeback this doesn't lock the account, as the client is the one being repaid.
+    ///# Panics
+    /// If less funds are held than are supposed to be charged back, the application must panic.
+    /// This means funds are being leaked somewhere and there is a malfunction. This should be
+    /// impossible.
+    pub fn chargeback_withdrawal(&mut self, currency: &str, amount: Decimal) {
+        let client = self.client;
+        let wallet = self.wallet(currency);
+        if wallet.held < amount {
+            //This should be impossible. The ledger is malfunctioning, so the system can't be trusted
+            panic!(
+                "System error on client {}. Trying to chargeback a withdrawal but amount: {} \
+            is greater than the value of held funds: {}",
+                client, amount, wallet.held
+            )
+        }
+        wallet.held -= amount;
+        wallet.available += amount;
+        trace!(
+            "[!] Client {} charged back a withdrawal of {} {} and has available = {} and held = {}.",
+            client,
+            amount,
+            currency,
+            wallet.available,
+            wallet.held
         );
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::ops::Neg;
-    use rust_decimal::Decimal;
+    use crate::client_config::ClientSettings;
+    use crate::error;
     use crate::models::client::Client;
+    use crate::transaction::DEFAULT_CURRENCY as USD;
+    use rust_decimal::Decimal;
+    use std::ops::Neg;
 
     #[test]
     fn deposit() {
         let mut client = Client::new(1);
-        let amount=Decimal::new(10000, 4);
-        client.deposit(amount);
-        assert_eq!(client.total, amount);
-        assert_eq!(client.available, amount);
+        let amount = Decimal::new(10000, 4);
+        client.deposit(USD, amount);
+        assert_eq!(client.balances(USD).total, amount);
+        assert_eq!(client.balances(USD).available, amount);
     }
 
     #[test]
     fn valid_withdrawal() {
         let mut client = Client::new(1);
-        let amount=Decimal::new(10000, 4);
+        let amount = Decimal::new(10000, 4);
         let zero = Decimal::new(00000, 4);
-        let result = client.withdraw(amount).is_ok();
+        let result = client.withdraw(USD, amount).is_ok();
         assert!(!result);
-        assert_eq!(client.total, zero);
-        assert_eq!(client.available, zero);
+        assert_eq!(client.balances(USD).total, zero);
+        assert_eq!(client.balances(USD).available, zero);
     }
 
     #[test]
     fn insufficient_funds_withdrawal() {
         let mut client = Client::new(1);
-        let amount=Decimal::new(10000, 4);
-        let result = client.withdraw(amount).is_ok();
+        let amount = Decimal::new(10000, 4);
+        let result = client.withdraw(USD, amount).is_ok();
         assert!(!result);
     }
 
+    #[test]
+    fn insufficient_funds_error() {
+        let mut client = Client::new(1);
+        let amount = Decimal::new(10000, 4);
+        match client.withdraw(USD, amount) {
+            Err(error::Error::InsufficientFunds {
+                client,
+                requested,
+                available,
+            }) => {
+                assert_eq!(client, 1);
+                assert_eq!(requested, amount);
+                assert_eq!(available, Decimal::ZERO);
+            }
+            other => panic!("Expected insufficient funds, found {:?}", other),
+        }
+    }
+
     #[test]
     fn dispute() {
         let mut client = Client::new(1);
-        let amount=Decimal::new(10000, 4);
-        client.dispute(amount);
-        assert_eq!(client.available, amount.neg());
-        assert_eq!(client.held, amount);
+        let amount = Decimal::new(10000, 4);
+        client.dispute(USD, amount);
+        assert_eq!(client.balances(USD).available, amount.neg());
+        assert_eq!(client.balances(USD).held, amount);
     }
 
     #[test]
     fn resolve() {
         let mut client = Client::new(1);
-        let amount=Decimal::new(10000, 4);
+        let amount = Decimal::new(10000, 4);
         let zero = Decimal::new(00000, 4);
-        client.dispute(amount);
-        client.resolve(amount);
-        assert_eq!(client.available, zero);
-        assert_eq!(client.held, zero);
+        client.dispute(USD, amount);
+        client.resolve(USD, amount);
+        assert_eq!(client.balances(USD).available, zero);
+        assert_eq!(client.balances(USD).held, zero);
     }
 
     #[test]
     fn chargeback() {
         let mut client = Client::new(1);
-        let amount=Decimal::new(10000, 4);
+        let amount = Decimal::new(10000, 4);
         let zero = Decimal::new(00000, 4);
-        client.dispute(amount);
-        client.chargeback(amount);
-        assert_eq!(client.available, amount.neg());
-        assert_eq!(client.held, zero);
+        client.dispute(USD, amount);
+        client.chargeback(USD, amount);
+        assert_eq!(client.balances(USD).available, amount.neg());
+        assert_eq!(client.balances(USD).held, zero);
     }
 
     #[test]
     #[should_panic(expected = "System error on client 1. Trying to resolve but amount: 1.0000 is greater than the value of held funds: 0")]
     fn resolve_not_enough_held() {
         let mut client = Client::new(1);
-        let amount=Decimal::new(10000, 4);
-        client.resolve(amount);
+        let amount = Decimal::new(10000, 4);
+        client.resolve(USD, amount);
     }
 
     #[test]
     #[should_panic(expected = "System error on client 1. Trying to chargeback but amount: 1.0000 is greater than the value of held funds: 0")]
     fn chargeback_not_enough_held() {
         let mut client = Client::new(1);
-        let amount=Decimal::new(10000, 4);
-        client.chargeback(amount);
+        let amount = Decimal::new(10000, 4);
+        client.chargeback(USD, amount);
+    }
+
+    #[test]
+    fn withdrawal_dispute_lifecycle() {
+        let mut client = Client::new(1);
+        let amount = Decimal::new(10000, 4);
+        client.deposit(USD, amount);
+        client.withdraw(USD, amount).unwrap();
+        client.dispute_withdrawal(USD, amount);
+        assert_eq!(client.balances(USD).available, Decimal::ZERO);
+        assert_eq!(client.balances(USD).held, amount);
+        assert_eq!(client.balances(USD).total, amount);
+        client.resolve_withdrawal(USD, amount);
+        assert_eq!(client.balances(USD).held, Decimal::ZERO);
+        assert_eq!(client.balances(USD).total, Decimal::ZERO);
+        assert!(!client.locked);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn withdrawal_chargeback() {
+        let mut client = Client::new(1);
+        let amount = Decimal::new(10000, 4);
+        client.dispute_withdrawal(USD, amount);
+        client.chargeback_withdrawal(USD, amount);
+        assert_eq!(client.balances(USD).available, amount);
+        assert_eq!(client.balances(USD).held, Decimal::ZERO);
+        assert_eq!(client.balances(USD).total, amount);
+        assert!(!client.locked);
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "System error on client 1. Trying to resolve a withdrawal but amount: 1.0000 is greater than the value of held funds: 0"
+    )]
+    fn resolve_withdrawal_not_enough_held() {
+        let mut client = Client::new(1);
+        let amount = Decimal::new(10000, 4);
+        client.resolve_withdrawal(USD, amount);
+    }
+
+    #[test]
+    fn unlock_after_chargeback() {
+        let mut client = Client::new(1);
+        let amount = Decimal::new(10000, 4);
+        assert!(client.unlock().is_err());
+        client.dispute(USD, amount);
+        client.chargeback(USD, amount);
+        assert!(client.locked);
+        client.unlock().unwrap();
+        assert!(!client.locked);
+    }
+
+    #[test]
+    fn currencies_are_separate() {
+        let mut client = Client::new(1);
+        let amount = Decimal::new(10000, 4);
+        client.deposit("EUR", amount);
+        assert!(client.withdraw(USD, amount).is_err());
+        client.dispute("EUR", amount);
+        assert_eq!(client.balances("EUR").held, amount);
+        assert_eq!(client.balances(USD).held, Decimal::ZERO);
+        let currencies: Vec<&str> = client.currencies().map(|(currency, _)| currency).collect();
+        assert_eq!(currencies, vec!["EUR"]);
+    }
+
+    #[test]
+    fn withdrawals_respect_the_overdraft_limit() {
+        let mut client = Client::new(1);
+        let mut settings = ClientSettings {
+            overdraft: Decimal::new(50, 1),
+            ..Default::default()
+        };
+        settings.currency_overdraft.insert(String::from("EUR"), Decimal::ZERO);
+        client.configure(settings);
+        client.deposit(USD, Decimal::new(10, 1));
+        assert!(client.withdraw(USD, Decimal::new(61, 1)).is_err());
+        client.withdraw(USD, Decimal::new(60, 1)).unwrap();
+        assert_eq!(client.balances(USD).available(), Decimal::new(-50, 1));
+        assert!(client.withdraw("EUR", Decimal::new(1, 1)).is_err());
+    }
+
+    #[test]
+    fn fees_come_out_of_available_funds() {
+        let mut client = Client::new(1);
+        client.deposit(USD, Decimal::new(100, 1));
+        let result = client.withdraw_with_fee(USD, Decimal::new(95, 1), Decimal::new(10, 1));
+        assert!(matches!(result, Err(error::Error::InsufficientFunds { requested, .. }) if requested == Decimal::new(105, 1)));
+        client.withdraw_with_fee(USD, Decimal::new(50, 1), Decimal::new(10, 1)).unwrap();
+        client.charge_fee(USD, Decimal::new(5, 1));
+        assert_eq!(client.balances(USD).available(), Decimal::new(35, 1));
+        assert_eq!(client.balances(USD).total(), Decimal::new(35, 1));
+        assert_eq!(client.balances(USD).fees(), Decimal::new(15, 1));
+    }
+
+    #[test]
+    fn freeze_and_close() {
+        let mut client = Client::new(1);
+        client.freeze().unwrap();
+        assert!(matches!(client.freeze(), Err(error::Error::AccountLocked { client: 1 })));
+        client.close().unwrap();
+        assert!(client.locked);
+        assert!(matches!(client.unlock(), Err(error::Error::AccountClosed { client: 1 })));
+    }
+}
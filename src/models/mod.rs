@@ -1,2 +1,4 @@
+///Client accounts and balances.
 pub mod client;
+///Transactions and their validation rules.
 pub mod transaction;
@@ -1,18 +1,72 @@
 use crate::error;
+use crate::policy::Precision;
 use log::{debug, trace};
 use rust_decimal::prelude::*;
 use rust_decimal_macros::dec;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+
+///Currency of transactions that don't give one, and of the balances they move.
+pub const DEFAULT_CURRENCY: &str = "USD";
 
 /// Types of transactions. See the README for details.
-#[derive(Debug, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq)]
 #[serde(rename_all = "lowercase")]
 pub enum TxTypes {
+    ///Credit funds to the client.
     Deposit,
+    ///Debit funds from the client.
     Withdrawal,
+    ///Hold the funds of a previous deposit pending investigation, or of a previous withdrawal if
+    /// the policy allows withdrawal disputes.
     Dispute,
+    ///Release the held funds of a disputed deposit, or finalize a disputed withdrawal.
     Resolve,
+    ///Reverse a disputed deposit and lock the client, or credit a disputed withdrawal back to the
+    /// client.
     Chargeback,
+    ///Administrative. Unlock a locked or frozen client.
+    Unlock,
+    ///Administrative. Lock a client pending investigation.
+    Freeze,
+    ///Administrative. Permanently close a client. Closed clients can't be unlocked.
+    Close,
+    ///A fee charged on a deposit or withdrawal and credited to the house account. Fees are
+    /// journaled by the engine and refused as input.
+    Fee,
+}
+
+impl TxTypes {
+    ///Whether the type is an administrative operation on the client rather than a movement of
+    /// funds. Administrative operations apply to locked clients.
+    pub fn is_admin(&self) -> bool {
+        match self {
+            TxTypes::Unlock | TxTypes::Freeze | TxTypes::Close => true,
+            TxTypes::Deposit | TxTypes::Withdrawal | TxTypes::Dispute | TxTypes::Resolve | TxTypes::Chargeback | TxTypes::Fee => false,
+        }
+    }
+}
+
+///Dispute lifecycle of a deposit or withdrawal in the ledger. Every transaction starts settled,
+/// and disputes, resolves and chargebacks move it between states:
+///
+/// | From                   | Dispute  | Resolve  | Chargeback |
+/// |------------------------|----------|----------|------------|
+/// | Settled                | Disputed | -        | -          |
+/// | Disputed               | -        | Resolved | ChargedBack|
+/// | Resolved               | Disputed if re-disputes are allowed | - | - |
+/// | ChargedBack            | -        | -        | -          |
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DisputeState {
+    ///Never disputed.
+    #[default]
+    Settled,
+    ///Under dispute, with the funds held.
+    Disputed,
+    ///A dispute was resolved and the funds released.
+    Resolved,
+    ///A dispute ended in a chargeback. Final.
+    ChargedBack,
 }
 
 ///Associated functions that validate and format transactions according to the rules of the
@@ -21,128 +75,206 @@ pub enum TxTypes {
 #[serde(rename_all = "lowercase")]
 pub struct Transaction {
     #[serde(rename = "type")]
+    /// Type of transaction, which decides how the rest of the fields are used
     pub tx_type: TxTypes,
     /// Unique client identification number
     pub client: u16,
     #[serde(rename = "tx")]
     /// Unique transaction number, or ID of transaction related to a dispute
     pub tx_id: u32,
-    /// Amounts are not present with dispute related transactions
+    /// Amounts are not present with dispute related or administrative transactions
     pub amount: Option<Decimal>,
-    /// Disputed is set by the transaction-engine, so it is defaulted when serializing
+    /// Audit reason for administrative transactions, such as an investigation reference
+    #[serde(default)]
+    pub reason: Option<String>,
+    /// Currency code of deposits and withdrawals, DEFAULT_CURRENCY if not given. Dispute related
+    /// transactions use the currency of the original transaction, and administrative ones
+    /// apply to every currency
+    #[serde(default)]
+    pub currency: Option<String>,
+    /// Dispute state is set by the transaction-engine, so it is defaulted when serializing
     #[serde(skip)]
-    pub disputed: bool,
+    pub state: DisputeState,
 }
 
 impl Transaction {
-    /// Checks that the transaction is valid and trims amount to 4 decimal places using
-    /// rust_decimal .round_dp(). Amounts must be positive and only presenton deposits
-    /// or withdrawals.
-    pub fn validate_transaction(amount: &mut Option<Decimal>, tx_type: &TxTypes) -> Result<(), error::Error> {
+    /// Currency the transaction is in, DEFAULT_CURRENCY if not given.
+    pub fn currency(&self) -> &str {
+        self.currency.as_deref().unwrap_or(DEFAULT_CURRENCY)
+    }
+
+    /// Checks that a currency, if given, is a three letter code and upper cases it. A blank
+    /// currency is treated as not given.
+    pub fn validate_currency(currency: &mut Option<String>) -> Result<(), error::Error> {
+        if let Some(code) = currency {
+            let trimmed = code.trim();
+            if trimmed.is_empty() {
+                *currency = None;
+            } else if trimmed.len() == 3 && trimmed.chars().all(|c| c.is_ascii_alphabetic()) {
+                *currency = Some(trimmed.to_ascii_uppercase());
+                trace!("Currency {:?} successfully validated.", currency);
+            } else {
+                return Err(error::Error::InvalidCurrency { currency: code.clone() });
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that the transaction is valid and brings the amount to the precision of its
+    /// currency, 4 decimal places with banker's rounding unless configured otherwise. Amounts
+    /// must be positive and only presenton deposits or withdrawals. Administrative transactions
+    /// must give a reason for the audit trail.
+    pub fn validate_transaction(
+        amount: &mut Option<Decimal>,
+        tx_type: &TxTypes,
+        reason: Option<&str>,
+        precision: Precision,
+    ) -> Result<(), error::Error> {
         match tx_type {
             TxTypes::Deposit | TxTypes::Withdrawal => {
                 trace!("Deposit or withdrawal detected, calling validate: {:?}", tx_type);
-                Self::validate_deposit_withdrawal_structure(amount)
+                Self::validate_deposit_withdrawal_structure(amount, tx_type, precision)
             }
             TxTypes::Dispute | TxTypes::Resolve | TxTypes::Chargeback => {
                 trace!("Dispute related transaction detected, calling validate: {:?}", tx_type);
-                Self::validate_dispute_related_structure(amount)
+                Self::validate_dispute_related_structure(amount, tx_type)
             }
+            TxTypes::Unlock | TxTypes::Freeze | TxTypes::Close => {
+                trace!("Administrative transaction detected, calling validate: {:?}", tx_type);
+                Self::validate_admin_structure(amount, tx_type, reason)
+            }
+            TxTypes::Fee => Err(error::Error::ReservedType { tx_type: *tx_type }),
         }
     }
 
-    fn validate_deposit_withdrawal_structure(amount: &mut Option<Decimal>) -> Result<(), error::Error> {
+    fn validate_deposit_withdrawal_structure(amount: &mut Option<Decimal>, tx_type: &TxTypes, precision: Precision) -> Result<(), error::Error> {
         if let Some(tx_amount) = amount {
             if tx_amount < &mut dec!(0) {
-                Err(error::Error::Transaction(String::from("Amount must be a positive number")))
+                Err(error::Error::NegativeAmount { amount: *tx_amount })
             } else {
-                *amount = Some(tx_amount.round_dp(4));
+                *amount = Some(precision.apply(*tx_amount)?);
                 trace!("withdrawal or deposit of {:?} successfully validated.", amount);
                 Ok(())
             }
         } else {
-            Err(error::Error::Transaction(String::from("Deposits and withdrawals require an amount.")))
+            Err(error::Error::MissingAmount { tx_type: *tx_type })
         }
     }
 
-    fn validate_dispute_related_structure(amount: &mut Option<Decimal>) -> Result<(), error::Error> {
+    fn validate_dispute_related_structure(amount: &mut Option<Decimal>, tx_type: &TxTypes) -> Result<(), error::Error> {
         if amount.is_some() {
-            Err(error::Error::Transaction(String::from("Disputes, resolutions and chargebacks shouldn't have amounts")))
+            Err(error::Error::UnexpectedAmount { tx_type: *tx_type })
         } else {
             debug!("Dispute related transaction ok.");
             Ok(())
         }
     }
 
-    ///Called once a transactions format is confirmed to be valid. Takes the disputed status of the
-    /// transaction related to the dispute, and whether the current transaction is resolving that
-    /// transaction or not. If resolving is true, the transaction must be disputed. We can't
-    /// resolve an undisputed transaction. Likewise, if resolving is false, the transaction can't
-    /// must be undisputed. We can't dispute a transaction already being disputed.
-    pub fn check_transaction_dispute_valid(tx_resolving: bool, ledger_disputed: bool) -> Result<(), error::Error> {
-        if tx_resolving != ledger_disputed {
-            Err(error::Error::Transaction(String::from("Cannot dispute a disputed transaction, \
-                        or resolve an undisputed transaction.")))
+    fn validate_admin_structure(amount: &Option<Decimal>, tx_type: &TxTypes, reason: Option<&str>) -> Result<(), error::Error> {
+        if amount.is_some() {
+            Err(error::Error::UnexpectedAmount { tx_type: *tx_type })
+        } else if reason.is_none_or(|reason| reason.trim().is_empty()) {
+            Err(error::Error::MissingReason { tx_type: *tx_type })
         } else {
-            trace!("Dispute related transaction ok. The targeted transaction disputed = {} \
-            and the new transaction has resolving = {}", ledger_disputed, tx_resolving);
+            debug!("Administrative transaction ok. Reason: {:?}", reason);
             Ok(())
         }
     }
-    ///Only deposits with valid amounts may be disputed.
+
+    ///Called once a transactions format is confirmed to be valid. Takes the type of the dispute
+    /// related transaction and the dispute state of the transaction it targets, returning the
+    /// state the target moves to. A dispute is only valid against a settled transaction, or a
+    /// resolved one if re-disputes are allowed. Resolves and chargebacks are only valid against
+    /// a disputed transaction. A charged back transaction can't be actioned again.
+    pub fn check_transaction_dispute_valid(tx_type: &TxTypes, state: DisputeState, redisputes: bool) -> Result<DisputeState, error::Error> {
+        let next = match (tx_type, state) {
+            (TxTypes::Dispute, DisputeState::Settled) => Some(DisputeState::Disputed),
+            (TxTypes::Dispute, DisputeState::Resolved) if redisputes => Some(DisputeState::Disputed),
+            (TxTypes::Resolve, DisputeState::Disputed) => Some(DisputeState::Resolved),
+            (TxTypes::Chargeback, DisputeState::Disputed) => Some(DisputeState::ChargedBack),
+            _ => None,
+        };
+        match next {
+            Some(next) => {
+                trace!(
+                    "Dispute related transaction ok. {:?} moves the targeted transaction from {:?} to {:?}",
+                    tx_type,
+                    state,
+                    next
+                );
+                Ok(next)
+            }
+            None => Err(error::Error::InvalidDisputeState { tx_type: *tx_type, state }),
+        }
+    }
+    ///Only deposits with valid amounts may be disputed, and withdrawals if withdrawal_disputes
+    /// is set by the policy.
     ///# Panics
     /// If the ledger has a deposit that doesn't have a value, the system must panic. The system
     /// is not keeping track of transactions, and data is being lost, which is a serious error.
-    pub fn check_transaction_is_disputable(amount: Option<Decimal>, tx_type: &TxTypes) -> Result<(), error::Error> {
+    pub fn check_transaction_is_disputable(amount: Option<Decimal>, tx_type: &TxTypes, withdrawal_disputes: bool) -> Result<(), error::Error> {
         match tx_type {
             TxTypes::Deposit => {
                 amount.expect("System error, ledger shows a deposit with no amount");
                 debug!("A deposit is being disputed or resolved for a value of {:?}", amount);
                 Ok(())
-
             }
-            _ => Err(error::Error::Transaction(format!("Trying to dispute or resolve a {:?}. Only Deposits are valid targets.", tx_type))),
+            TxTypes::Withdrawal if withdrawal_disputes => {
+                amount.expect("System error, ledger shows a withdrawal with no amount");
+                debug!("A withdrawal is being disputed or resolved for a value of {:?}", amount);
+                Ok(())
+            }
+            _ => Err(error::Error::NotDisputable { tx_type: *tx_type }),
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::error;
+    use crate::policy::{Precision, Rounding};
+    use crate::transaction::{DisputeState, Transaction, TxTypes};
     use rust_decimal::Decimal;
-    use crate::transaction::{Transaction, TxTypes};
 
     #[test]
     fn deposit_is_disputable() {
         let amount: Option<Decimal> = Some(Decimal::new(10000, 4));
-        let result = Transaction::check_transaction_is_disputable( amount, &TxTypes::Deposit).is_ok();
+        let result = Transaction::check_transaction_is_disputable(amount, &TxTypes::Deposit, false).is_ok();
         assert!(result);
     }
 
     #[test]
     fn withdraw_not_disputable() {
         let amount: Option<Decimal> = Some(Decimal::new(10000, 4));
-        let result = Transaction::check_transaction_is_disputable( amount, &TxTypes::Withdrawal).is_ok();
+        let result = Transaction::check_transaction_is_disputable(amount, &TxTypes::Withdrawal, false).is_ok();
         assert!(!result);
     }
 
+    #[test]
+    fn withdraw_disputable_by_policy() {
+        let amount: Option<Decimal> = Some(Decimal::new(10000, 4));
+        assert!(Transaction::check_transaction_is_disputable(amount, &TxTypes::Withdrawal, true).is_ok());
+        assert!(Transaction::check_transaction_is_disputable(amount, &TxTypes::Dispute, true).is_err());
+    }
+
     #[test]
     fn dispute_not_disputable() {
         let amount: Option<Decimal> = Some(Decimal::new(10000, 4));
-        let result = Transaction::check_transaction_is_disputable( amount, &TxTypes::Dispute).is_ok();
+        let result = Transaction::check_transaction_is_disputable(amount, &TxTypes::Dispute, false).is_ok();
         assert!(!result);
     }
 
     #[test]
     fn resolve_not_disputable() {
         let amount: Option<Decimal> = Some(Decimal::new(10000, 4));
-        let result = Transaction::check_transaction_is_disputable( amount, &TxTypes::Resolve).is_ok();
+        let result = Transaction::check_transaction_is_disputable(amount, &TxTypes::Resolve, false).is_ok();
         assert!(!result);
     }
 
     #[test]
     fn chargeback_not_disputable() {
         let amount: Option<Decimal> = Some(Decimal::new(10000, 4));
-        let result = Transaction::check_transaction_is_disputable( amount, &TxTypes::Chargeback).is_ok();
+        let result = Transaction::check_transaction_is_disputable(amount, &TxTypes::Chargeback, false).is_ok();
         assert!(!result);
     }
 
@@ -150,114 +282,231 @@ mod tests {
     #[should_panic(expected = "System error, ledger shows a deposit with no amount")]
     fn valid_dispute_with_no_amount() {
         let amount: Option<Decimal> = None;
-        let _result = Transaction::check_transaction_is_disputable(amount, &TxTypes::Deposit);
+        let _result = Transaction::check_transaction_is_disputable(amount, &TxTypes::Deposit, false);
     }
 
     #[test]
     fn resolving_disputed_transaction() {
-        let result = Transaction::check_transaction_dispute_valid(true, true).is_ok();
-        assert!(result);
+        let result = Transaction::check_transaction_dispute_valid(&TxTypes::Resolve, DisputeState::Disputed, false);
+        assert_eq!(result.unwrap(), DisputeState::Resolved);
+        let result = Transaction::check_transaction_dispute_valid(&TxTypes::Chargeback, DisputeState::Disputed, false);
+        assert_eq!(result.unwrap(), DisputeState::ChargedBack);
     }
 
     #[test]
     fn disputing_undisputed_transaction() {
-        let result = Transaction::check_transaction_dispute_valid(true, true).is_ok();
-        assert!(result);
+        let result = Transaction::check_transaction_dispute_valid(&TxTypes::Dispute, DisputeState::Settled, false);
+        assert_eq!(result.unwrap(), DisputeState::Disputed);
     }
 
     #[test]
     fn resolving_undisputed_transaction() {
-        let result = Transaction::check_transaction_dispute_valid(true, false).is_ok();
+        let result = Transaction::check_transaction_dispute_valid(&TxTypes::Resolve, DisputeState::Settled, false).is_ok();
+        assert!(!result);
+        let result = Transaction::check_transaction_dispute_valid(&TxTypes::Chargeback, DisputeState::Resolved, true).is_ok();
         assert!(!result);
     }
 
     #[test]
     fn disputing_disputed_transaction() {
-        let result =  Transaction::check_transaction_dispute_valid(false, true).is_ok();
+        let result = Transaction::check_transaction_dispute_valid(&TxTypes::Dispute, DisputeState::Disputed, true).is_ok();
+        assert!(!result);
+    }
+
+    #[test]
+    fn redisputing_resolved_transaction() {
+        let result = Transaction::check_transaction_dispute_valid(&TxTypes::Dispute, DisputeState::Resolved, false).is_ok();
         assert!(!result);
+        let result = Transaction::check_transaction_dispute_valid(&TxTypes::Dispute, DisputeState::Resolved, true);
+        assert_eq!(result.unwrap(), DisputeState::Disputed);
+    }
+
+    #[test]
+    fn charged_back_transaction_is_final() {
+        for tx_type in [TxTypes::Dispute, TxTypes::Resolve, TxTypes::Chargeback] {
+            assert!(Transaction::check_transaction_dispute_valid(&tx_type, DisputeState::ChargedBack, true).is_err());
+        }
+    }
+
+    #[test]
+    fn currencies_are_normalized() {
+        let mut currency = Some(String::from(" eur "));
+        assert!(Transaction::validate_currency(&mut currency).is_ok());
+        assert_eq!(currency.as_deref(), Some("EUR"));
+        let mut currency = Some(String::new());
+        assert!(Transaction::validate_currency(&mut currency).is_ok());
+        assert_eq!(currency, None);
+    }
+
+    #[test]
+    fn invalid_currencies() {
+        for code in ["EURO", "E1R", "€"] {
+            let result = Transaction::validate_currency(&mut Some(String::from(code)));
+            assert!(matches!(result, Err(error::Error::InvalidCurrency { .. })));
+        }
+    }
+
+    #[test]
+    fn amounts_follow_precision() {
+        let mut amount: Option<Decimal> = Some(Decimal::new(123456, 5));
+        assert!(Transaction::validate_transaction(&mut amount, &TxTypes::Deposit, None, Precision::default()).is_ok());
+        assert_eq!(amount, Some(Decimal::new(12346, 4)));
+        let precision = Precision {
+            scale: 2,
+            rounding: Rounding::Reject,
+        };
+        let result = Transaction::validate_transaction(&mut amount, &TxTypes::Withdrawal, None, precision);
+        assert!(matches!(result, Err(error::Error::TooPrecise { scale: 2, .. })));
     }
 
     #[test]
     fn valid_deposit() {
         let mut amount: Option<Decimal> = Some(Decimal::new(10000, 4));
-        let result = Transaction::validate_transaction(& mut amount, &TxTypes::Deposit).is_ok();
+        let result = Transaction::validate_transaction(&mut amount, &TxTypes::Deposit, None, Precision::default()).is_ok();
         assert!(result);
     }
 
     #[test]
     fn valid_withdrawal() {
         let mut amount: Option<Decimal> = Some(Decimal::new(10000, 4));
-        let result = Transaction::validate_transaction(& mut amount, &TxTypes::Withdrawal).is_ok();
+        let result = Transaction::validate_transaction(&mut amount, &TxTypes::Withdrawal, None, Precision::default()).is_ok();
         assert!(result);
     }
 
     #[test]
     fn negative_deposit() {
         let mut amount: Option<Decimal> = Some(Decimal::new(-10000, 4));
-        let result = Transaction::validate_transaction(& mut amount, &TxTypes::Deposit).is_ok();
+        let result = Transaction::validate_transaction(&mut amount, &TxTypes::Deposit, None, Precision::default()).is_ok();
         assert!(!result);
     }
 
     #[test]
     fn negative_withdrawal() {
         let mut amount: Option<Decimal> = Some(Decimal::new(-10000, 4));
-        let result = Transaction::validate_transaction(& mut amount, &TxTypes::Withdrawal).is_ok();
+        let result = Transaction::validate_transaction(&mut amount, &TxTypes::Withdrawal, None, Precision::default()).is_ok();
         assert!(!result);
     }
 
     #[test]
-    fn missing_amount_deposit(){
+    fn missing_amount_deposit() {
         let mut amount: Option<Decimal> = None;
-        let result = Transaction::validate_transaction(& mut amount, &TxTypes::Deposit).is_ok();
+        let result = Transaction::validate_transaction(&mut amount, &TxTypes::Deposit, None, Precision::default()).is_ok();
         assert!(!result);
     }
 
     #[test]
-    fn missing_amount_withdrawal(){
+    fn missing_amount_withdrawal() {
         let mut amount: Option<Decimal> = None;
-        let result = Transaction::validate_transaction(& mut amount, &TxTypes::Withdrawal).is_ok();
+        let result = Transaction::validate_transaction(&mut amount, &TxTypes::Withdrawal, None, Precision::default()).is_ok();
         assert!(!result);
     }
 
     #[test]
     fn valid_dispute() {
         let mut amount: Option<Decimal> = None;
-        let result = Transaction::validate_transaction(& mut amount, &TxTypes::Dispute).is_ok();
+        let result = Transaction::validate_transaction(&mut amount, &TxTypes::Dispute, None, Precision::default()).is_ok();
         assert!(result);
     }
 
     #[test]
     fn valid_resolve() {
         let mut amount: Option<Decimal> = None;
-        let result = Transaction::validate_transaction(& mut amount, &TxTypes::Resolve).is_ok();
+        let result = Transaction::validate_transaction(&mut amount, &TxTypes::Resolve, None, Precision::default()).is_ok();
         assert!(result);
     }
 
     #[test]
     fn valid_chargeback() {
         let mut amount: Option<Decimal> = None;
-        let result = Transaction::validate_transaction(& mut amount, &TxTypes::Chargeback).is_ok();
+        let result = Transaction::validate_transaction(&mut amount, &TxTypes::Chargeback, None, Precision::default()).is_ok();
         assert!(result);
     }
 
     #[test]
     fn amount_with_dispute() {
         let mut amount: Option<Decimal> = Some(Decimal::new(-10000, 4));
-        let result = Transaction::validate_transaction(& mut amount, &TxTypes::Dispute).is_ok();
+        let result = Transaction::validate_transaction(&mut amount, &TxTypes::Dispute, None, Precision::default()).is_ok();
         assert!(!result);
     }
 
     #[test]
     fn amount_with_resolve() {
         let mut amount: Option<Decimal> = Some(Decimal::new(-10000, 4));
-        let result = Transaction::validate_transaction(& mut amount, &TxTypes::Resolve).is_ok();
+        let result = Transaction::validate_transaction(&mut amount, &TxTypes::Resolve, None, Precision::default()).is_ok();
         assert!(!result);
     }
 
     #[test]
     fn amount_with_chargeback() {
         let mut amount: Option<Decimal> = Some(Decimal::new(-10000, 4));
-        let result = Transaction::validate_transaction(& mut amount, &TxTypes::Chargeback).is_ok();
+        let result = Transaction::validate_transaction(&mut amount, &TxTypes::Chargeback, None, Precision::default()).is_ok();
         assert!(!result);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn typed_validation_errors() {
+        let mut amount: Option<Decimal> = Some(Decimal::new(-10000, 4));
+        let result = Transaction::validate_transaction(&mut amount, &TxTypes::Deposit, None, Precision::default());
+        assert!(matches!(result, Err(error::Error::NegativeAmount { .. })));
+        let mut amount: Option<Decimal> = None;
+        let result = Transaction::validate_transaction(&mut amount, &TxTypes::Withdrawal, None, Precision::default());
+        assert!(matches!(
+            result,
+            Err(error::Error::MissingAmount {
+                tx_type: TxTypes::Withdrawal
+            })
+        ));
+        let mut amount: Option<Decimal> = Some(Decimal::new(10000, 4));
+        let result = Transaction::validate_transaction(&mut amount, &TxTypes::Chargeback, None, Precision::default());
+        assert!(matches!(
+            result,
+            Err(error::Error::UnexpectedAmount {
+                tx_type: TxTypes::Chargeback
+            })
+        ));
+    }
+
+    #[test]
+    fn typed_dispute_errors() {
+        let result = Transaction::check_transaction_dispute_valid(&TxTypes::Dispute, DisputeState::Disputed, false);
+        assert!(matches!(
+            result,
+            Err(error::Error::InvalidDisputeState {
+                state: DisputeState::Disputed,
+                ..
+            })
+        ));
+        let result = Transaction::check_transaction_is_disputable(Some(Decimal::new(10000, 4)), &TxTypes::Withdrawal, false);
+        assert!(matches!(
+            result,
+            Err(error::Error::NotDisputable {
+                tx_type: TxTypes::Withdrawal
+            })
+        ));
+    }
+
+    #[test]
+    fn admin_requires_reason() {
+        let mut amount: Option<Decimal> = None;
+        let result = Transaction::validate_transaction(&mut amount, &TxTypes::Unlock, Some("chargeback reviewed"), Precision::default());
+        assert!(result.is_ok());
+        let result = Transaction::validate_transaction(&mut amount, &TxTypes::Freeze, None, Precision::default());
+        assert!(matches!(result, Err(error::Error::MissingReason { tx_type: TxTypes::Freeze })));
+        let result = Transaction::validate_transaction(&mut amount, &TxTypes::Close, Some(" "), Precision::default());
+        assert!(matches!(result, Err(error::Error::MissingReason { tx_type: TxTypes::Close })));
+    }
+
+    #[test]
+    fn amount_with_admin() {
+        let mut amount: Option<Decimal> = Some(Decimal::new(10000, 4));
+        let result = Transaction::validate_transaction(&mut amount, &TxTypes::Unlock, Some("reviewed"), Precision::default());
+        assert!(matches!(result, Err(error::Error::UnexpectedAmount { tx_type: TxTypes::Unlock })));
+    }
+
+    #[test]
+    fn fees_are_refused_as_input() {
+        let mut amount: Option<Decimal> = Some(Decimal::new(10000, 4));
+        let result = Transaction::validate_transaction(&mut amount, &TxTypes::Fee, None, Precision::default());
+        assert!(matches!(result, Err(error::Error::ReservedType { tx_type: TxTypes::Fee })));
+    }
+}
@@ -0,0 +1,215 @@
+use crate::client_config::ClientConfig;
+use crate::error;
+use crate::fees::FeeSchedule;
+use crate::rules::RuleSet;
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+///Rules of the engine that vary between deployments. The default policy follows the rules
+/// described in the README.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Policy {
+    ///Whether withdrawals may be disputed as well as deposits. A disputed withdrawal holds the
+    /// withdrawn funds pending investigation, a resolve finalizes the withdrawal, and a
+    /// chargeback credits the funds back to the client.
+    pub withdrawal_disputes: bool,
+    ///Whether a resolved transaction may be disputed again. Charged back transactions can never
+    /// be disputed again.
+    pub redisputes: bool,
+    ///Precision of deposit and withdrawal amounts in any currency without its own precision.
+    pub precision: Precision,
+    ///Precision of deposit and withdrawal amounts by currency code, overriding the precision of
+    /// the run.
+    pub currency_precision: BTreeMap<String, Precision>,
+    ///Fees charged on deposits and withdrawals, credited to the schedule's house account.
+    pub fees: FeeSchedule,
+    ///Settings of individual clients, such as their overdraft limits.
+    pub client_config: ClientConfig,
+    ///What happens when a dispute of a deposit would take available funds below the client's
+    /// overdraft limit.
+    pub dispute_overdraft: DisputeOverdraft,
+    ///Velocity and fraud rules checked against deposits and withdrawals before they are applied.
+    pub rules: RuleSet,
+}
+
+impl Policy {
+    ///Precision of amounts in a currency.
+    pub fn precision(&self, currency: &str) -> Precision {
+        self.currency_precision.get(currency).copied().unwrap_or(self.precision)
+    }
+}
+
+///What happens when a dispute of a deposit would take a client's available funds below its
+/// overdraft limit. Disputes of withdrawals never reduce available funds, so aren't affected.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DisputeOverdraft {
+    ///Hold the full amount, however far below the limit it takes available funds.
+    #[default]
+    Allow,
+    ///Hold only as much of the amount as the limit allows. Resolves and chargebacks release or
+    /// reverse only what was held.
+    Cap,
+    ///Refuse the dispute.
+    Reject,
+}
+
+impl FromStr for DisputeOverdraft {
+    type Err = error::Error;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy {
+            "allow" => Ok(DisputeOverdraft::Allow),
+            "cap" => Ok(DisputeOverdraft::Cap),
+            "reject" => Ok(DisputeOverdraft::Reject),
+            _ => Err(error::Error::InvalidArgument(format!("Unknown dispute overdraft policy: {}", policy))),
+        }
+    }
+}
+
+///How deposit and withdrawal amounts with more decimal places than the scale are handled.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Rounding {
+    ///Round to the nearest value, with midpoints rounded to the even neighbour.
+    #[default]
+    Bankers,
+    ///Round to the nearest value, with midpoints rounded away from zero.
+    HalfUp,
+    ///Drop the extra decimal places.
+    Truncate,
+    ///Refuse the transaction.
+    Reject,
+}
+
+impl FromStr for Rounding {
+    type Err = error::Error;
+
+    fn from_str(rounding: &str) -> Result<Self, Self::Err> {
+        match rounding {
+            "bankers" => Ok(Rounding::Bankers),
+            "half-up" => Ok(Rounding::HalfUp),
+            "truncate" => Ok(Rounding::Truncate),
+            "reject" => Ok(Rounding::Reject),
+            _ => Err(error::Error::InvalidArgument(format!("Unknown rounding mode: {}", rounding))),
+        }
+    }
+}
+
+///Number of decimal places amounts are kept to, and how extra places are handled. Defaults to
+/// four places with banker's rounding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Precision {
+    ///Decimal places kept, at most 28.
+    pub scale: u32,
+    ///How amounts with more decimal places are handled.
+    pub rounding: Rounding,
+}
+
+impl Default for Precision {
+    fn default() -> Self {
+        Self {
+            scale: 4,
+            rounding: Rounding::Bankers,
+        }
+    }
+}
+
+impl Precision {
+    ///Largest scale a decimal can hold.
+    pub const MAX_SCALE: u32 = 28;
+
+    ///Brings an amount to the scale, or refuses it if it is more precise than the scale allows
+    /// and the rounding mode is reject.
+    pub fn apply(&self, amount: Decimal) -> Result<Decimal, error::Error> {
+        let rounded = amount.round_dp_with_strategy(self.scale, self.strategy(RoundingStrategy::ToZero));
+        if self.rounding == Rounding::Reject && rounded != amount {
+            Err(error::Error::TooPrecise { amount, scale: self.scale })
+        } else {
+            Ok(rounded)
+        }
+    }
+
+    ///Brings an amount worked out by the engine, such as a fee, to the scale. Such amounts are
+    /// never refused, so they are rounded with banker's rounding if the rounding mode is reject.
+    pub fn round(&self, amount: Decimal) -> Decimal {
+        amount.round_dp_with_strategy(self.scale, self.strategy(RoundingStrategy::MidpointNearestEven))
+    }
+
+    fn strategy(&self, reject: RoundingStrategy) -> RoundingStrategy {
+        match self.rounding {
+            Rounding::Bankers => RoundingStrategy::MidpointNearestEven,
+            Rounding::HalfUp => RoundingStrategy::MidpointAwayFromZero,
+            Rounding::Truncate => RoundingStrategy::ToZero,
+            Rounding::Reject => reject,
+        }
+    }
+}
+
+///Passes numbers in a json config through as their original text, so amounts keep full precision
+/// rather than being read as floats. Numbers under the kept keys, such as client ids, are left as
+/// numbers.
+pub(crate) fn amounts_as_text(value: Value, keep: &[&str]) -> Value {
+    match value {
+        Value::Object(map) => Value::Object(
+            map.into_iter()
+                .map(|(key, value)| match value {
+                    Value::Number(n) if !keep.contains(&key.as_str()) => (key, Value::String(n.to_string())),
+                    _ => (key, amounts_as_text(value, keep)),
+                })
+                .collect(),
+        ),
+        Value::Array(values) => Value::Array(values.into_iter().map(|value| amounts_as_text(value, keep)).collect()),
+        _ => value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error;
+    use crate::policy::{DisputeOverdraft, Policy, Precision, Rounding};
+    use rust_decimal_macros::dec;
+    use std::str::FromStr;
+
+    fn precision(scale: u32, rounding: Rounding) -> Precision {
+        Precision { scale, rounding }
+    }
+
+    #[test]
+    fn rounding_modes() {
+        assert_eq!(precision(2, Rounding::Bankers).apply(dec!(1.125)).unwrap(), dec!(1.12));
+        assert_eq!(precision(2, Rounding::Bankers).apply(dec!(1.135)).unwrap(), dec!(1.14));
+        assert_eq!(precision(2, Rounding::HalfUp).apply(dec!(1.125)).unwrap(), dec!(1.13));
+        assert_eq!(precision(2, Rounding::Truncate).apply(dec!(1.129)).unwrap(), dec!(1.12));
+        assert_eq!(Precision::default().apply(dec!(1.00005)).unwrap(), dec!(1.0000));
+    }
+
+    #[test]
+    fn reject_refuses_only_extra_places() {
+        let reject = precision(2, Rounding::Reject);
+        assert_eq!(reject.apply(dec!(1.50000)).unwrap(), dec!(1.5));
+        let result = reject.apply(dec!(1.505));
+        assert!(matches!(result, Err(error::Error::TooPrecise { scale: 2, .. })));
+    }
+
+    #[test]
+    fn currency_precision_overrides_the_run() {
+        let mut policy = Policy::default();
+        policy.currency_precision.insert(String::from("JPY"), precision(0, Rounding::Reject));
+        assert_eq!(policy.precision("JPY"), precision(0, Rounding::Reject));
+        assert_eq!(policy.precision("USD"), Precision::default());
+    }
+
+    #[test]
+    fn dispute_overdraft_from_str() {
+        assert_eq!(DisputeOverdraft::from_str("cap").unwrap(), DisputeOverdraft::Cap);
+        assert_eq!(DisputeOverdraft::default(), DisputeOverdraft::Allow);
+        assert!(DisputeOverdraft::from_str("ignore").is_err());
+    }
+
+    #[test]
+    fn rounding_from_str() {
+        assert_eq!(Rounding::from_str("half-up").unwrap(), Rounding::HalfUp);
+        assert!(Rounding::from_str("up").is_err());
+    }
+}
@@ -1,135 +1,552 @@
-use std::collections::HashMap;
-use std::fmt::Display;
-
 use crate::client_repo::ClientRepo;
+use crate::error;
+use crate::events::{Event, Outcome, Subscribers};
+use crate::ledger::Ledger;
 use crate::models::client::Client;
-use crate::transaction::{Transaction, TxTypes};
-use crate::{error, TransactionFeed};
-use log::{debug, info, trace};
+use crate::policy::{DisputeOverdraft, Policy};
+use crate::rejects::Rejection;
+use crate::rules::{Clock, Flag, RuleAction, RuleState};
+use crate::snapshot::Snapshot;
+use crate::transaction::{DisputeState, Transaction, TxTypes};
+use crate::transaction_feed::TransactionSource;
+use crate::wal::{LogRecord, WriteAheadLog};
+use log::{debug, error, info, trace};
+use rust_decimal::Decimal;
+use std::sync::mpsc;
 
 /// The main engine for processing transactions, making calls to clients, and ensuring that
-/// transactions are applied according to the rules of the system. Keeps a ledger as a record
-/// of withdrawals and deposits, calls appropriate validation functions, and iterates through
-/// a feed of all transactions until complete.
+/// transactions are applied according to the rules of the system. Owns the client accounts and
+/// keeps a ledger as a record of every applied transaction. Transactions can be applied one at a
+/// time, or by iterating through a feed of transactions until complete. State is kept between
+/// calls, so balances and the ledger can be queried at any point.
 #[derive(Default, Debug)]
 pub struct Processor {
-    ledger: HashMap<u32, Transaction>,
+    ledger: Ledger,
+    clients: ClientRepo,
+    policy: Policy,
+    wal: Option<WriteAheadLog>,
+    subscribers: Subscribers,
+    rule_state: RuleState,
+    ///Whether the house account is kept by another shard of a sharded run, which is sent the fees
+    /// charged here instead.
+    house_elsewhere: bool,
 }
 
 impl Processor {
-    ///Associated function that self constructs and then iterates through the provided
-    /// transaction feed, handling transactions one by one and updating the client.
-    /// Erroneous csv records are skipped and errors are logged. Transactions are validated,
-    /// and if valid, processed only if the client is not locked.
+    ///Creates a processor with no clients and an empty ledger.
+    pub fn new() -> Self {
+        Self { ..Default::default() }
+    }
+
+    ///Creates a processor with no clients and an empty ledger, applying the rules of the policy.
+    pub fn with_policy(policy: Policy) -> Self {
+        let mut processor = Self {
+            policy,
+            ..Default::default()
+        };
+        processor.prepare_clients();
+        processor
+    }
+
+    ///Creates a processor holding the clients and ledger of a snapshot, applying the rules of
+    /// the policy.
+    pub fn from_snapshot(snapshot: Snapshot, policy: Policy) -> Self {
+        let (clients, ledger) = snapshot.restore();
+        let mut processor = Self {
+            ledger,
+            clients,
+            policy,
+            ..Default::default()
+        };
+        processor.prepare_clients();
+        processor
+    }
+
+    ///Creates a processor for one shard of a sharded run, holding the clients and ledger of the
+    /// shard's snapshot. Unless the shard owns the house account, fees charged are left for the
+    /// caller to credit to the shard that does.
+    pub(crate) fn for_shard(snapshot: Snapshot, policy: Policy, owns_house: bool) -> Self {
+        let (clients, ledger) = snapshot.restore();
+        let mut processor = Self {
+            ledger,
+            clients,
+            policy,
+            house_elsewhere: !owns_house,
+            ..Default::default()
+        };
+        processor.prepare_clients();
+        processor
+    }
+
+    ///Applies the client settings of the policy, and adds the house account up front if the
+    /// policy charges fees, so it is listed whether or not any fee has been charged yet.
+    fn prepare_clients(&mut self) {
+        self.clients.configure(self.policy.client_config.clone());
+        if !self.policy.fees.is_empty() && !self.house_elsewhere {
+            self.clients.get_house(self.policy.fees.house);
+        }
+    }
+
+    ///Captures the clients and ledger so a later run can continue from them.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot::capture(&self.clients, &self.ledger)
+    }
+
+    ///Records every transaction in the write-ahead log before it is applied from now on.
+    pub fn set_write_ahead_log(&mut self, wal: WriteAheadLog) {
+        self.wal = Some(wal);
+    }
+
+    ///Counts daily volumes of rules by the days the clock returns, such as [`crate::utc_day`] for a
+    /// long running engine. Without a clock every transaction counts towards the same day.
+    pub fn set_clock(&mut self, clock: Clock) {
+        self.rule_state.set_clock(clock);
+    }
+
+    ///Whether a clock has been set for daily volumes.
+    pub fn has_clock(&self) -> bool {
+        self.rule_state.has_clock()
+    }
+
+    ///Stops recording transactions, returning the write-ahead log.
+    pub fn take_write_ahead_log(&mut self) -> Option<WriteAheadLog> {
+        self.wal.take()
+    }
+
+    ///Replays records recovered from a write-ahead log through the same rules, rebuilding the
+    /// state at the time they were logged. Records refused the first time are refused again and
+    /// passed to on_reject, so a rejects report started afresh still lists them. No events are
+    /// emitted, as they were emitted when the records were first handled. Returns the offset of
+    /// the last record read from a source, if any, so the input can be resumed after it.
+    pub fn recover<F>(&mut self, records: Vec<LogRecord>, mut on_reject: F) -> Result<Option<u64>, error::Error>
+    where
+        F: FnMut(Rejection) -> Result<(), error::Error>,
+    {
+        let mut offset = None;
+        for record in records {
+            offset = record.offset.or(offset);
+            let line = record.offset.unwrap_or_default();
+            let tx = record.into_transaction();
+            let (client, tx_id, tx_type) = (tx.client, tx.tx_id, tx.tx_type);
+            if let Err(err) = self.apply_transaction(tx, None) {
+                debug!("Recovered record at {} refused again: {}", line, err);
+                on_reject(Rejection::new(line, Some((client, tx_id, tx_type)), &err))?;
+            }
+        }
+        info!("Recovered write-ahead log up to offset {:?}", offset);
+        Ok(offset)
+    }
+
+    ///Rules applied by the processor.
+    pub fn policy(&self) -> &Policy {
+        &self.policy
+    }
+
+    ///Client accounts and their balances.
+    pub fn clients(&self) -> &ClientRepo {
+        &self.clients
+    }
+
+    ///Gets a client's account, if the client has been seen.
+    pub fn client(&self, id: u16) -> Option<&Client> {
+        self.clients.clients.get(&id)
+    }
+
+    ///Every transaction applied so far, including disputes, resolves and chargebacks.
+    pub fn ledger(&self) -> &Ledger {
+        &self.ledger
+    }
+
+    ///Subscribes a callback to the event emitted for every record handled, applied or refused.
+    pub fn subscribe<F: FnMut(&Event) + Send + 'static>(&mut self, callback: F) {
+        self.subscribers.subscribe(callback);
+    }
+
+    ///Subscribes a channel to the event emitted for every record handled, applied or refused.
+    pub fn subscribe_channel(&mut self) -> mpsc::Receiver<Event> {
+        self.subscribers.subscribe_channel()
+    }
+
+    ///Consumes the processor, returning the client accounts.
+    pub fn into_clients(self) -> ClientRepo {
+        self.clients
+    }
+
+    ///Iterates through the provided transaction source, applying transactions one by one and
+    /// updating the client. Erroneous csv records are skipped and errors are logged.
+    /// Transactions are validated, and if valid, processed only if the client is not locked.
+    /// # Rejections
+    /// Every refused record is passed to on_reject along with its position in the source and
+    /// the reason it was refused. An error from on_reject stops processing and is returned.
+    /// # Events
+    /// An event is emitted to subscribers for every record, whether applied or refused.
+    /// # Write-ahead Log
+    /// If a write-ahead log is set, every parsed transaction is recorded before it is applied.
+    /// Failing to record a transaction stops processing and the error is returned.
+    /// # Rules
+    /// Deposits and withdrawals are checked against the policy's rules before they are applied,
+    /// once they are known not to be duplicates and to be covered by the client's funds.
+    /// A broken rule refuses the transaction, refuses it and locks the client, or applies it and
+    /// emits a Flagged event after the Applied event.
     /// # Deposits and Withdrawals
     /// Duplicate deposits and withdrawals are ignored. Errors are logged and successful
     /// transactions are added to the ledger
     /// # Dispute Related Transactions
     /// Dispute, Resolve, and Chargeback go through additional validation by Transaction.
-    /// If it passes, the client is updated and the disputed status is updated
+    /// If it passes, the client is updated, the dispute state of the original transaction moves
+    /// on, and the transaction is journaled in the ledger against the original transaction.
+    /// Only deposits can be disputed unless the policy allows withdrawal disputes, and resolved
+    /// transactions can only be disputed again if the policy allows re-disputes.
+    /// # Administrative Transactions
+    /// Unlock, Freeze, and Close must give an audit reason, and apply to locked clients so
+    /// accounts can be restored after a chargeback. Closed clients refuse everything.
     /// # Panic
     /// The system will panic if a dispute related transaction makes it to a deposit/withdrawal
     /// path or vice versa. This is impossible, and there is no way to process this transaction
     /// at this point, therefore it is a critical error.
     /// Likewise, amounts are checked through the Transaction module. If an amount isn't present
     /// where it should be, the system must panic because transaction screening is failing.
-    pub fn handle_transactions(consumer: TransactionFeed, clients: &mut ClientRepo) {
-        let mut processor = Self { ..Default::default() };
-        for transaction in consumer {
-            match transaction {
-                Err(err) => {
-                    error!("[!] Error parsing transaction: {:?}", err);
-                    continue;
-                }
-                Ok(mut tx) => {
-                    trace!("[!] transaction parsed = {:?}", tx);
-                    if let Err(err) = Transaction::validate_transaction(&mut tx.amount, &tx.tx_type) {
-                        error!("[!] Error validating transactions: {:?}", err);
-                        continue;
-                    }
-                    match clients.get_client(tx.client) {
-                        Ok(client) => {
-                            processor.process_transaction(client, tx);
-                        }
-                        Err(err) => {
-                            error!("[!] Error getting client: {:?}", err);
-                        }
-                    }
+    pub fn handle_transactions<S, F>(&mut self, mut consumer: S, mut on_reject: F) -> Result<(), error::Error>
+    where
+        S: TransactionSource,
+        F: FnMut(Rejection) -> Result<(), error::Error>,
+    {
+        while let Some(transaction) = consumer.next() {
+            let line = consumer.position();
+            if let Err(err) = &transaction {
+                error!("[!] Error parsing transaction at {}:{}: {:?}", consumer.name(), line, err);
+            }
+            if let Some(rejection) = self.handle_record(line, transaction)? {
+                on_reject(rejection)?;
+            }
+        }
+        Ok(())
+    }
+
+    ///Handles a single record read from a position in its source, returning the rejection if it
+    /// was refused. Errors are only returned if the record couldn't be recorded in the
+    /// write-ahead log, which must stop processing.
+    pub(crate) fn handle_record(&mut self, line: u64, transaction: Result<Transaction, error::Error>) -> Result<Option<Rejection>, error::Error> {
+        match transaction {
+            Err(err) => {
+                let rejection = Rejection::new(line, None, &err);
+                self.subscribers.emit(&Event::Rejected(rejection.clone()));
+                Ok(Some(rejection))
+            }
+            Ok(tx) => {
+                trace!("[!] transaction parsed = {:?}", tx);
+                self.log(LogRecord::new(line, &tx))?;
+                let (client, tx_id, tx_type) = (tx.client, tx.tx_id, tx.tx_type);
+                Ok(self
+                    .apply_at(line, tx)
+                    .err()
+                    .map(|err| Rejection::new(line, Some((client, tx_id, tx_type)), &err)))
+            }
+        }
+    }
+
+    ///Validates and applies a single transaction, returning the change to the client's balances
+    /// or the reason the transaction was refused. A refused transaction doesn't change any balances
+    /// or the ledger, though a client seen for the first time is still added with no funds, and a
+    /// transaction refused by a rule with the lock action locks the client and journals a freeze.
+    /// An event is emitted to subscribers either way, with a line of 0 as there is no source.
+    /// See handle_transactions for the rules applied.
+    pub fn apply(&mut self, tx: Transaction) -> Result<Outcome, error::Error> {
+        //Applied without a source, so logged without an offset to keep the resume point intact
+        self.log(LogRecord::unsourced(&tx))?;
+        self.apply_at(0, tx)
+    }
+
+    fn log(&mut self, record: LogRecord) -> Result<(), error::Error> {
+        match self.wal.as_mut() {
+            Some(wal) => wal.append(&record).map_err(|err| {
+                error!("[!] Fatal error writing to the write-ahead log: {:?}", err);
+                err
+            }),
+            None => Ok(()),
+        }
+    }
+
+    fn apply_at(&mut self, line: u64, tx: Transaction) -> Result<Outcome, error::Error> {
+        let (client, tx_id, tx_type) = (tx.client, tx.tx_id, tx.tx_type);
+        let result = self.apply_transaction(tx, None);
+        match &result {
+            Ok(outcome) => {
+                self.subscribers.emit(&Event::Applied(outcome.clone()));
+                for rule in &outcome.flags {
+                    self.subscribers.emit(&Event::Flagged(Flag {
+                        line,
+                        client,
+                        tx: tx_id,
+                        tx_type,
+                        rule: rule.clone(),
+                    }));
                 }
             }
+            Err(err) => self
+                .subscribers
+                .emit(&Event::Rejected(Rejection::new(line, Some((client, tx_id, tx_type)), err))),
+        }
+        result
+    }
+
+    ///Applies a transaction for one shard of a sharded run, without emitting events. The foreign
+    /// owner is the client holding the referenced transaction id in another shard's ledger, if any.
+    pub(crate) fn apply_in_shard(&mut self, tx: Transaction, foreign_owner: Option<u16>) -> Result<Outcome, error::Error> {
+        self.apply_transaction(tx, foreign_owner)
+    }
+
+    ///Credits a fee to the house account, whatever its status.
+    pub(crate) fn credit_house(&mut self, currency: &str, fee: Decimal) {
+        let house = self.policy.fees.house;
+        self.clients.get_house(house).deposit(currency, fee);
+        debug!("Fee of {} {} credited to house account {}", fee, currency, house);
+    }
+
+    ///Checks a transaction's structure and currency, bringing its amount to the precision of its
+    /// currency. Validation doesn't depend on any client or the ledger.
+    pub(crate) fn validate(policy: &Policy, tx: &mut Transaction) -> Result<(), error::Error> {
+        Transaction::validate_currency(&mut tx.currency)?;
+        let precision = policy.precision(tx.currency());
+        Transaction::validate_transaction(&mut tx.amount, &tx.tx_type, tx.reason.as_deref(), precision)
+    }
+
+    fn apply_transaction(&mut self, mut tx: Transaction, foreign_owner: Option<u16>) -> Result<Outcome, error::Error> {
+        if let Err(err) = Self::validate(&self.policy, &mut tx) {
+            error!("[!] Error validating transactions: {:?}", err);
+            return Err(err);
+        }
+        let (tx_id, tx_type) = (tx.tx_id, tx.tx_type);
+        //Dispute related transactions move funds in the currency of the original transaction
+        let currency = match tx_type {
+            TxTypes::Dispute | TxTypes::Resolve | TxTypes::Chargeback => self.ledger.get(tx_id).map(|original| original.currency()),
+            _ => None,
+        }
+        .unwrap_or(tx.currency())
+        .to_owned();
+        //Administrative transactions are the only way to act on a locked client
+        let client = if tx_type.is_admin() {
+            self.clients.get_client_for_admin(tx.client)
+        } else {
+            self.clients.get_client(tx.client)
+        };
+        let client = match client {
+            Ok(client) => client,
+            Err(err) => {
+                error!("[!] Error getting client: {:?}", err);
+                return Err(err);
+            }
+        };
+        let before = client.balances(&currency);
+        let amount = tx.amount;
+        //Rules are only checked against transactions that would otherwise be applied, so a
+        //transaction refused anyway can't lock the client
+        if let Some(owner) = foreign_owner {
+            return Err(Self::foreign_transaction_error(&tx, owner));
+        }
+        if matches!(tx_type, TxTypes::Deposit | TxTypes::Withdrawal) {
+            Self::check_deposit_withdrawal(&self.ledger, &self.policy, client, &tx)?;
+        }
+        let broken = match amount {
+            Some(amount) => self.rule_state.broken(&self.policy.rules, client.client, tx_type, &currency, amount),
+            None => Vec::new(),
+        };
+        if let Some(rule) = broken.iter().find(|rule| rule.action == RuleAction::Lock) {
+            client.freeze()?;
+            let reason = format!("Locked by rule {}", rule.name);
+            self.ledger.record_admin(TxTypes::Freeze, client.client, tx_id, Some(reason));
+            info!("Client {} locked by rule {} on transaction {}", client.client, rule.name, tx_id);
+            return Err(error::Error::RuleLocked {
+                client: client.client,
+                tx: tx_id,
+                rule: rule.name.clone(),
+            });
+        }
+        if let Some(rule) = broken.iter().find(|rule| rule.action == RuleAction::Reject) {
+            return Err(error::Error::RuleViolation {
+                client: client.client,
+                tx: tx_id,
+                rule: rule.name.clone(),
+            });
         }
+        //Rules with any other action have refused the transaction by now
+        let flags: Vec<String> = broken.iter().map(|rule| rule.name.clone()).collect();
+        let id = client.client;
+        let fee = Self::process_transaction(&mut self.ledger, &self.policy, client, tx)?;
+        if let Some(amount) = amount {
+            self.rule_state.record(&self.policy.rules, id, tx_type, &currency, amount);
+        }
+        if !fee.is_zero() && !self.house_elsewhere {
+            self.credit_house(&currency, fee);
+        }
+        let client = &self.clients.clients[&id];
+        let after = client.balances(&currency);
+        Ok(Outcome {
+            client: client.client,
+            tx: tx_id,
+            tx_type,
+            currency,
+            fee,
+            available_delta: after.available() - before.available(),
+            held_delta: after.held() - before.held(),
+            total_delta: after.total() - before.total(),
+            available: after.available(),
+            held: after.held(),
+            total: after.total(),
+            locked: client.locked,
+            flags,
+        })
     }
 
-    fn process_transaction(&mut self, client: &mut Client, mut transaction: Transaction) {
+    ///Applies a validated transaction to the client, returning the fee charged on it.
+    fn process_transaction(ledger: &mut Ledger, policy: &Policy, client: &mut Client, mut transaction: Transaction) -> Result<Decimal, error::Error> {
         match transaction.tx_type {
-            TxTypes::Deposit | TxTypes::Withdrawal => {
-                if let Err(err) = self.handle_deposits_withdrawals(&mut transaction, client) {
+            TxTypes::Deposit | TxTypes::Withdrawal => match Self::handle_deposits_withdrawals(ledger, policy, &mut transaction, client) {
+                Err(err) => {
                     error!("[!] Error processing deposit or withdrawal: {}", err);
-                } else {
+                    Err(err)
+                }
+                Ok(fee) => {
                     debug!("Successful transaction. Inserting into ledger: {:?}", transaction);
-                    self.ledger.insert(transaction.tx_id, transaction);
+                    let (tx_id, currency) = (transaction.tx_id, transaction.currency().to_owned());
+                    ledger.insert(transaction);
+                    if !fee.is_zero() {
+                        ledger.record_fee(client.client, tx_id, fee, &currency, policy.fees.house);
+                    }
+                    Ok(fee)
                 }
-            }
+            },
             TxTypes::Dispute | TxTypes::Resolve | TxTypes::Chargeback => {
                 debug!("Found dispute related transaction: {:?}", transaction.tx_type);
-                //resolve and chargeback resolve disputed transactions
-                trace!("Type of transaction checked is: {:?}", transaction.tx_type);
-                let resolving = transaction.tx_type != TxTypes::Dispute;
-                trace!("Resolving found to be: {}", resolving);
-                if let Err(err) = self.handle_disputed_transaction(client, &mut transaction, resolving) {
+                if let Err(err) = Self::handle_disputed_transaction(ledger, policy, client, &mut transaction) {
                     error!("[!] Error handling a dispute related transaction: {:?}", err);
+                    Err(err)
+                } else {
+                    Ok(Decimal::ZERO)
                 }
             }
+            TxTypes::Unlock | TxTypes::Freeze | TxTypes::Close => {
+                debug!("Found administrative transaction: {:?}", transaction.tx_type);
+                if let Err(err) = Self::handle_admin_transaction(ledger, client, transaction) {
+                    error!("[!] Error handling an administrative transaction: {:?}", err);
+                    Err(err)
+                } else {
+                    Ok(Decimal::ZERO)
+                }
+            }
+            TxTypes::Fee => panic!(
+                "System error, unreachable line. Fees are refused by validation \
+                and only journaled by the engine."
+            ),
+        }
+    }
+
+    ///The error the ledger would give for a transaction id held by another client, when that
+    /// client's ledger entries live in another shard.
+    fn foreign_transaction_error(transaction: &Transaction, owner: u16) -> error::Error {
+        let err = match transaction.tx_type {
+            TxTypes::Deposit | TxTypes::Withdrawal => error::Error::DuplicateTransaction { tx: transaction.tx_id },
+            TxTypes::Dispute | TxTypes::Resolve | TxTypes::Chargeback => error::Error::ClientMismatch {
+                client: transaction.client,
+                owner,
+            },
+            TxTypes::Unlock | TxTypes::Freeze | TxTypes::Close | TxTypes::Fee => panic!(
+                "System error, unreachable line. Administrative transactions \
+                and fees don't reference the ledger."
+            ),
         };
+        error!("[!] Error processing transaction held by another shard: {}", err);
+        err
     }
 
-    fn check_client_ids_match<T: Display + PartialEq>(id1: T, id2: T) -> Result<(), error::Error> {
+    fn check_client_ids_match(id1: u16, id2: u16) -> Result<(), error::Error> {
         if id1 != id2 {
-            Err(error::Error::Transaction(format!(
-                "Client {} is trying to action a transaction belonging to client {}",
-                id2, id1
-            )))
+            Err(error::Error::ClientMismatch { client: id2, owner: id1 })
         } else {
             debug!("Client ids {} and {} match.", id1, id2);
             Ok(())
         }
     }
 
-    fn get_disputed_transaction(&mut self, client: &Client, tx_id: u32, resolving: bool) -> Result<&mut Transaction, error::Error> {
-        match self.ledger.get_mut(&tx_id) {
+    ///A dispute related transaction may leave out the currency, but if given it must match the
+    /// original transaction.
+    fn check_currencies_match(original: &Transaction, transaction: &Transaction) -> Result<(), error::Error> {
+        match transaction.currency.as_deref() {
+            Some(found) if found != original.currency() => Err(error::Error::CurrencyMismatch {
+                tx: original.tx_id,
+                expected: original.currency().to_owned(),
+                found: found.to_owned(),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    fn get_disputed_transaction<'a>(
+        ledger: &'a mut Ledger,
+        policy: &Policy,
+        client: &Client,
+        transaction: &Transaction,
+    ) -> Result<(&'a mut Transaction, DisputeState), error::Error> {
+        match ledger.get_mut(transaction.tx_id) {
             Some(tx) => {
                 Processor::check_client_ids_match(tx.client, client.client)?;
-                Transaction::check_transaction_dispute_valid(resolving, tx.disputed)?;
-                Transaction::check_transaction_is_disputable(tx.amount, &tx.tx_type)?;
+                Processor::check_currencies_match(tx, transaction)?;
+                Transaction::check_transaction_is_disputable(tx.amount, &tx.tx_type, policy.withdrawal_disputes)?;
+                let next = Transaction::check_transaction_dispute_valid(&transaction.tx_type, tx.state, policy.redisputes)?;
                 debug!("Dispute related transaction is valid");
-                Ok(tx)
+                Ok((tx, next))
             }
-            None => Err(error::Error::Transaction(format!(
-                "Trying to action transaction {},\
-                but that transaction does not exist",
-                tx_id
-            ))),
+            None => Err(error::Error::UnknownTransaction { tx: transaction.tx_id }),
+        }
+    }
+
+    ///Refuses a deposit or withdrawal that would be refused when applied, as a duplicate or for
+    /// lack of funds, without changing the client or the ledger.
+    fn check_deposit_withdrawal(ledger: &Ledger, policy: &Policy, client: &Client, transaction: &Transaction) -> Result<(), error::Error> {
+        if ledger.contains(transaction.tx_id) {
+            return Err(error::Error::DuplicateTransaction { tx: transaction.tx_id });
+        }
+        if transaction.tx_type == TxTypes::Withdrawal {
+            //Impossible as amount is checked in validators, so in the absence of a dto, use .expect.
+            let amount = transaction.amount.expect("System error, amount check failed.");
+            let currency = transaction.currency();
+            let fee = policy.fees.fee(transaction.tx_type, amount, policy.precision(currency));
+            client.check_withdrawal(currency, amount, fee)?;
         }
+        Ok(())
     }
 
-    fn handle_deposits_withdrawals(&self, transaction: &mut Transaction, client: &mut Client) -> Result<(), error::Error> {
-        if self.ledger.contains_key(&transaction.tx_id) {
-            Err(error::Error::Transaction(String::from("Duplicate transaction")))
+    ///Applies a deposit or withdrawal along with its fee, returning the fee charged. A deposit is
+    /// never charged more than the amount deposited, while a withdrawal is refused unless the
+    /// available funds cover the amount and the fee.
+    fn handle_deposits_withdrawals(
+        ledger: &Ledger,
+        policy: &Policy,
+        transaction: &mut Transaction,
+        client: &mut Client,
+    ) -> Result<Decimal, error::Error> {
+        if ledger.contains(transaction.tx_id) {
+            Err(error::Error::DuplicateTransaction { tx: transaction.tx_id })
         } else {
             //Impossible as amount is checked in validators, so in the absence of a dto, use .expect.
-            let amount = transaction.amount.expect("System error, amount check failed.").round_dp(4);
-            trace!("Amount is : {}, and Tx amount is: {}", amount, transaction.amount.unwrap());
+            //The amount is already at the precision of its currency.
+            let amount = transaction.amount.expect("System error, amount check failed.");
+            let currency = transaction.currency();
+            let fee = policy.fees.fee(transaction.tx_type, amount, policy.precision(currency));
+            trace!("Amount is : {} {}, with a fee of {}", amount, currency, fee);
             match transaction.tx_type {
                 TxTypes::Deposit => {
-                    client.deposit(amount);
-                    Ok(())
+                    let fee = fee.min(amount);
+                    client.deposit(currency, amount);
+                    client.charge_fee(currency, fee);
+                    Ok(fee)
                 }
                 TxTypes::Withdrawal => {
-                    if let Err(err) = client.withdraw(amount) {
+                    if let Err(err) = client.withdraw_with_fee(currency, amount, fee) {
                         error!("[!] Error withdrawing funds: {:?}", err);
                         Err(err)
                     } else {
-                        Ok(())
+                        Ok(fee)
                     }
                 }
                 _ => panic!(
@@ -140,25 +557,54 @@ impl Processor {
         }
     }
 
-    fn handle_disputed_transaction(&mut self, client: &mut Client, transaction: &mut Transaction, resolving: bool) -> Result<(), error::Error> {
-        match self.get_disputed_transaction(client, transaction.tx_id, resolving) {
-            Err(err) => return Err(error::Error::Transaction(format!("Error validating dispute: {}", err))),
-            Ok(tx) => {
+    fn handle_disputed_transaction(
+        ledger: &mut Ledger,
+        policy: &Policy,
+        client: &mut Client,
+        transaction: &mut Transaction,
+    ) -> Result<(), error::Error> {
+        //A dispute capped at the overdraft limit holds less than the original amount, and the
+        //resolve or chargeback that follows acts on only what was held
+        let capped = ledger.held(transaction.tx_id);
+        let (amount, full_amount) = match Self::get_disputed_transaction(ledger, policy, client, transaction) {
+            Err(err) => {
+                error!("[!] Error validating dispute: {}", err);
+                return Err(err);
+            }
+            Ok((tx, next)) => {
                 trace!("Found disputed transaction: {:?}", tx);
                 //Impossible as amount is checked in validators, so in the absence of a dto, use .expect.
-                let amount = tx.amount.expect("System error, amount check failed.");
-                match transaction.tx_type {
+                let full_amount = tx.amount.expect("System error, amount check failed.");
+                let amount = capped.unwrap_or(full_amount);
+                let currency = tx.currency().to_owned();
+                //Disputed withdrawals reverse in the opposite direction to deposits
+                let withdrawal = tx.tx_type == TxTypes::Withdrawal;
+                let amount = match transaction.tx_type {
                     TxTypes::Dispute => {
-                        client.dispute(amount);
-                        tx.disputed = true;
+                        if withdrawal {
+                            client.dispute_withdrawal(&currency, full_amount);
+                            full_amount
+                        } else {
+                            let held = Self::dispute_hold(policy, client, &currency, tx.tx_id, full_amount)?;
+                            client.dispute(&currency, held);
+                            held
+                        }
                     }
                     TxTypes::Resolve => {
-                        client.resolve(amount);
-                        tx.disputed = false;
+                        if withdrawal {
+                            client.resolve_withdrawal(&currency, amount);
+                        } else {
+                            client.resolve(&currency, amount);
+                        }
+                        amount
                     }
                     TxTypes::Chargeback => {
-                        client.chargeback(amount);
-                        tx.disputed = false;
+                        if withdrawal {
+                            client.chargeback_withdrawal(&currency, amount);
+                        } else {
+                            client.chargeback(&currency, amount);
+                        }
+                        amount
                     }
                     //This function is called as a fall-through of transaction parser that handles
                     //all other cases. This should be impossible, and if reached is a critical bug.
@@ -167,10 +613,663 @@ impl Processor {
                         transactions must be handled before here."
                     ),
                 };
+                tx.state = next;
+                (amount, full_amount)
             }
-        }
-        //logging info here in absense of a ledger
+        };
+        let hold = (transaction.tx_type == TxTypes::Dispute && amount != full_amount).then_some(amount);
+        ledger.set_held(transaction.tx_id, hold);
+        ledger.record(transaction.tx_type, client.client, transaction.tx_id, amount);
         info!("Dispute related transaction successfully handled: {:?}", transaction);
         Ok(())
     }
+
+    ///The amount a dispute of a deposit holds. If holding all of it would take available funds
+    /// below the client's overdraft limit, the policy decides whether it is held anyway, capped at
+    /// what the limit allows, or refused.
+    fn dispute_hold(policy: &Policy, client: &Client, currency: &str, tx_id: u32, amount: Decimal) -> Result<Decimal, error::Error> {
+        let limit = client.overdraft(currency);
+        let room = client.balances(currency).available() + limit;
+        if amount <= room {
+            return Ok(amount);
+        }
+        debug!("Dispute of {} {} exceeds the overdraft room of {}", amount, currency, room);
+        match policy.dispute_overdraft {
+            DisputeOverdraft::Allow => Ok(amount),
+            DisputeOverdraft::Cap => Ok(room.max(Decimal::ZERO)),
+            DisputeOverdraft::Reject => Err(error::Error::OverLimit {
+                client: client.client,
+                tx: tx_id,
+                limit,
+            }),
+        }
+    }
+
+    fn handle_admin_transaction(ledger: &mut Ledger, client: &mut Client, transaction: Transaction) -> Result<(), error::Error> {
+        match transaction.tx_type {
+            TxTypes::Unlock => client.unlock()?,
+            TxTypes::Freeze => client.freeze()?,
+            TxTypes::Close => client.close()?,
+            //This function is called as a fall-through of transaction parser that handles
+            //all other cases. This should be impossible, and if reached is a critical bug.
+            _ => panic!(
+                "System error, unreachable line. Non-administrative \
+                transactions must be handled before here."
+            ),
+        };
+        info!("Administrative transaction successfully handled: {:?}", transaction);
+        ledger.record_admin(transaction.tx_type, client.client, transaction.tx_id, transaction.reason);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::client_config::{ClientConfig, ClientSettings};
+    use crate::error;
+    use crate::error::RejectReason;
+    use crate::events::Event;
+    use crate::fees::{FeeRule, FeeSchedule};
+    use crate::policy::{DisputeOverdraft, Policy, Precision, Rounding};
+    use crate::processor::Processor;
+    use crate::rules::{Check, Flag, RuleAction, RuleSet};
+    use crate::test_support::{rule, transaction};
+    use crate::transaction::{DisputeState, Transaction, TxTypes, DEFAULT_CURRENCY};
+    use crate::transaction_feed::{ResumeFrom, TransactionFeed};
+    use crate::wal::WriteAheadLog;
+    use rust_decimal::Decimal;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn rejections_are_reported() {
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,1.0\n\
+                     deposit,1,1,1.0\n\
+                     withdrawal,1,2,5.0\n\
+                     dispute,2,1,\n\
+                     resolve,1,1,\n\
+                     dispute,1,9,\n\
+                     deposit,x,3,1.0\n";
+        let feed = TransactionFeed::from_reader(String::from("test"), Box::new(input.as_bytes())).unwrap();
+        let mut processor = Processor::new();
+        let mut rejections = Vec::new();
+        processor
+            .handle_transactions(feed, |rejection| {
+                rejections.push((rejection.line, rejection.reason));
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(
+            rejections,
+            vec![
+                (3, RejectReason::DuplicateTransaction),
+                (4, RejectReason::InsufficientFunds),
+                (5, RejectReason::WrongClient),
+                (6, RejectReason::NotDisputed),
+                (7, RejectReason::UnknownTransaction),
+                (8, RejectReason::Malformed),
+            ]
+        );
+    }
+
+    #[test]
+    fn apply_keeps_state_between_calls() {
+        let mut processor = Processor::new();
+        let outcome = processor.apply(transaction(TxTypes::Deposit, 1, 1, Some(Decimal::new(20, 1)))).unwrap();
+        assert_eq!(outcome.available, Decimal::new(20, 1));
+        assert_eq!(processor.client(1).unwrap().balances(DEFAULT_CURRENCY).total(), Decimal::new(20, 1));
+
+        let outcome = processor.apply(transaction(TxTypes::Dispute, 1, 1, None)).unwrap();
+        assert_eq!(outcome.available, Decimal::ZERO);
+        assert_eq!(outcome.held, Decimal::new(20, 1));
+        assert_eq!(outcome.available_delta, Decimal::new(-20, 1));
+        assert_eq!(outcome.held_delta, Decimal::new(20, 1));
+        assert_eq!(outcome.total_delta, Decimal::ZERO);
+        assert_eq!(processor.ledger().get(1).unwrap().state, DisputeState::Disputed);
+        assert_eq!(processor.ledger().lifecycle(1).len(), 2);
+
+        let outcome = processor.apply(transaction(TxTypes::Chargeback, 1, 1, None)).unwrap();
+        assert!(outcome.locked);
+        assert_eq!(outcome.total, Decimal::ZERO);
+    }
+
+    #[test]
+    fn apply_refuses_invalid_transactions() {
+        let mut processor = Processor::new();
+        let result = processor.apply(transaction(TxTypes::Withdrawal, 1, 1, Some(Decimal::ONE)));
+        assert!(matches!(result, Err(error::Error::InsufficientFunds { .. })));
+        assert!(processor.ledger().journal().is_empty());
+        let result = processor.apply(transaction(TxTypes::Resolve, 1, 1, None));
+        assert!(matches!(result, Err(error::Error::UnknownTransaction { tx: 1 })));
+    }
+
+    #[test]
+    fn events_are_emitted_for_every_record() {
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,1.0\n\
+                     withdrawal,1,2,5.0\n\
+                     deposit,x,3,1.0\n";
+        let feed = TransactionFeed::from_reader(String::from("test"), Box::new(input.as_bytes())).unwrap();
+        let mut processor = Processor::new();
+        let events = processor.subscribe_channel();
+        let counted = Arc::new(Mutex::new(0));
+        let counter = Arc::clone(&counted);
+        processor.subscribe(move |_| *counter.lock().unwrap() += 1);
+        processor.handle_transactions(feed, |_| Ok(())).unwrap();
+
+        let events: Vec<Event> = events.try_iter().collect();
+        assert_eq!(events.len(), 3);
+        assert_eq!(*counted.lock().unwrap(), 3);
+        match &events[0] {
+            Event::Applied(outcome) => {
+                assert_eq!((outcome.client, outcome.tx, outcome.tx_type), (1, 1, TxTypes::Deposit));
+                assert_eq!(outcome.total_delta, Decimal::new(10, 1));
+                assert!(!outcome.locked);
+            }
+            other => panic!("Expected an applied event, found {:?}", other),
+        }
+        match &events[1] {
+            Event::Rejected(rejection) => {
+                assert_eq!(rejection.line, 3);
+                assert_eq!(rejection.reason, RejectReason::InsufficientFunds);
+            }
+            other => panic!("Expected a rejected event, found {:?}", other),
+        }
+        assert!(matches!(&events[2], Event::Rejected(rejection) if rejection.reason == RejectReason::Malformed));
+    }
+
+    fn withdrawal_processor() -> Processor {
+        let mut processor = Processor::with_policy(Policy {
+            withdrawal_disputes: true,
+            ..Default::default()
+        });
+        processor.apply(transaction(TxTypes::Deposit, 1, 1, Some(Decimal::new(50, 1)))).unwrap();
+        processor
+            .apply(transaction(TxTypes::Withdrawal, 1, 2, Some(Decimal::new(20, 1))))
+            .unwrap();
+        processor
+    }
+
+    #[test]
+    fn withdrawal_disputes_need_policy() {
+        let mut processor = Processor::new();
+        processor.apply(transaction(TxTypes::Deposit, 1, 1, Some(Decimal::new(50, 1)))).unwrap();
+        processor
+            .apply(transaction(TxTypes::Withdrawal, 1, 2, Some(Decimal::new(20, 1))))
+            .unwrap();
+        let result = processor.apply(transaction(TxTypes::Dispute, 1, 2, None));
+        assert!(matches!(
+            result,
+            Err(error::Error::NotDisputable {
+                tx_type: TxTypes::Withdrawal
+            })
+        ));
+    }
+
+    #[test]
+    fn withdrawal_dispute_holds_funds() {
+        let mut processor = withdrawal_processor();
+        let outcome = processor.apply(transaction(TxTypes::Dispute, 1, 2, None)).unwrap();
+        assert_eq!(outcome.available_delta, Decimal::ZERO);
+        assert_eq!(outcome.held_delta, Decimal::new(20, 1));
+        assert_eq!(outcome.total, Decimal::new(50, 1));
+        assert_eq!(processor.ledger().get(2).unwrap().state, DisputeState::Disputed);
+    }
+
+    #[test]
+    fn withdrawal_resolve_finalizes_withdrawal() {
+        let mut processor = withdrawal_processor();
+        processor.apply(transaction(TxTypes::Dispute, 1, 2, None)).unwrap();
+        let outcome = processor.apply(transaction(TxTypes::Resolve, 1, 2, None)).unwrap();
+        assert_eq!(
+            (outcome.available, outcome.held, outcome.total),
+            (Decimal::new(30, 1), Decimal::ZERO, Decimal::new(30, 1))
+        );
+        assert!(!outcome.locked);
+    }
+
+    #[test]
+    fn withdrawal_chargeback_credits_client() {
+        let mut processor = withdrawal_processor();
+        processor.apply(transaction(TxTypes::Dispute, 1, 2, None)).unwrap();
+        let outcome = processor.apply(transaction(TxTypes::Chargeback, 1, 2, None)).unwrap();
+        assert_eq!(
+            (outcome.available, outcome.held, outcome.total),
+            (Decimal::new(50, 1), Decimal::ZERO, Decimal::new(50, 1))
+        );
+        assert!(!outcome.locked);
+        assert_eq!(processor.ledger().lifecycle(2).len(), 3);
+    }
+
+    #[test]
+    fn redisputes_follow_policy() {
+        let mut processor = Processor::new();
+        processor.apply(transaction(TxTypes::Deposit, 1, 1, Some(Decimal::ONE))).unwrap();
+        processor.apply(transaction(TxTypes::Dispute, 1, 1, None)).unwrap();
+        processor.apply(transaction(TxTypes::Resolve, 1, 1, None)).unwrap();
+        assert_eq!(processor.ledger().get(1).unwrap().state, DisputeState::Resolved);
+        let result = processor.apply(transaction(TxTypes::Dispute, 1, 1, None));
+        assert_eq!(result.unwrap_err().reason(), RejectReason::DisputeClosed);
+
+        let mut processor = Processor::with_policy(Policy {
+            redisputes: true,
+            ..Default::default()
+        });
+        processor.apply(transaction(TxTypes::Deposit, 1, 1, Some(Decimal::ONE))).unwrap();
+        processor.apply(transaction(TxTypes::Dispute, 1, 1, None)).unwrap();
+        processor.apply(transaction(TxTypes::Resolve, 1, 1, None)).unwrap();
+        processor.apply(transaction(TxTypes::Dispute, 1, 1, None)).unwrap();
+        assert_eq!(processor.ledger().get(1).unwrap().state, DisputeState::Disputed);
+    }
+
+    #[test]
+    fn chargebacks_are_final() {
+        let mut processor = Processor::with_policy(Policy {
+            withdrawal_disputes: true,
+            redisputes: true,
+            ..Default::default()
+        });
+        processor.apply(transaction(TxTypes::Deposit, 1, 1, Some(Decimal::new(50, 1)))).unwrap();
+        processor.apply(transaction(TxTypes::Withdrawal, 1, 2, Some(Decimal::ONE))).unwrap();
+        processor.apply(transaction(TxTypes::Dispute, 1, 2, None)).unwrap();
+        processor.apply(transaction(TxTypes::Chargeback, 1, 2, None)).unwrap();
+        assert_eq!(processor.ledger().get(2).unwrap().state, DisputeState::ChargedBack);
+        let err = processor.apply(transaction(TxTypes::Dispute, 1, 2, None)).unwrap_err();
+        assert_eq!(err.reason(), RejectReason::DisputeClosed);
+    }
+
+    fn admin(tx_type: TxTypes, client: u16, tx_id: u32, reason: &str) -> Transaction {
+        Transaction {
+            reason: Some(String::from(reason)),
+            ..transaction(tx_type, client, tx_id, None)
+        }
+    }
+
+    #[test]
+    fn unlock_restores_account_after_chargeback() {
+        let mut processor = Processor::new();
+        processor.apply(transaction(TxTypes::Deposit, 1, 1, Some(Decimal::new(20, 1)))).unwrap();
+        processor.apply(transaction(TxTypes::Dispute, 1, 1, None)).unwrap();
+        processor.apply(transaction(TxTypes::Chargeback, 1, 1, None)).unwrap();
+        let result = processor.apply(transaction(TxTypes::Deposit, 1, 2, Some(Decimal::ONE)));
+        assert!(matches!(result, Err(error::Error::AccountLocked { client: 1 })));
+
+        let outcome = processor.apply(admin(TxTypes::Unlock, 1, 3, "investigation closed")).unwrap();
+        assert!(!outcome.locked);
+        assert_eq!(outcome.total_delta, Decimal::ZERO);
+        processor.apply(transaction(TxTypes::Deposit, 1, 2, Some(Decimal::ONE))).unwrap();
+        let entry = &processor.ledger().journal()[3];
+        assert_eq!((entry.tx_type, entry.reason.as_deref()), (TxTypes::Unlock, Some("investigation closed")));
+    }
+
+    #[test]
+    fn admin_transactions_are_validated() {
+        let mut processor = Processor::new();
+        let result = processor.apply(transaction(TxTypes::Freeze, 1, 1, None));
+        assert_eq!(result.unwrap_err().reason(), RejectReason::MissingReason);
+        let result = processor.apply(admin(TxTypes::Unlock, 1, 1, "unlock"));
+        assert_eq!(result.unwrap_err().reason(), RejectReason::NotLocked);
+        processor.apply(admin(TxTypes::Freeze, 1, 1, "suspicious activity")).unwrap();
+        processor.apply(admin(TxTypes::Close, 1, 2, "client request")).unwrap();
+        let result = processor.apply(admin(TxTypes::Unlock, 1, 3, "unlock"));
+        assert_eq!(result.unwrap_err().reason(), RejectReason::AccountClosed);
+        assert!(processor.client(1).unwrap().closed);
+    }
+
+    fn in_currency(currency: &str, transaction: Transaction) -> Transaction {
+        Transaction {
+            currency: Some(String::from(currency)),
+            ..transaction
+        }
+    }
+
+    #[test]
+    fn disputes_use_the_original_currency() {
+        let mut processor = Processor::new();
+        processor.apply(transaction(TxTypes::Deposit, 1, 1, Some(Decimal::new(20, 1)))).unwrap();
+        processor
+            .apply(in_currency("eur", transaction(TxTypes::Deposit, 1, 2, Some(Decimal::new(50, 1)))))
+            .unwrap();
+        let result = processor.apply(in_currency("EUR", transaction(TxTypes::Withdrawal, 1, 3, Some(Decimal::new(60, 1)))));
+        assert!(matches!(result, Err(error::Error::InsufficientFunds { .. })));
+
+        let outcome = processor.apply(transaction(TxTypes::Dispute, 1, 2, None)).unwrap();
+        assert_eq!(outcome.currency, "EUR");
+        assert_eq!(outcome.held_delta, Decimal::new(50, 1));
+        let client = processor.client(1).unwrap();
+        assert_eq!(client.balances("EUR").held(), Decimal::new(50, 1));
+        assert_eq!(client.balances(DEFAULT_CURRENCY).available(), Decimal::new(20, 1));
+
+        let err = processor
+            .apply(in_currency("GBP", transaction(TxTypes::Resolve, 1, 2, None)))
+            .unwrap_err();
+        assert_eq!(err.reason(), RejectReason::CurrencyMismatch);
+        let err = processor
+            .apply(in_currency("EURO", transaction(TxTypes::Resolve, 1, 2, None)))
+            .unwrap_err();
+        assert_eq!(err.reason(), RejectReason::InvalidCurrency);
+        processor.apply(in_currency("EUR", transaction(TxTypes::Chargeback, 1, 2, None))).unwrap();
+        let client = processor.client(1).unwrap();
+        assert_eq!(client.balances("EUR").total(), Decimal::ZERO);
+        assert!(client.locked);
+        assert_eq!(processor.ledger().lifecycle(2)[2].currency.as_deref(), Some("EUR"));
+    }
+
+    #[test]
+    fn amounts_follow_currency_precision() {
+        let mut policy = Policy::default();
+        policy.precision.rounding = Rounding::Truncate;
+        policy.currency_precision.insert(
+            String::from("GBP"),
+            Precision {
+                scale: 2,
+                rounding: Rounding::Reject,
+            },
+        );
+        let mut processor = Processor::with_policy(policy);
+        let outcome = processor
+            .apply(transaction(TxTypes::Deposit, 1, 1, Some(Decimal::new(123459, 5))))
+            .unwrap();
+        assert_eq!(outcome.available, Decimal::new(12345, 4));
+        let err = processor
+            .apply(in_currency("GBP", transaction(TxTypes::Deposit, 1, 2, Some(Decimal::new(1001, 3)))))
+            .unwrap_err();
+        assert_eq!(err.reason(), RejectReason::TooPrecise);
+        processor
+            .apply(in_currency("GBP", transaction(TxTypes::Deposit, 1, 2, Some(Decimal::new(1010, 3)))))
+            .unwrap();
+        assert_eq!(processor.client(1).unwrap().balances("GBP").total(), Decimal::new(101, 2));
+    }
+
+    #[test]
+    fn fees_are_credited_to_the_house_account() {
+        let policy = Policy {
+            fees: FeeSchedule {
+                house: 99,
+                deposit: Some(FeeRule {
+                    flat: Decimal::new(5, 0),
+                    ..Default::default()
+                }),
+                withdrawal: Some(FeeRule {
+                    percent: Decimal::new(1, 0),
+                    min: Some(Decimal::new(1, 0)),
+                    ..Default::default()
+                }),
+            },
+            ..Default::default()
+        };
+        let mut processor = Processor::with_policy(policy);
+        assert!(processor.client(99).is_some());
+
+        let outcome = processor.apply(transaction(TxTypes::Deposit, 1, 1, Some(Decimal::new(100, 0)))).unwrap();
+        assert_eq!(outcome.fee, Decimal::new(5, 0));
+        assert_eq!(outcome.available_delta, Decimal::new(95, 0));
+        //Available funds must cover the withdrawal and its fee
+        let err = processor
+            .apply(transaction(TxTypes::Withdrawal, 1, 2, Some(Decimal::new(95, 0))))
+            .unwrap_err();
+        assert_eq!(err.reason(), RejectReason::InsufficientFunds);
+        processor
+            .apply(transaction(TxTypes::Withdrawal, 1, 2, Some(Decimal::new(50, 0))))
+            .unwrap();
+        //A deposit is never charged more than it deposits
+        let outcome = processor.apply(transaction(TxTypes::Deposit, 2, 3, Some(Decimal::new(2, 0)))).unwrap();
+        assert_eq!(outcome.fee, Decimal::new(2, 0));
+        assert_eq!(outcome.total, Decimal::ZERO);
+
+        let client = processor.client(1).unwrap().balances(DEFAULT_CURRENCY);
+        assert_eq!(client.available(), Decimal::new(44, 0));
+        assert_eq!(client.fees(), Decimal::new(6, 0));
+        let house = processor.client(99).unwrap().balances(DEFAULT_CURRENCY);
+        assert_eq!(house.total(), Decimal::new(8, 0));
+        assert_eq!(house.fees(), Decimal::ZERO);
+
+        let fees: Vec<(u16, u32, Decimal, Option<u16>)> = processor
+            .ledger()
+            .journal()
+            .iter()
+            .filter(|entry| entry.tx_type == TxTypes::Fee)
+            .map(|entry| (entry.client, entry.tx, entry.amount, entry.house))
+            .collect();
+        assert_eq!(
+            fees,
+            vec![
+                (1, 1, Decimal::new(5, 0), Some(99)),
+                (1, 2, Decimal::new(1, 0), Some(99)),
+                (2, 3, Decimal::new(2, 0), Some(99))
+            ]
+        );
+        //Disputes hold the deposited amount, and fees aren't refunded
+        processor.apply(transaction(TxTypes::Dispute, 1, 1, None)).unwrap();
+        assert_eq!(processor.client(1).unwrap().balances(DEFAULT_CURRENCY).held(), Decimal::new(100, 0));
+    }
+
+    fn overdraft_policy(dispute_overdraft: DisputeOverdraft) -> Policy {
+        let mut client_config = ClientConfig::default();
+        client_config.clients.insert(
+            1,
+            ClientSettings {
+                overdraft: Decimal::new(10, 0),
+                ..Default::default()
+            },
+        );
+        Policy {
+            client_config,
+            dispute_overdraft,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn withdrawals_use_the_overdraft_limit() {
+        let mut processor = Processor::with_policy(overdraft_policy(DisputeOverdraft::Allow));
+        processor.apply(transaction(TxTypes::Deposit, 1, 1, Some(Decimal::new(5, 0)))).unwrap();
+        processor.apply(transaction(TxTypes::Deposit, 2, 2, Some(Decimal::new(5, 0)))).unwrap();
+        let outcome = processor
+            .apply(transaction(TxTypes::Withdrawal, 1, 3, Some(Decimal::new(15, 0))))
+            .unwrap();
+        assert_eq!(outcome.available, Decimal::new(-10, 0));
+        let err = processor
+            .apply(transaction(TxTypes::Withdrawal, 1, 4, Some(Decimal::new(1, 2))))
+            .unwrap_err();
+        assert_eq!(err.reason(), RejectReason::InsufficientFunds);
+        //Clients left out of the config have no overdraft
+        let err = processor
+            .apply(transaction(TxTypes::Withdrawal, 2, 5, Some(Decimal::new(6, 0))))
+            .unwrap_err();
+        assert_eq!(err.reason(), RejectReason::InsufficientFunds);
+    }
+
+    #[test]
+    fn disputes_below_the_overdraft_limit_follow_the_policy() {
+        let spent = |dispute_overdraft| {
+            let mut processor = Processor::with_policy(overdraft_policy(dispute_overdraft));
+            processor.apply(transaction(TxTypes::Deposit, 1, 1, Some(Decimal::new(30, 0)))).unwrap();
+            processor
+                .apply(transaction(TxTypes::Withdrawal, 1, 2, Some(Decimal::new(25, 0))))
+                .unwrap();
+            processor
+        };
+
+        let mut processor = spent(DisputeOverdraft::Allow);
+        let outcome = processor.apply(transaction(TxTypes::Dispute, 1, 1, None)).unwrap();
+        assert_eq!(outcome.available, Decimal::new(-25, 0));
+
+        let mut processor = spent(DisputeOverdraft::Reject);
+        let err = processor.apply(transaction(TxTypes::Dispute, 1, 1, None)).unwrap_err();
+        assert_eq!(err.reason(), RejectReason::OverLimit);
+        assert_eq!(processor.ledger().get(1).unwrap().state, DisputeState::Settled);
+
+        let mut processor = spent(DisputeOverdraft::Cap);
+        let outcome = processor.apply(transaction(TxTypes::Dispute, 1, 1, None)).unwrap();
+        assert_eq!(outcome.available, Decimal::new(-10, 0));
+        assert_eq!(outcome.held, Decimal::new(15, 0));
+        assert_eq!(processor.ledger().held(1), Some(Decimal::new(15, 0)));
+        //Only what was held is charged back
+        let outcome = processor.apply(transaction(TxTypes::Chargeback, 1, 1, None)).unwrap();
+        assert_eq!(outcome.held, Decimal::ZERO);
+        assert_eq!(outcome.total, Decimal::new(-10, 0));
+        assert_eq!(processor.ledger().held(1), None);
+        let amounts: Vec<Decimal> = processor.ledger().lifecycle(1).iter().map(|entry| entry.amount).collect();
+        assert_eq!(amounts, vec![Decimal::new(30, 0), Decimal::new(15, 0), Decimal::new(15, 0)]);
+    }
+
+    #[test]
+    fn rules_reject_flag_and_lock() {
+        let policy = Policy {
+            rules: RuleSet {
+                rules: vec![
+                    rule(
+                        "large",
+                        Check::MaxAmount {
+                            amount: Decimal::new(100, 0),
+                        },
+                        RuleAction::Reject,
+                    ),
+                    rule("burst", Check::WithdrawalFrequency { max: 1, window: 2 }, RuleAction::Flag),
+                    rule(
+                        "pass-through",
+                        Check::DepositThenWithdraw {
+                            window: 3,
+                            percent: Decimal::new(90, 0),
+                        },
+                        RuleAction::Lock,
+                    ),
+                ],
+            },
+            ..Default::default()
+        };
+        let mut processor = Processor::with_policy(policy);
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let recorded = events.clone();
+        processor.subscribe(move |event| recorded.lock().unwrap().push(event.clone()));
+
+        let err = processor
+            .apply(transaction(TxTypes::Deposit, 1, 1, Some(Decimal::new(101, 0))))
+            .unwrap_err();
+        assert_eq!(err.reason(), RejectReason::RuleViolation);
+        assert!(processor.ledger().get(1).is_none());
+
+        processor.apply(transaction(TxTypes::Deposit, 1, 1, Some(Decimal::new(50, 0)))).unwrap();
+        processor.apply(transaction(TxTypes::Deposit, 1, 2, Some(Decimal::new(50, 0)))).unwrap();
+        processor
+            .apply(transaction(TxTypes::Withdrawal, 1, 3, Some(Decimal::new(10, 0))))
+            .unwrap();
+        let outcome = processor
+            .apply(transaction(TxTypes::Withdrawal, 1, 4, Some(Decimal::new(10, 0))))
+            .unwrap();
+        assert_eq!(outcome.flags, vec![String::from("burst")]);
+        assert_eq!(outcome.total, Decimal::new(80, 0));
+        assert!(matches!(
+            events.lock().unwrap().last(),
+            Some(Event::Flagged(Flag { tx: 4, rule, .. })) if rule == "burst"
+        ));
+
+        processor.apply(transaction(TxTypes::Deposit, 2, 5, Some(Decimal::new(50, 0)))).unwrap();
+        let err = processor
+            .apply(transaction(TxTypes::Withdrawal, 2, 6, Some(Decimal::new(45, 0))))
+            .unwrap_err();
+        assert_eq!(err.reason(), RejectReason::LockedByRule);
+        let client = processor.client(2).unwrap();
+        assert!(client.locked);
+        assert_eq!(client.balances(DEFAULT_CURRENCY).total(), Decimal::new(50, 0));
+        let entry = processor.ledger().journal().last().unwrap();
+        assert_eq!(entry.tx_type, TxTypes::Freeze);
+        assert_eq!(entry.reason.as_deref(), Some("Locked by rule pass-through"));
+        let err = processor
+            .apply(transaction(TxTypes::Deposit, 2, 7, Some(Decimal::new(1, 0))))
+            .unwrap_err();
+        assert_eq!(err.reason(), RejectReason::AccountLocked);
+    }
+
+    #[test]
+    fn refused_transactions_never_trip_lock_rules() {
+        let policy = Policy {
+            rules: RuleSet {
+                rules: vec![rule(
+                    "large",
+                    Check::MaxAmount {
+                        amount: Decimal::new(100, 0),
+                    },
+                    RuleAction::Lock,
+                )],
+            },
+            ..Default::default()
+        };
+        let mut processor = Processor::with_policy(policy);
+        processor.apply(transaction(TxTypes::Deposit, 1, 1, Some(Decimal::new(50, 0)))).unwrap();
+        let err = processor
+            .apply(transaction(TxTypes::Deposit, 1, 1, Some(Decimal::new(500, 0))))
+            .unwrap_err();
+        assert!(matches!(err, error::Error::DuplicateTransaction { tx: 1 }));
+        let err = processor
+            .apply(transaction(TxTypes::Withdrawal, 1, 2, Some(Decimal::new(500, 0))))
+            .unwrap_err();
+        assert_eq!(err.reason(), RejectReason::InsufficientFunds);
+        assert!(!processor.client(1).unwrap().locked);
+        assert!(processor.ledger().journal().iter().all(|entry| entry.tx_type != TxTypes::Freeze));
+    }
+
+    #[test]
+    fn recovery_rebuilds_state_and_resumes() {
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,2.0\n\
+                     withdrawal,1,2,5.0\n\
+                     dispute,1,1,\n\
+                     deposit,2,3,1.0\n";
+        let file_path = std::env::temp_dir().join(format!("transaction-processor-recovery-{}.jsonl", std::process::id()));
+        let (wal, _) = WriteAheadLog::open(&file_path).unwrap();
+        let mut processor = Processor::new();
+        processor.set_write_ahead_log(wal);
+        //Simulate a crash after the third record by cutting the input short
+        let partial = &input[..input.find("deposit,2").unwrap()];
+        let feed = TransactionFeed::from_reader(String::from("test"), Box::new(partial.as_bytes())).unwrap();
+        processor.handle_transactions(feed, |_| Ok(())).unwrap();
+        drop(processor);
+
+        let (wal, records) = WriteAheadLog::open(&file_path).unwrap();
+        let mut recovered = Processor::new();
+        let mut rejections = Vec::new();
+        let offset = recovered
+            .recover(records, |rejection| {
+                rejections.push(rejection);
+                Ok(())
+            })
+            .unwrap()
+            .unwrap();
+        assert_eq!(offset, 4);
+        assert_eq!(rejections.len(), 1);
+        assert_eq!(rejections[0].line, 3);
+        assert_eq!(rejections[0].reason, RejectReason::InsufficientFunds);
+        assert_eq!(recovered.client(1).unwrap().balances(DEFAULT_CURRENCY).held(), Decimal::new(20, 1));
+        recovered.set_write_ahead_log(wal);
+        let feed = TransactionFeed::from_reader(String::from("test"), Box::new(input.as_bytes())).unwrap();
+        recovered.handle_transactions(ResumeFrom::new(feed, offset), |_| Ok(())).unwrap();
+
+        let mut expected = Processor::new();
+        let feed = TransactionFeed::from_reader(String::from("test"), Box::new(input.as_bytes())).unwrap();
+        expected.handle_transactions(feed, |_| Ok(())).unwrap();
+        assert_eq!(recovered.snapshot(), expected.snapshot());
+        recovered.take_write_ahead_log().unwrap().remove().unwrap();
+    }
+
+    #[test]
+    fn direct_applies_keep_the_resume_offset() {
+        let file_path = std::env::temp_dir().join(format!("transaction-processor-direct-{}.jsonl", std::process::id()));
+        let (wal, _) = WriteAheadLog::open(&file_path).unwrap();
+        let mut processor = Processor::new();
+        processor.set_write_ahead_log(wal);
+        let input = "type,client,tx,amount\ndeposit,1,1,2.0\n";
+        let feed = TransactionFeed::from_reader(String::from("test"), Box::new(input.as_bytes())).unwrap();
+        processor.handle_transactions(feed, |_| Ok(())).unwrap();
+        processor.apply(transaction(TxTypes::Deposit, 1, 2, Some(Decimal::new(1, 0)))).unwrap();
+        drop(processor);
+
+        let (wal, records) = WriteAheadLog::open(&file_path).unwrap();
+        assert_eq!(records[1].offset, None);
+        let mut recovered = Processor::new();
+        assert_eq!(recovered.recover(records, |_| Ok(())).unwrap(), Some(2));
+        assert_eq!(recovered.client(1).unwrap().balances(DEFAULT_CURRENCY).total(), Decimal::new(30, 1));
+        wal.remove().unwrap();
+    }
 }
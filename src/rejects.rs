@@ -0,0 +1,213 @@
+use crate::error;
+use crate::error::RejectReason;
+use crate::transaction::TxTypes;
+use csv::WriterBuilder;
+use serde::Serialize;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+///A record refused by the Processor, with enough detail for operations to find, review and
+/// reprocess it. Client, tx and type are empty if the record could not be parsed.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct Rejection {
+    ///Position of the record in its source, such as a line number. 0 if the transaction was
+    /// applied directly rather than read from a source.
+    pub line: u64,
+    ///Client id of the refused transaction.
+    pub client: Option<u16>,
+    ///Transaction id of the refused transaction.
+    pub tx: Option<u32>,
+    ///Type of the refused transaction.
+    #[serde(rename = "type")]
+    pub tx_type: Option<TxTypes>,
+    ///Machine readable reason the record was refused.
+    pub reason: RejectReason,
+    ///Human readable description of the error.
+    pub message: String,
+}
+
+impl Rejection {
+    ///Creates a rejection from the error that refused the record, and the client, tx id and type
+    /// of the transaction if it was parsed.
+    pub fn new(line: u64, transaction: Option<(u16, u32, TxTypes)>, err: &error::Error) -> Self {
+        Self {
+            line,
+            client: transaction.map(|(client, _, _)| client),
+            tx: transaction.map(|(_, tx, _)| tx),
+            tx_type: transaction.map(|(_, _, tx_type)| tx_type),
+            reason: err.reason(),
+            message: err.to_string(),
+        }
+    }
+}
+
+///Supported formats for the rejects report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectFormat {
+    ///Comma separated values with a header row.
+    Csv,
+    ///One json rejection object per line, also known as NDJSON.
+    JsonLines,
+}
+
+impl RejectFormat {
+    ///Guesses the report format from a file extension. Anything other than .jsonl or .ndjson
+    /// is written as csv.
+    pub fn from_path(file_path: &Path) -> RejectFormat {
+        match file_path.extension().and_then(|ext| ext.to_str()) {
+            Some("jsonl") | Some("ndjson") => RejectFormat::JsonLines,
+            _ => RejectFormat::Csv,
+        }
+    }
+}
+
+impl FromStr for RejectFormat {
+    type Err = error::Error;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "csv" => Ok(RejectFormat::Csv),
+            "jsonl" | "ndjson" => Ok(RejectFormat::JsonLines),
+            _ => Err(error::Error::InvalidArgument(format!("Unknown rejects format: {}", format))),
+        }
+    }
+}
+
+///Writes a report of every refused record. Writes are buffered, so call flush once done, or after
+/// each record if the report must be readable while processing continues.
+pub enum RejectWriter {
+    ///Report written as csv with a header row.
+    Csv(Box<csv::Writer<Box<dyn io::Write>>>),
+    ///Report written as one json object per line.
+    JsonLines(io::BufWriter<Box<dyn io::Write>>),
+}
+
+impl RejectWriter {
+    ///Creates a rejects report at the given path. If no format is given, it is guessed from
+    /// the file extension. An existing report is truncated, so a run recovering from a write-ahead
+    /// log starts its report from zero, and the records refused before the crash are reported
+    /// again as they are replayed, under the offsets they were logged with.
+    pub fn create(file_path: OsString, format: Option<RejectFormat>) -> Result<RejectWriter, error::Error> {
+        let format = format.unwrap_or_else(|| RejectFormat::from_path(Path::new(&file_path)));
+        let file = File::create(file_path)?;
+        Self::from_writer(format, Box::new(file))
+    }
+
+    ///Creates a rejects report on any writer. The csv header is written immediately so that an
+    /// empty report is still a valid csv file.
+    pub fn from_writer(format: RejectFormat, out: Box<dyn io::Write>) -> Result<RejectWriter, error::Error> {
+        match format {
+            RejectFormat::Csv => {
+                let mut wtr = WriterBuilder::new().has_headers(false).from_writer(out);
+                wtr.write_record(["line", "client", "tx", "type", "reason", "message"])?;
+                Ok(RejectWriter::Csv(Box::new(wtr)))
+            }
+            RejectFormat::JsonLines => Ok(RejectWriter::JsonLines(io::BufWriter::new(out))),
+        }
+    }
+
+    ///Appends a rejection to the report.
+    pub fn write(&mut self, rejection: &Rejection) -> Result<(), error::Error> {
+        match self {
+            RejectWriter::Csv(wtr) => wtr.serialize(rejection)?,
+            RejectWriter::JsonLines(wtr) => {
+                serde_json::to_writer(&mut *wtr, rejection)?;
+                io::Write::write_all(wtr, b"\n")?;
+            }
+        }
+        Ok(())
+    }
+
+    ///Flushes any buffered rejections to the underlying writer.
+    pub fn flush(&mut self) -> Result<(), error::Error> {
+        match self {
+            RejectWriter::Csv(wtr) => wtr.flush()?,
+            RejectWriter::JsonLines(wtr) => io::Write::flush(wtr)?,
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error;
+    use crate::error::RejectReason;
+    use crate::rejects::{RejectFormat, RejectWriter, Rejection};
+    use crate::transaction::TxTypes;
+    use std::cell::RefCell;
+    use std::io;
+    use std::rc::Rc;
+
+    ///Shared buffer so the report can be read back after the writer takes ownership.
+    #[derive(Clone, Default)]
+    struct Buffer(Rc<RefCell<Vec<u8>>>);
+
+    impl io::Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn rejections() -> Vec<Rejection> {
+        let duplicate = error::Error::DuplicateTransaction { tx: 1 };
+        let locked = error::Error::AccountLocked { client: 2 };
+        vec![
+            Rejection::new(3, Some((1, 1, TxTypes::Deposit)), &duplicate),
+            Rejection::new(4, Some((2, 5, TxTypes::Withdrawal)), &locked),
+        ]
+    }
+
+    #[test]
+    fn rejection_reason() {
+        let rejection = &rejections()[0];
+        assert_eq!(rejection.reason, RejectReason::DuplicateTransaction);
+        assert_eq!(rejection.message, "Duplicate transaction 1");
+    }
+
+    #[test]
+    fn csv_report() {
+        let buffer = Buffer::default();
+        let mut wtr = RejectWriter::from_writer(RejectFormat::Csv, Box::new(buffer.clone())).unwrap();
+        for rejection in rejections() {
+            wtr.write(&rejection).unwrap();
+        }
+        wtr.flush().unwrap();
+        assert_eq!(
+            String::from_utf8(buffer.0.borrow().clone()).unwrap(),
+            "line,client,tx,type,reason,message\n\
+             3,1,1,deposit,duplicate_transaction,Duplicate transaction 1\n\
+             4,2,5,withdrawal,account_locked,Client 2 is locked\n"
+        );
+    }
+
+    #[test]
+    fn json_lines_report() {
+        let buffer = Buffer::default();
+        let mut wtr = RejectWriter::from_writer(RejectFormat::JsonLines, Box::new(buffer.clone())).unwrap();
+        let malformed = Rejection::new(7, None, &error::Error::InvalidArgument(String::from("bad record")));
+        wtr.write(&malformed).unwrap();
+        wtr.flush().unwrap();
+        assert_eq!(
+            String::from_utf8(buffer.0.borrow().clone()).unwrap(),
+            "{\"line\":7,\"client\":null,\"tx\":null,\"type\":null,\"reason\":\"malformed\",\"message\":\"Invalid Argument: bad record\"}\n"
+        );
+    }
+
+    #[test]
+    fn empty_csv_report_has_header() {
+        let buffer = Buffer::default();
+        let mut wtr = RejectWriter::from_writer(RejectFormat::Csv, Box::new(buffer.clone())).unwrap();
+        wtr.flush().unwrap();
+        assert_eq!(
+            String::from_utf8(buffer.0.borrow().clone()).unwrap(),
+            "line,client,tx,type,reason,message\n"
+        );
+    }
+}
@@ -0,0 +1,437 @@
+use crate::error;
+use crate::policy::amounts_as_text;
+use crate::transaction::TxTypes;
+use crate::writer::write_atomically;
+use log::{debug, trace};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+///Velocity and fraud rules checked against every deposit and withdrawal after it is validated
+/// and before it is applied. Loaded from a json config file such as:
+///
+/// ```json
+/// {
+///     "rules": [
+///         { "name": "withdrawal-burst", "check": { "withdrawal_frequency": { "max": 3, "window": 5 } }, "action": "flag" },
+///         { "name": "large-amount", "check": { "max_amount": { "amount": "10000" } }, "action": "reject" },
+///         { "name": "daily-volume", "check": { "daily_volume": { "amount": "50000" } }, "action": "reject" },
+///         { "name": "pass-through", "check": { "deposit_then_withdraw": { "window": 2, "percent": "90" } }, "action": "lock" }
+///     ]
+/// }
+/// ```
+///
+/// Amounts are in units of the transaction's currency, and only the client's transactions in the
+/// same currency count towards volumes. The default rule set checks nothing.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuleSet {
+    ///Rules checked, in order.
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+}
+
+///A named check and the action taken when a transaction fails it.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    ///Name reported when the rule is broken.
+    pub name: String,
+    ///What the rule checks.
+    pub check: Check,
+    ///What happens to a transaction that fails the check.
+    pub action: RuleAction,
+}
+
+///What a rule checks. Windows count the client's applied deposits and withdrawals, including the
+/// transaction being checked. A window of 0, refused when rules are read, holds only the transaction
+/// being checked.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum Check {
+    ///At most max of the client's last window transactions may be withdrawals.
+    WithdrawalFrequency {
+        ///Withdrawals allowed in the window.
+        max: u32,
+        ///Number of transactions in the window.
+        window: u32,
+    },
+    ///No single deposit or withdrawal may be larger than the amount.
+    MaxAmount {
+        ///Largest amount allowed.
+        amount: Decimal,
+    },
+    ///The client's deposits and withdrawals on a day may add up to at most the amount. Transactions
+    /// carry no dates, so days are told by the processor's clock. Without a clock, as in a run over
+    /// a file, every transaction counts towards the same day.
+    DailyVolume {
+        ///Largest volume allowed in a day.
+        amount: Decimal,
+    },
+    ///A withdrawal may not take percent or more of a deposit made within the client's last
+    /// window transactions.
+    DepositThenWithdraw {
+        ///Number of transactions in the window.
+        window: u32,
+        ///Share of the deposit, such as 90 for 90%.
+        percent: Decimal,
+    },
+}
+
+///What happens to a transaction that breaks a rule.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleAction {
+    ///Refuse the transaction.
+    Reject,
+    ///Apply the transaction, reporting it for review.
+    Flag,
+    ///Refuse the transaction and lock the client pending investigation.
+    Lock,
+}
+
+///A transaction applied despite breaking a rule with the flag action, reported for review.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Flag {
+    ///Position of the record in its source, 0 if applied directly.
+    pub line: u64,
+    ///Client id of the flagged transaction.
+    pub client: u16,
+    ///Transaction id of the flagged transaction.
+    pub tx: u32,
+    ///Type of the flagged transaction.
+    #[serde(rename = "type")]
+    pub tx_type: TxTypes,
+    ///Name of the rule broken.
+    pub rule: String,
+}
+
+impl RuleSet {
+    ///Whether the rule set checks nothing.
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    ///Reads a rule set from any reader, refusing unknown fields and invalid rules. Amounts may be
+    /// given as strings or numbers.
+    pub fn from_reader<R: io::Read>(reader: R) -> Result<Self, error::Error> {
+        let value: serde_json::Value = serde_json::from_reader(reader)?;
+        let rules: Self = serde_json::from_value(amounts_as_text(value, &["max", "window"]))?;
+        rules.check()?;
+        Ok(rules)
+    }
+
+    ///Reads a rule set from a file.
+    pub fn read(file_path: &Path) -> Result<Self, error::Error> {
+        debug!("Loading rules from {:?}", file_path);
+        Self::from_reader(io::BufReader::new(fs::File::open(file_path)?))
+    }
+
+    ///Checks that rules have distinct names, windows aren't empty and amounts aren't negative.
+    pub fn check(&self) -> Result<(), error::Error> {
+        for (index, rule) in self.rules.iter().enumerate() {
+            let invalid = |message: &str| error::Error::InvalidArgument(format!("Invalid rule {:?}: {}", rule.name, message));
+            if rule.name.trim().is_empty() {
+                return Err(invalid("rules must be named"));
+            }
+            if self.rules[..index].iter().any(|other| other.name == rule.name) {
+                return Err(invalid("rule names must be unique"));
+            }
+            match rule.check {
+                Check::WithdrawalFrequency { window: 0, .. } | Check::DepositThenWithdraw { window: 0, .. } => {
+                    return Err(invalid("windows must hold at least one transaction"))
+                }
+                Check::MaxAmount { amount } | Check::DailyVolume { amount } | Check::DepositThenWithdraw { percent: amount, .. }
+                    if amount.is_sign_negative() =>
+                {
+                    return Err(invalid("amounts can't be negative"))
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    ///Longest window of any rule, so no more history is kept than the rules look at.
+    fn longest_window(&self) -> usize {
+        self.rules
+            .iter()
+            .map(|rule| match rule.check {
+                Check::WithdrawalFrequency { window, .. } | Check::DepositThenWithdraw { window, .. } => window as usize,
+                Check::MaxAmount { .. } | Check::DailyVolume { .. } => 0,
+            })
+            .max()
+            .unwrap_or_default()
+    }
+}
+
+///Writes flagged transactions to a csv report atomically.
+pub fn write_flags(flags: &[Flag], file_path: &Path) -> Result<(), error::Error> {
+    debug!("Writing {} flagged transactions to {:?}", flags.len(), file_path);
+    write_atomically(file_path, |out| {
+        let mut wtr = csv::WriterBuilder::new().has_headers(false).from_writer(out);
+        wtr.write_record(["line", "client", "tx", "type", "rule"])?;
+        for flag in flags {
+            wtr.serialize(flag)?;
+        }
+        wtr.flush()?;
+        Ok(())
+    })
+}
+
+///Returns the current day as a number, such as days since the unix epoch. Daily volumes are
+/// counted per day the clock returns.
+pub type Clock = fn() -> u64;
+
+///Days since the unix epoch in UTC, the clock of long running engines such as the server.
+pub fn utc_day() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs() / 86_400)
+        .unwrap_or_default()
+}
+
+///Recent activity of each client that rules are checked against. Activity is kept in memory only
+/// and isn't saved in snapshots, so windows and daily volumes start afresh in each run, including
+/// runs continuing from a snapshot. Without a clock they depend only on the transactions applied,
+/// so a run replayed from a write-ahead log checks rules exactly as the original did.
+#[derive(Debug, Default)]
+pub(crate) struct RuleState {
+    ///Clock telling the days daily volumes are counted in, if any.
+    clock: Option<Clock>,
+    ///Day the volumes were counted in.
+    day: u64,
+    ///Each client's most recent deposits and withdrawals, newest last.
+    recent: HashMap<u16, VecDeque<(TxTypes, String, Decimal)>>,
+    ///Each client's volume by currency on the day.
+    volume: HashMap<(u16, String), Decimal>,
+}
+
+impl RuleState {
+    ///Rules a deposit or withdrawal would break if applied, in rule order. Other transaction
+    /// types are never checked.
+    pub(crate) fn broken<'a>(&self, rules: &'a RuleSet, client: u16, tx_type: TxTypes, currency: &str, amount: Decimal) -> Vec<&'a Rule> {
+        if !matches!(tx_type, TxTypes::Deposit | TxTypes::Withdrawal) {
+            return Vec::new();
+        }
+        let empty = VecDeque::new();
+        let recent = self.recent.get(&client).unwrap_or(&empty);
+        let today = self.today();
+        let broken: Vec<&Rule> = rules
+            .rules
+            .iter()
+            .filter(|rule| match rule.check {
+                Check::WithdrawalFrequency { max, window } => {
+                    //Earlier transactions in the window, leaving room for this one
+                    let earlier = recent.iter().rev().take(window.saturating_sub(1) as usize);
+                    let withdrawals = earlier.filter(|(tx_type, _, _)| *tx_type == TxTypes::Withdrawal).count();
+                    tx_type == TxTypes::Withdrawal && withdrawals + 1 > max as usize
+                }
+                Check::MaxAmount { amount: max } => amount > max,
+                Check::DailyVolume { amount: max } => self.volume(today, client, currency) + amount > max,
+                Check::DepositThenWithdraw { window, percent } => {
+                    tx_type == TxTypes::Withdrawal
+                        && recent
+                            .iter()
+                            .rev()
+                            .take(window.saturating_sub(1) as usize)
+                            .any(|(earlier, deposit_currency, deposit)| {
+                                *earlier == TxTypes::Deposit && deposit_currency == currency && amount * Decimal::ONE_HUNDRED >= *deposit * percent
+                            })
+                }
+            })
+            .collect();
+        trace!("Client {} {:?} of {} {} breaks {} rules", client, tx_type, amount, currency, broken.len());
+        broken
+    }
+
+    ///Records an applied deposit or withdrawal.
+    pub(crate) fn record(&mut self, rules: &RuleSet, client: u16, tx_type: TxTypes, currency: &str, amount: Decimal) {
+        if rules.is_empty() || !matches!(tx_type, TxTypes::Deposit | TxTypes::Withdrawal) {
+            return;
+        }
+        let window = rules.longest_window();
+        if window > 0 {
+            let recent = self.recent.entry(client).or_default();
+            recent.push_back((tx_type, currency.to_owned(), amount));
+            if recent.len() > window {
+                recent.pop_front();
+            }
+        }
+        let today = self.today();
+        if today != self.day {
+            debug!("Daily volumes of day {} cleared on day {}", self.day, today);
+            self.volume.clear();
+            self.day = today;
+        }
+        *self.volume.entry((client, currency.to_owned())).or_default() += amount;
+    }
+
+    ///Sets the clock telling the days daily volumes are counted in.
+    pub(crate) fn set_clock(&mut self, clock: Clock) {
+        self.clock = Some(clock);
+    }
+
+    ///Whether a clock has been set.
+    pub(crate) fn has_clock(&self) -> bool {
+        self.clock.is_some()
+    }
+
+    fn today(&self) -> u64 {
+        self.clock.map_or(0, |clock| clock())
+    }
+
+    fn volume(&self, today: u64, client: u16, currency: &str) -> Decimal {
+        if today != self.day {
+            return Decimal::ZERO;
+        }
+        self.volume.get(&(client, currency.to_owned())).copied().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error;
+    use crate::rules::{Check, RuleAction, RuleSet, RuleState};
+    use crate::test_support::rule;
+    use crate::transaction::TxTypes;
+    use rust_decimal_macros::dec;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    fn rule_set(check: Check) -> RuleSet {
+        RuleSet {
+            rules: vec![rule("test", check, RuleAction::Reject)],
+        }
+    }
+
+    #[test]
+    fn withdrawal_frequency() {
+        let rules = rule_set(Check::WithdrawalFrequency { max: 2, window: 3 });
+        let mut state = RuleState::default();
+        let mut apply = |tx_type| {
+            let broken = !state.broken(&rules, 1, tx_type, "USD", dec!(1)).is_empty();
+            if !broken {
+                state.record(&rules, 1, tx_type, "USD", dec!(1));
+            }
+            broken
+        };
+        assert!(!apply(TxTypes::Withdrawal));
+        assert!(!apply(TxTypes::Withdrawal));
+        assert!(apply(TxTypes::Withdrawal));
+        assert!(!apply(TxTypes::Deposit));
+        //The first withdrawal has left the window
+        assert!(!apply(TxTypes::Withdrawal));
+    }
+
+    #[test]
+    fn max_amount_and_daily_volume() {
+        let rules = RuleSet {
+            rules: vec![
+                rule("max", Check::MaxAmount { amount: dec!(10) }, RuleAction::Reject),
+                rule("daily", Check::DailyVolume { amount: dec!(15) }, RuleAction::Flag),
+            ],
+        };
+        let mut state = RuleState::default();
+        let names = |state: &RuleState, amount| -> Vec<String> {
+            state
+                .broken(&rules, 1, TxTypes::Deposit, "USD", amount)
+                .iter()
+                .map(|rule| rule.name.clone())
+                .collect()
+        };
+        assert_eq!(names(&state, dec!(11)), vec!["max"]);
+        state.record(&rules, 1, TxTypes::Deposit, "USD", dec!(10));
+        assert_eq!(names(&state, dec!(6)), vec!["daily"]);
+        assert!(state.broken(&rules, 1, TxTypes::Deposit, "EUR", dec!(6)).is_empty());
+        assert!(state.broken(&rules, 2, TxTypes::Deposit, "USD", dec!(6)).is_empty());
+        //The next run starts afresh
+        assert!(names(&RuleState::default(), dec!(6)).is_empty());
+    }
+
+    static DAY: AtomicU64 = AtomicU64::new(1);
+
+    fn day() -> u64 {
+        DAY.load(Ordering::SeqCst)
+    }
+
+    #[test]
+    fn daily_volume_starts_afresh_each_day() {
+        let rules = rule_set(Check::DailyVolume { amount: dec!(15) });
+        let mut state = RuleState::default();
+        state.set_clock(day);
+        state.record(&rules, 1, TxTypes::Deposit, "USD", dec!(10));
+        assert_eq!(state.broken(&rules, 1, TxTypes::Withdrawal, "USD", dec!(6)).len(), 1);
+        DAY.store(2, Ordering::SeqCst);
+        assert!(state.broken(&rules, 1, TxTypes::Withdrawal, "USD", dec!(6)).is_empty());
+        state.record(&rules, 1, TxTypes::Withdrawal, "USD", dec!(6));
+        assert_eq!(state.broken(&rules, 1, TxTypes::Deposit, "USD", dec!(10)).len(), 1);
+    }
+
+    #[test]
+    fn deposit_then_withdraw() {
+        let rules = rule_set(Check::DepositThenWithdraw {
+            window: 2,
+            percent: dec!(90),
+        });
+        let mut state = RuleState::default();
+        state.record(&rules, 1, TxTypes::Deposit, "USD", dec!(100));
+        assert!(state.broken(&rules, 1, TxTypes::Withdrawal, "USD", dec!(89)).is_empty());
+        assert_eq!(state.broken(&rules, 1, TxTypes::Withdrawal, "USD", dec!(90)).len(), 1);
+        assert!(state.broken(&rules, 1, TxTypes::Withdrawal, "EUR", dec!(90)).is_empty());
+        state.record(&rules, 1, TxTypes::Withdrawal, "USD", dec!(1));
+        //The deposit has left the window
+        assert!(state.broken(&rules, 1, TxTypes::Withdrawal, "USD", dec!(90)).is_empty());
+    }
+
+    #[test]
+    fn unchecked_empty_windows_only_hold_the_transaction() {
+        let rules = RuleSet {
+            rules: vec![
+                rule("frequency", Check::WithdrawalFrequency { max: 1, window: 0 }, RuleAction::Reject),
+                rule(
+                    "pass-through",
+                    Check::DepositThenWithdraw {
+                        window: 0,
+                        percent: dec!(90),
+                    },
+                    RuleAction::Reject,
+                ),
+            ],
+        };
+        let mut state = RuleState::default();
+        state.record(&rules, 1, TxTypes::Deposit, "USD", dec!(100));
+        assert!(state.broken(&rules, 1, TxTypes::Withdrawal, "USD", dec!(100)).is_empty());
+    }
+
+    #[test]
+    fn rule_sets_are_read_from_json() {
+        let json = r#"{"rules": [
+            {"name": "burst", "check": {"withdrawal_frequency": {"max": 3, "window": 5}}, "action": "flag"},
+            {"name": "large", "check": {"max_amount": {"amount": 10000}}, "action": "lock"}
+        ]}"#;
+        let rules = RuleSet::from_reader(json.as_bytes()).unwrap();
+        assert_eq!(rules.rules[0].check, Check::WithdrawalFrequency { max: 3, window: 5 });
+        assert_eq!(rules.rules[1].check, Check::MaxAmount { amount: dec!(10000) });
+        assert_eq!(rules.rules[1].action, RuleAction::Lock);
+        assert_eq!(rules.longest_window(), 5);
+
+        let empty_window = r#"{"rules": [{"name": "a", "check": {"deposit_then_withdraw": {"window": 0, "percent": 1}}, "action": "flag"}]}"#;
+        assert!(matches!(
+            RuleSet::from_reader(empty_window.as_bytes()),
+            Err(error::Error::InvalidArgument(_))
+        ));
+        let negative = r#"{"rules": [{"name": "a", "check": {"daily_volume": {"amount": "-1"}}, "action": "flag"}]}"#;
+        assert!(matches!(RuleSet::from_reader(negative.as_bytes()), Err(error::Error::InvalidArgument(_))));
+        let duplicate = r#"{"rules": [
+            {"name": "a", "check": {"max_amount": {"amount": 1}}, "action": "flag"},
+            {"name": "a", "check": {"max_amount": {"amount": 2}}, "action": "flag"}
+        ]}"#;
+        assert!(RuleSet::from_reader(duplicate.as_bytes()).is_err());
+        let unknown = r#"{"rules": [{"name": "a", "check": {"velocity": {}}, "action": "flag"}]}"#;
+        assert!(RuleSet::from_reader(unknown.as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,352 @@
+use log::{error, info, trace};
+use std::cell::Cell;
+use std::io;
+use std::io::Read;
+use tiny_http::{Header, Method, Request, Server};
+use transaction_processor::writer::{write_client, write_client_data, OutputFormat};
+use transaction_processor::{error, ClientOrder, JsonLinesFeed, Processor, Rejection, Transaction, TransactionFeed, TransactionSource};
+
+///Address the server listens on unless `--listen` is given.
+pub const DEFAULT_LISTEN: &str = "127.0.0.1:8080";
+
+///Header assumed for csv bodies that don't start with one. The currency comes before the reason,
+/// so deposits and withdrawals can give a currency without an empty reason.
+const CSV_HEADER: &str = "type,client,tx,amount,currency,reason\n";
+
+///Largest request body read, so a single request can't exhaust memory.
+const MAX_BODY_BYTES: u64 = 16 * 1024 * 1024;
+
+///Counts the records read from a source, so those applied can be told from those refused.
+struct Counted<'a, S> {
+    source: S,
+    records: &'a Cell<usize>,
+}
+
+impl<S: TransactionSource> Iterator for Counted<'_, S> {
+    type Item = Result<Transaction, error::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let record = self.source.next();
+        if record.is_some() {
+            self.records.set(self.records.get() + 1);
+        }
+        record
+    }
+}
+
+impl<S: TransactionSource> TransactionSource for Counted<'_, S> {
+    fn name(&self) -> &str {
+        self.source.name()
+    }
+
+    fn position(&self) -> u64 {
+        self.source.position()
+    }
+}
+
+///A json response to a request.
+#[derive(Debug, PartialEq)]
+pub struct Response {
+    ///HTTP status code.
+    pub status: u16,
+    ///Json body.
+    pub body: String,
+}
+
+impl Response {
+    fn ok(body: String) -> Self {
+        Self { status: 200, body }
+    }
+
+    fn error(status: u16, message: &str) -> Self {
+        Self {
+            status,
+            body: serde_json::json!({ "error": message }).to_string(),
+        }
+    }
+}
+
+///Listens on an address and applies transactions posted to it until the process is stopped.
+/// Requests are handled one at a time, so each sees the balances left by the one before.
+///# Errors
+/// If the address can't be listened on.
+pub fn serve<F>(processor: &mut Processor, listen: &str, mut on_reject: F) -> Result<(), error::Error>
+where
+    F: FnMut(Rejection) -> Result<(), error::Error>,
+{
+    let server = Server::http(listen).map_err(|err| error::Error::InvalidArgument(format!("Can't listen on {}: {}", listen, err)))?;
+    info!("[!] Listening on {}", listen);
+    for mut request in server.incoming_requests() {
+        let response = match read_body(&mut request) {
+            Err(response) => response,
+            Ok(body) => {
+                let content_type = request
+                    .headers()
+                    .iter()
+                    .find(|header| header.field.equiv("Content-Type"))
+                    .map(|header| header.value.as_str().to_owned());
+                handle(processor, request.method(), request.url(), content_type.as_deref(), body, &mut on_reject)
+            }
+        };
+        trace!("[!] {} {} -> {}", request.method(), request.url(), response.status);
+        let json = Header::from_bytes("Content-Type", "application/json").expect("System error, invalid content type header");
+        let reply = tiny_http::Response::from_string(response.body)
+            .with_status_code(response.status)
+            .with_header(json);
+        if let Err(err) = request.respond(reply) {
+            error!("[!] Error responding to request: {:?}", err);
+        }
+    }
+    Ok(())
+}
+
+fn read_body(request: &mut Request) -> Result<String, Response> {
+    if request.body_length().is_some_and(|length| length as u64 > MAX_BODY_BYTES) {
+        return Err(too_large(MAX_BODY_BYTES));
+    }
+    read_limited(request.as_reader(), MAX_BODY_BYTES)
+}
+
+///Reads a body of at most limit bytes, refusing a longer one with a 413 as soon as the limit is
+/// passed, as the Content-Length header may be missing or wrong.
+fn read_limited<R: Read>(reader: R, limit: u64) -> Result<String, Response> {
+    let mut body = String::new();
+    if let Err(err) = reader.take(limit + 1).read_to_string(&mut body) {
+        return Err(Response::error(400, &format!("Can't read request body: {}", err)));
+    }
+    if body.len() as u64 > limit {
+        return Err(too_large(limit));
+    }
+    Ok(body)
+}
+
+fn too_large(limit: u64) -> Response {
+    Response::error(413, &format!("Request body is larger than {} bytes", limit))
+}
+
+///Routes a request to the processor:
+/// - `POST /transactions` applies csv lines, or json lines if the content type is json
+/// - `GET /clients` returns every client in ascending client id order
+/// - `GET /clients/{id}` returns a single client's rows, one per currency
+pub fn handle<F>(processor: &mut Processor, method: &Method, url: &str, content_type: Option<&str>, body: String, on_reject: F) -> Response
+where
+    F: FnMut(Rejection) -> Result<(), error::Error>,
+{
+    let path = url.split('?').next().unwrap_or_default().trim_end_matches('/');
+    match (method, path) {
+        (Method::Post, "/transactions") => post_transactions(processor, content_type, body, on_reject),
+        (Method::Get, "/clients") => {
+            let mut out = Vec::new();
+            match write_client_data(processor.clients(), OutputFormat::Json, ClientOrder::Ascending, &mut out) {
+                Err(err) => Response::error(500, &err.to_string()),
+                Ok(()) => Response::ok(String::from_utf8_lossy(&out).trim_end().to_owned()),
+            }
+        }
+        (Method::Get, path) if path.starts_with("/clients/") => match path["/clients/".len()..].parse::<u16>() {
+            Err(_) => Response::error(400, "Invalid client id"),
+            Ok(id) => match processor.client(id) {
+                None => Response::error(404, &format!("Client {} does not exist", id)),
+                Some(client) => {
+                    let mut out = Vec::new();
+                    match write_client(client, OutputFormat::Json, &mut out) {
+                        Err(err) => Response::error(500, &err.to_string()),
+                        Ok(()) => Response::ok(String::from_utf8_lossy(&out).trim_end().to_owned()),
+                    }
+                }
+            },
+        },
+        (_, "/transactions" | "/clients") => Response::error(405, "Method not allowed"),
+        (_, path) if path.starts_with("/clients/") => Response::error(405, "Method not allowed"),
+        _ => Response::error(404, "Not found"),
+    }
+}
+
+///Applies every transaction in a request body, returning how many were applied and the
+/// rejections of the rest. Lines are numbered within the body. If the rejects report or the
+/// write-ahead log fails part way, the records already applied are kept, so the 500 returned
+/// still reports them along with the rejections so far.
+fn post_transactions<F>(processor: &mut Processor, content_type: Option<&str>, body: String, mut on_reject: F) -> Response
+where
+    F: FnMut(Rejection) -> Result<(), error::Error>,
+{
+    let json =
+        content_type.is_some_and(|content_type| content_type.starts_with("application/json") || content_type.starts_with("application/x-ndjson"));
+    //Lines of an implied csv header are taken off so rejections point at lines of the body
+    let mut implied_lines = 0;
+    let source: Box<dyn TransactionSource> = if json {
+        Box::new(JsonLinesFeed::from_reader(String::from("request"), Box::new(io::Cursor::new(body))))
+    } else {
+        let body = if body.trim_start().starts_with("type") {
+            body
+        } else {
+            implied_lines = 1;
+            format!("{}{}", CSV_HEADER, body)
+        };
+        match TransactionFeed::from_reader(String::from("request"), Box::new(io::Cursor::new(body))) {
+            Err(err) => return Response::error(400, &err.to_string()),
+            Ok(feed) => Box::new(feed),
+        }
+    };
+
+    let records = Cell::new(0);
+    let mut rejected = Vec::new();
+    let mut reject_failed = false;
+    let counted = Counted { source, records: &records };
+    let handled = processor.handle_transactions(counted, |mut rejection| {
+        rejection.line -= implied_lines;
+        rejected.push(rejection.clone());
+        on_reject(rejection).inspect_err(|_| reject_failed = true)
+    });
+    //Every record is either applied or rejected, whatever it adds to the journal, except one that
+    //couldn't be written to the write-ahead log, which is neither
+    let unlogged = usize::from(handled.is_err() && !reject_failed);
+    let applied = records.get() - rejected.len() - unlogged;
+    match handled {
+        Err(err) => {
+            error!("[!] Error handling posted transactions after {} applied: {:?}", applied, err);
+            Response {
+                status: 500,
+                body: serde_json::json!({ "error": err.to_string(), "applied": applied, "rejected": rejected }).to_string(),
+            }
+        }
+        Ok(()) => Response::ok(serde_json::json!({ "applied": applied, "rejected": rejected }).to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::serve::{handle, read_limited, Response};
+    use rust_decimal::Decimal;
+    use tiny_http::Method;
+    use transaction_processor::{error, Policy, Processor, Rejection, RuleSet};
+
+    fn request(processor: &mut Processor, method: Method, url: &str, content_type: Option<&str>, body: &str) -> (u16, serde_json::Value) {
+        let Response { status, body } = handle(processor, &method, url, content_type, body.to_owned(), |_: Rejection| Ok(()));
+        (status, serde_json::from_str(&body).unwrap())
+    }
+
+    #[test]
+    fn csv_lines_are_applied() {
+        let mut processor = Processor::new();
+        let (status, body) = request(
+            &mut processor,
+            Method::Post,
+            "/transactions",
+            None,
+            "deposit, 1, 1, 2.0\nwithdrawal, 1, 2, 5.0\n",
+        );
+        assert_eq!(status, 200);
+        assert_eq!(body["applied"], 1);
+        assert_eq!(body["rejected"][0]["line"], 2);
+        assert_eq!(body["rejected"][0]["reason"], "insufficient_funds");
+
+        let (status, _) = request(
+            &mut processor,
+            Method::Post,
+            "/transactions",
+            Some("text/csv"),
+            "type,client,tx,amount\ndeposit,2,3,1.0\n",
+        );
+        assert_eq!(status, 200);
+        assert_eq!(processor.clients().clients.len(), 2);
+    }
+
+    #[test]
+    fn headerless_csv_lines_take_a_currency() {
+        let mut processor = Processor::new();
+        let (status, body) = request(
+            &mut processor,
+            Method::Post,
+            "/transactions",
+            None,
+            "deposit,1,1,2.0,EUR\nfreeze,1,2,,,review\n",
+        );
+        assert_eq!(status, 200);
+        assert_eq!(body["applied"], 2);
+        let client = processor.client(1).unwrap();
+        assert_eq!(client.balances("EUR").total(), Decimal::new(20, 1));
+        assert_eq!(client.balances("USD").total(), Decimal::ZERO);
+        assert!(client.locked);
+    }
+
+    #[test]
+    fn json_lines_are_applied() {
+        let mut processor = Processor::new();
+        let body = "{\"type\": \"deposit\", \"client\": 1, \"tx\": 1, \"amount\": \"1.23456\"}\n\
+                    {\"type\": \"dispute\", \"client\": 1, \"tx\": 1}\n";
+        let (status, body) = request(&mut processor, Method::Post, "/transactions", Some("application/json"), body);
+        assert_eq!(status, 200);
+        assert_eq!(body["applied"], 2);
+        assert_eq!(body["rejected"], serde_json::json!([]));
+
+        let (status, client) = request(&mut processor, Method::Get, "/clients/1", None, "");
+        assert_eq!(status, 200);
+        assert_eq!(client[0]["currency"], "USD");
+        assert_eq!(client[0]["held"], "1.2346");
+        assert_eq!(client[0]["available"], "0.0000");
+        let (_, clients) = request(&mut processor, Method::Get, "/clients", None, "");
+        assert_eq!(client, clients);
+    }
+
+    #[test]
+    fn failed_reports_keep_the_applied_count() {
+        let mut processor = Processor::new();
+        let body = String::from("deposit,1,1,2.0\nwithdrawal,1,2,5.0\ndeposit,1,3,1.0\n");
+        let failing = |_: Rejection| Err(error::Error::InvalidArgument(String::from("report unavailable")));
+        let Response { status, body } = handle(&mut processor, &Method::Post, "/transactions", None, body, failing);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(status, 500);
+        assert_eq!(body["applied"], 1);
+        assert_eq!(body["rejected"][0]["line"], 2);
+        assert_eq!(processor.client(1).unwrap().balances("USD").total(), Decimal::new(20, 1));
+    }
+
+    #[test]
+    fn large_bodies_are_refused() {
+        assert_eq!(read_limited("deposit,1,1,2.0\n".as_bytes(), 16).unwrap(), "deposit,1,1,2.0\n");
+        assert_eq!(read_limited("deposit,1,1,2.0\n".as_bytes(), 15).unwrap_err().status, 413);
+    }
+
+    #[test]
+    fn rule_locks_are_not_counted_as_applied() {
+        //Rules reach the server as a rules file
+        let rules = r#"{"rules": [{"name": "pass-through", "check": {"deposit_then_withdraw": {"window": 2, "percent": 90}}, "action": "lock"}]}"#;
+        let policy = Policy {
+            rules: RuleSet::from_reader(rules.as_bytes()).unwrap(),
+            ..Default::default()
+        };
+        let mut processor = Processor::with_policy(policy);
+        let (status, body) = request(
+            &mut processor,
+            Method::Post,
+            "/transactions",
+            None,
+            "deposit,1,1,100\nwithdrawal,1,2,95\n",
+        );
+        assert_eq!(status, 200);
+        assert_eq!(body["applied"], 1);
+        assert_eq!(body["rejected"][0]["line"], 2);
+        assert_eq!(body["rejected"][0]["reason"], "locked_by_rule");
+        assert!(processor.client(1).unwrap().locked);
+    }
+
+    #[test]
+    fn clients_are_listed_in_order() {
+        let mut processor = Processor::new();
+        request(&mut processor, Method::Post, "/transactions", None, "deposit,3,1,1.0\ndeposit,1,2,1.0\n");
+        let (status, clients) = request(&mut processor, Method::Get, "/clients/", None, "");
+        assert_eq!(status, 200);
+        let ids: Vec<&serde_json::Value> = clients.as_array().unwrap().iter().map(|client| &client["client"]).collect();
+        assert_eq!(ids, vec![1, 3]);
+    }
+
+    #[test]
+    fn unknown_requests_are_refused() {
+        let mut processor = Processor::new();
+        assert_eq!(request(&mut processor, Method::Get, "/clients/1", None, "").0, 404);
+        assert_eq!(request(&mut processor, Method::Get, "/clients/x", None, "").0, 400);
+        assert_eq!(request(&mut processor, Method::Get, "/transactions", None, "").0, 405);
+        assert_eq!(request(&mut processor, Method::Delete, "/clients/1", None, "").0, 405);
+        assert_eq!(request(&mut processor, Method::Get, "/balances", None, "").0, 404);
+    }
+}
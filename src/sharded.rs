@@ -0,0 +1,496 @@
+use crate::error;
+use crate::ledger::JournalEntry;
+use crate::policy::Policy;
+use crate::processor::Processor;
+use crate::rejects::Rejection;
+use crate::snapshot::{Snapshot, SNAPSHOT_VERSION};
+use crate::transaction::Transaction;
+use crate::transaction_feed::TransactionSource;
+use log::{debug, error, trace};
+use rust_decimal::Decimal;
+use std::collections::{HashMap, HashSet};
+use std::sync::mpsc;
+use std::thread;
+
+///Records queued per worker before the reader waits, so a slow shard applies backpressure
+/// rather than buffering the whole input.
+const QUEUE_DEPTH: usize = 1024;
+
+enum Job {
+    ///Apply a transaction read at a line, with the owner of its tx id if held by another shard.
+    Apply {
+        line: u64,
+        tx: Transaction,
+        foreign_owner: Option<u16>,
+    },
+    ///Reply with the client owning a tx id in this shard's ledger, once every earlier job is done.
+    Owner { tx_id: u32, reply: mpsc::Sender<Option<u16>> },
+    ///Credit a fee charged by another shard to the house account.
+    Credit { currency: String, fee: Decimal },
+    ///Reply once every earlier job is done, including sending the fees they charged.
+    Sync { reply: mpsc::Sender<()> },
+}
+
+///Who has referenced a transaction id so far.
+enum Seen {
+    Client(u16),
+    Contested,
+}
+
+///Runs the Processor rules across worker threads, each owning the clients and ledger entries of
+/// a shard of client ids. Every rule is scoped to a single client, so records are read in order
+/// and sent to the shard of their client, preserving per-client ordering.
+///
+/// Transaction ids are shared between clients, so a record referencing a tx id another client
+/// has used waits for every shard to catch up, then asks the shards who holds the tx id. This
+/// gives the same duplicate and wrong client rejections as a serial run. Such records are rare
+/// in practice, so shards otherwise run independently.
+///
+/// Fees charged in any shard are sent to the shard owning the house account. Before a
+/// transaction of the house account itself is applied, every other shard catches up so the
+/// house account sees the same fees as it would in a serial run.
+///
+/// Once the input is exhausted the shards are merged into a single Processor with the same
+/// clients, ledger and journal order as a serial run. Rejections are reported in input order
+/// once every shard is done. Events aren't emitted and write-ahead logging isn't supported.
+#[derive(Debug)]
+pub struct ShardedProcessor {
+    workers: usize,
+    policy: Policy,
+    snapshot: Option<Snapshot>,
+}
+
+impl ShardedProcessor {
+    ///Creates a sharded processor with no clients and an empty ledger.
+    ///# Panics
+    /// If workers is 0.
+    pub fn new(workers: usize, policy: Policy) -> Self {
+        assert!(workers > 0, "A sharded processor needs at least one worker");
+        Self {
+            workers,
+            policy,
+            snapshot: None,
+        }
+    }
+
+    ///Creates a sharded processor holding the clients and ledger of a snapshot.
+    ///# Panics
+    /// If workers is 0.
+    pub fn from_snapshot(snapshot: Snapshot, workers: usize, policy: Policy) -> Self {
+        Self {
+            snapshot: Some(snapshot),
+            ..Self::new(workers, policy)
+        }
+    }
+
+    fn shard_of(&self, client: u16) -> usize {
+        client as usize % self.workers
+    }
+
+    ///Applies every transaction in the source across the shards, returning a Processor holding
+    /// the merged result. See Processor::handle_transactions for the rules applied. An error
+    /// from on_reject stops reporting and is returned.
+    pub fn handle_transactions<S, F>(self, mut consumer: S, mut on_reject: F) -> Result<Processor, error::Error>
+    where
+        S: TransactionSource,
+        F: FnMut(Rejection) -> Result<(), error::Error>,
+    {
+        let snapshot = self.snapshot.clone().unwrap_or_else(|| Processor::new().snapshot());
+        let mut first_seen: Vec<u16> = snapshot.clients.iter().map(|state| state.client).collect();
+        //The house account is opened up front, as a serial run does
+        if !self.policy.fees.is_empty() && !first_seen.contains(&self.policy.fees.house) {
+            first_seen.push(self.policy.fees.house);
+        }
+        let mut known: HashSet<u16> = first_seen.iter().copied().collect();
+        let mut seen: HashMap<u32, Seen> = snapshot.transactions.iter().map(|tx| (tx.tx, Seen::Client(tx.client))).collect();
+        let mut rejections = Vec::new();
+        let (reject_tx, reject_rx) = mpsc::channel();
+
+        let (jobs, receivers): (Vec<_>, Vec<_>) = (0..self.workers).map(|_| mpsc::sync_channel(QUEUE_DEPTH)).unzip();
+        let house_shard = (!self.policy.fees.is_empty()).then(|| self.shard_of(self.policy.fees.house));
+        let workers: Vec<_> = receivers
+            .into_iter()
+            .enumerate()
+            .map(|(shard, job_rx)| {
+                //Shards not owning the house account send it the fees they charge
+                let house = house_shard
+                    .filter(|house_shard| *house_shard != shard)
+                    .map(|house_shard| jobs[house_shard].clone());
+                let processor = Processor::for_shard(self.shard_snapshot(&snapshot, shard), self.policy.clone(), house.is_none());
+                let reject_tx = reject_tx.clone();
+                thread::spawn(move || Self::run_shard(processor, job_rx, reject_tx, house))
+            })
+            .collect();
+        drop(reject_tx);
+
+        while let Some(transaction) = consumer.next() {
+            let line = consumer.position();
+            let mut tx = match transaction {
+                Err(err) => {
+                    error!("[!] Error parsing transaction at {}:{}: {:?}", consumer.name(), line, err);
+                    rejections.push(Rejection::new(line, None, &err));
+                    continue;
+                }
+                Ok(tx) => tx,
+            };
+            trace!("[!] transaction parsed = {:?}", tx);
+            //Validation doesn't depend on state, so refused records never reach a shard
+            if let Err(err) = Processor::validate(&self.policy, &mut tx) {
+                error!("[!] Error validating transactions: {:?}", err);
+                rejections.push(Rejection::new(line, Some((tx.client, tx.tx_id, tx.tx_type)), &err));
+                continue;
+            }
+            if known.insert(tx.client) {
+                first_seen.push(tx.client);
+            }
+            let shard = self.shard_of(tx.client);
+            let foreign_owner = if tx.tx_type.is_admin() {
+                None
+            } else {
+                match seen.get(&tx.tx_id) {
+                    None => {
+                        seen.insert(tx.tx_id, Seen::Client(tx.client));
+                        None
+                    }
+                    Some(Seen::Client(client)) if *client == tx.client => None,
+                    Some(_) => {
+                        seen.insert(tx.tx_id, Seen::Contested);
+                        Self::find_owner(&jobs, tx.tx_id)
+                            .filter(|(owner_shard, _)| *owner_shard != shard)
+                            .map(|(_, owner)| owner)
+                    }
+                }
+            };
+            if house_shard.is_some() && tx.client == self.policy.fees.house {
+                Self::catch_up(&jobs, shard);
+            }
+            Self::send(&jobs[shard], Job::Apply { line, tx, foreign_owner });
+        }
+        drop(jobs);
+
+        let mut shards = Vec::with_capacity(workers.len());
+        for worker in workers {
+            shards.push(worker.join().expect("System error, a shard worker panicked"));
+        }
+        rejections.extend(reject_rx.try_iter());
+        rejections.sort_by_key(|rejection| rejection.line);
+        for rejection in rejections {
+            on_reject(rejection)?;
+        }
+        Ok(Processor::from_snapshot(Self::merge(snapshot, shards, &first_seen), self.policy))
+    }
+
+    ///Asks every shard which client holds a tx id, once each has applied every earlier record.
+    fn find_owner(jobs: &[mpsc::SyncSender<Job>], tx_id: u32) -> Option<(usize, u16)> {
+        debug!("Transaction {} is referenced by more than one client, waiting for shards", tx_id);
+        let mut replies = Vec::with_capacity(jobs.len());
+        for job_tx in jobs {
+            let (reply, reply_rx) = mpsc::channel();
+            Self::send(job_tx, Job::Owner { tx_id, reply });
+            replies.push(reply_rx);
+        }
+        let mut owner = None;
+        for (shard, reply_rx) in replies.into_iter().enumerate() {
+            if let Some(client) = reply_rx.recv().expect("System error, a shard worker stopped early") {
+                owner = Some((shard, client));
+            }
+        }
+        owner
+    }
+
+    ///Waits for every shard other than the house account's to apply every earlier record, so
+    /// the fees they charged are queued for the house account ahead of its own transactions.
+    fn catch_up(jobs: &[mpsc::SyncSender<Job>], house_shard: usize) {
+        debug!("House account transacting, waiting for shards");
+        let mut replies = Vec::with_capacity(jobs.len());
+        for (shard, job_tx) in jobs.iter().enumerate() {
+            if shard != house_shard {
+                let (reply, reply_rx) = mpsc::channel();
+                Self::send(job_tx, Job::Sync { reply });
+                replies.push(reply_rx);
+            }
+        }
+        for reply_rx in replies {
+            reply_rx.recv().expect("System error, a shard worker stopped early");
+        }
+    }
+
+    ///# Panics
+    /// Workers only stop once their queue is closed, so a closed queue means a worker panicked.
+    fn send(job_tx: &mpsc::SyncSender<Job>, job: Job) {
+        job_tx.send(job).expect("System error, a shard worker stopped early");
+    }
+
+    fn run_shard(
+        mut processor: Processor,
+        jobs: mpsc::Receiver<Job>,
+        rejects: mpsc::Sender<Rejection>,
+        house: Option<mpsc::SyncSender<Job>>,
+    ) -> (Snapshot, Vec<u64>) {
+        //Line of the record behind each journal entry, so journals can be merged in input order
+        let mut lines = Vec::new();
+        for job in jobs {
+            match job {
+                Job::Apply { line, tx, foreign_owner } => {
+                    let (client, tx_id, tx_type) = (tx.client, tx.tx_id, tx.tx_type);
+                    let journaled = processor.ledger().journal().len();
+                    match processor.apply_in_shard(tx, foreign_owner) {
+                        Ok(outcome) => {
+                            if let Some(house) = house.as_ref().filter(|_| !outcome.fee.is_zero()) {
+                                Self::send(
+                                    house,
+                                    Job::Credit {
+                                        currency: outcome.currency,
+                                        fee: outcome.fee,
+                                    },
+                                );
+                            }
+                        }
+                        Err(err) => {
+                            //The receiver outlives every worker, so this can't fail
+                            let _ = rejects.send(Rejection::new(line, Some((client, tx_id, tx_type)), &err));
+                        }
+                    }
+                    lines.resize(lines.len() + processor.ledger().journal().len() - journaled, line);
+                }
+                Job::Owner { tx_id, reply } => {
+                    let _ = reply.send(processor.ledger().get(tx_id).map(|tx| tx.client));
+                }
+                Job::Credit { currency, fee } => processor.credit_house(&currency, fee),
+                Job::Sync { reply } => {
+                    let _ = reply.send(());
+                }
+            }
+        }
+        (processor.snapshot(), lines)
+    }
+
+    ///The clients and ledger entries of a shard. Journals stay with the original snapshot and
+    /// are put back in front when the shards are merged.
+    fn shard_snapshot(&self, snapshot: &Snapshot, shard: usize) -> Snapshot {
+        Snapshot {
+            version: SNAPSHOT_VERSION,
+            clients: snapshot
+                .clients
+                .iter()
+                .filter(|state| self.shard_of(state.client) == shard)
+                .cloned()
+                .collect(),
+            transactions: snapshot
+                .transactions
+                .iter()
+                .filter(|tx| self.shard_of(tx.client) == shard)
+                .cloned()
+                .collect(),
+            journal: Vec::new(),
+        }
+    }
+
+    fn merge(snapshot: Snapshot, shards: Vec<(Snapshot, Vec<u64>)>, first_seen: &[u16]) -> Snapshot {
+        let mut clients = HashMap::new();
+        let mut transactions = Vec::new();
+        let mut journal: Vec<(u64, JournalEntry)> = Vec::new();
+        for (shard, lines) in shards {
+            clients.extend(shard.clients.into_iter().map(|state| (state.client, state)));
+            transactions.extend(shard.transactions);
+            journal.extend(lines.into_iter().zip(shard.journal));
+        }
+        //Stable, so entries from the same record keep their order
+        journal.sort_by_key(|(line, _)| *line);
+        transactions.sort_by_key(|tx| tx.tx);
+        let mut merged_journal = snapshot.journal;
+        for (_, mut entry) in journal {
+            entry.sequence = merged_journal.len() as u64;
+            merged_journal.push(entry);
+        }
+        Snapshot {
+            version: SNAPSHOT_VERSION,
+            clients: first_seen.iter().filter_map(|client| clients.remove(client)).collect(),
+            transactions,
+            journal: merged_journal,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fees::{FeeRule, FeeSchedule};
+    use crate::policy::Policy;
+    use crate::processor::Processor;
+    use crate::rejects::Rejection;
+    use crate::rules::{Check, RuleAction, RuleSet};
+    use crate::sharded::ShardedProcessor;
+    use crate::test_support::rule;
+    use crate::transaction_feed::TransactionFeed;
+    use rust_decimal::Decimal;
+    use std::ffi::OsString;
+    use std::fs;
+    use std::io;
+
+    fn serial(input: &str, policy: Policy) -> (Processor, Vec<Rejection>) {
+        let feed = TransactionFeed::from_reader(String::from("test"), Box::new(io::Cursor::new(input.to_owned()))).unwrap();
+        let mut processor = Processor::with_policy(policy);
+        let mut rejections = Vec::new();
+        processor
+            .handle_transactions(feed, |rejection| {
+                rejections.push(rejection);
+                Ok(())
+            })
+            .unwrap();
+        (processor, rejections)
+    }
+
+    fn sharded(input: &str, workers: usize, policy: Policy) -> (Processor, Vec<Rejection>) {
+        let feed = TransactionFeed::from_reader(String::from("test"), Box::new(io::Cursor::new(input.to_owned()))).unwrap();
+        let mut rejections = Vec::new();
+        let processor = ShardedProcessor::new(workers, policy)
+            .handle_transactions(feed, |rejection| {
+                rejections.push(rejection);
+                Ok(())
+            })
+            .unwrap();
+        (processor, rejections)
+    }
+
+    #[test]
+    fn cross_client_tx_ids_match_serial() {
+        let input = "type,client,tx,amount\n\
+                     withdrawal,1,1,5.0\n\
+                     deposit,2,1,3.0\n\
+                     deposit,1,1,1.0\n\
+                     dispute,1,1,\n\
+                     dispute,2,1,\n\
+                     deposit,3,2,1.0\n\
+                     deposit,x,3,1.0\n\
+                     chargeback,2,1,\n\
+                     deposit,2,4,1.0\n";
+        let (expected, expected_rejections) = serial(input, Policy::default());
+        for workers in 1..4 {
+            let (processor, rejections) = sharded(input, workers, Policy::default());
+            assert_eq!(processor.snapshot(), expected.snapshot());
+            assert_eq!(rejections, expected_rejections);
+        }
+    }
+
+    #[test]
+    fn fees_match_serial() {
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,10.0\n\
+                     deposit,2,2,20.0\n\
+                     withdrawal,1,3,5.0\n\
+                     withdrawal,3,4,1.0\n\
+                     deposit,4,5,1.0\n\
+                     withdrawal,2,6,19.9\n";
+        let policy = Policy {
+            fees: FeeSchedule {
+                house: 4,
+                withdrawal: Some(FeeRule {
+                    flat: Decimal::new(5, 1),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (expected, expected_rejections) = serial(input, policy.clone());
+        assert_eq!(expected.client(4).unwrap().balances("USD").total(), Decimal::new(15, 1));
+        for workers in 1..4 {
+            let (processor, rejections) = sharded(input, workers, policy.clone());
+            assert_eq!(processor.snapshot(), expected.snapshot());
+            assert_eq!(rejections, expected_rejections);
+        }
+    }
+
+    #[test]
+    fn rules_match_serial() {
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,10.0\n\
+                     deposit,2,2,20.0\n\
+                     withdrawal,1,3,9.5\n\
+                     deposit,1,4,500.0\n\
+                     withdrawal,2,5,1.0\n\
+                     deposit,1,6,1.0\n\
+                     deposit,3,2,1.0\n";
+        let policy = Policy {
+            rules: RuleSet {
+                rules: vec![
+                    rule(
+                        "large",
+                        Check::MaxAmount {
+                            amount: Decimal::new(100, 0),
+                        },
+                        RuleAction::Reject,
+                    ),
+                    rule(
+                        "pass-through",
+                        Check::DepositThenWithdraw {
+                            window: 2,
+                            percent: Decimal::new(90, 0),
+                        },
+                        RuleAction::Lock,
+                    ),
+                ],
+            },
+            ..Default::default()
+        };
+        let (expected, expected_rejections) = serial(input, policy.clone());
+        assert!(expected.client(1).unwrap().locked);
+        assert_eq!(expected_rejections.len(), 4);
+        for workers in 1..4 {
+            let (processor, rejections) = sharded(input, workers, policy.clone());
+            assert_eq!(processor.snapshot(), expected.snapshot());
+            assert_eq!(rejections, expected_rejections);
+        }
+    }
+
+    #[test]
+    fn house_transactions_match_serial() {
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,10.0\n\
+                     withdrawal,1,2,1.0\n\
+                     deposit,3,3,10.0\n\
+                     withdrawal,3,4,1.0\n\
+                     withdrawal,1,5,1.0\n\
+                     withdrawal,4,6,1.0\n\
+                     withdrawal,3,7,1.0\n";
+        let policy = Policy {
+            fees: FeeSchedule {
+                house: 4,
+                withdrawal: Some(FeeRule {
+                    flat: Decimal::new(5, 1),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (expected, expected_rejections) = serial(input, policy.clone());
+        assert!(expected_rejections.is_empty());
+        assert_eq!(expected.client(4).unwrap().balances("USD").total(), Decimal::new(10, 1));
+        for workers in 1..4 {
+            let (processor, rejections) = sharded(input, workers, policy.clone());
+            assert_eq!(processor.snapshot(), expected.snapshot());
+            assert_eq!(rejections, expected_rejections);
+        }
+    }
+
+    #[test]
+    fn sample_inputs_match_serial() {
+        let policy = Policy {
+            withdrawal_disputes: true,
+            redisputes: true,
+            ..Default::default()
+        };
+        for entry in fs::read_dir("test-inputs").unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension() != Some(OsString::from("csv").as_os_str()) {
+                continue;
+            }
+            let input = fs::read_to_string(&path).unwrap();
+            let (expected, expected_rejections) = serial(&input, policy.clone());
+            let (processor, rejections) = sharded(&input, 3, policy.clone());
+            assert_eq!(processor.snapshot(), expected.snapshot(), "{:?}", path);
+            assert_eq!(rejections, expected_rejections, "{:?}", path);
+        }
+    }
+}
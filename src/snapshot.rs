@@ -0,0 +1,357 @@
+use crate::client_repo::{ClientOrder, ClientRepo};
+use crate::error;
+use crate::ledger::{JournalEntry, Ledger};
+use crate::models::client::{Balances, Client};
+use crate::transaction::{DisputeState, Transaction, TxTypes, DEFAULT_CURRENCY};
+use crate::writer::write_atomically;
+use log::debug;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+///Version of the snapshot format written by this build. Version 1 snapshots, from before
+/// multi-currency accounts, are read with their balances in DEFAULT_CURRENCY. Snapshots of any
+/// other version are refused rather than guessed at.
+pub const SNAPSHOT_VERSION: u32 = 2;
+
+///The full state of a Processor, clients plus ledger, so a run can pick up where the last one
+/// left off. Snapshots are written as json with amounts as strings so no precision is lost.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    ///Version of the snapshot format.
+    pub version: u32,
+    ///Client accounts, in the order they were first seen.
+    pub clients: Vec<ClientState>,
+    ///Deposits and withdrawals in the ledger, in ascending transaction id order.
+    pub transactions: Vec<TransactionState>,
+    ///Every journal entry in the order applied.
+    pub journal: Vec<JournalEntry>,
+}
+
+///A client account in a snapshot.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClientState {
+    ///Client id.
+    pub client: u16,
+    ///Funds in each currency, in currency order.
+    pub balances: Vec<BalanceState>,
+    ///Whether the client is locked.
+    pub locked: bool,
+    ///Whether the client is closed.
+    pub closed: bool,
+}
+
+///A client's funds in one currency in a snapshot.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BalanceState {
+    ///Currency code.
+    pub currency: String,
+    ///Funds available for withdrawal.
+    pub available: Decimal,
+    ///Funds held by disputes.
+    pub held: Decimal,
+    ///Total funds.
+    pub total: Decimal,
+    ///Fees charged.
+    #[serde(default)]
+    pub fees: Decimal,
+}
+
+///A client account in a version 1 snapshot, with a single set of balances.
+#[derive(Deserialize)]
+struct ClientStateV1 {
+    client: u16,
+    available: Decimal,
+    held: Decimal,
+    total: Decimal,
+    locked: bool,
+    closed: bool,
+}
+
+///A version 1 snapshot. Transactions and journal entries read without a currency are in
+/// DEFAULT_CURRENCY.
+#[derive(Deserialize)]
+struct SnapshotV1 {
+    clients: Vec<ClientStateV1>,
+    transactions: Vec<TransactionState>,
+    journal: Vec<JournalEntry>,
+}
+
+impl From<SnapshotV1> for Snapshot {
+    fn from(snapshot: SnapshotV1) -> Self {
+        let clients = snapshot
+            .clients
+            .into_iter()
+            .map(|state| ClientState {
+                client: state.client,
+                balances: vec![BalanceState {
+                    currency: DEFAULT_CURRENCY.to_owned(),
+                    available: state.available,
+                    held: state.held,
+                    total: state.total,
+                    fees: Decimal::ZERO,
+                }],
+                locked: state.locked,
+                closed: state.closed,
+            })
+            .collect();
+        let journal = snapshot
+            .journal
+            .into_iter()
+            .map(|entry| {
+                if entry.tx_type.is_admin() {
+                    entry
+                } else {
+                    JournalEntry {
+                        currency: Some(DEFAULT_CURRENCY.to_owned()),
+                        ..entry
+                    }
+                }
+            })
+            .collect();
+        Self {
+            version: SNAPSHOT_VERSION,
+            clients,
+            transactions: snapshot.transactions,
+            journal,
+        }
+    }
+}
+
+///A deposit or withdrawal in a snapshot, along with its dispute state.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TransactionState {
+    ///Deposit or withdrawal.
+    #[serde(rename = "type")]
+    pub tx_type: TxTypes,
+    ///Client id.
+    pub client: u16,
+    ///Transaction id.
+    pub tx: u32,
+    ///Amount of the transaction.
+    pub amount: Decimal,
+    ///Currency of the transaction.
+    #[serde(default = "default_currency")]
+    pub currency: String,
+    ///Dispute state of the transaction.
+    pub state: DisputeState,
+    ///Amount held by a dispute capped at the client's overdraft limit, if less than the amount.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub held: Option<Decimal>,
+}
+
+fn default_currency() -> String {
+    DEFAULT_CURRENCY.to_owned()
+}
+
+impl Snapshot {
+    ///Captures the state of the clients and ledger.
+    pub fn capture(clients: &ClientRepo, ledger: &Ledger) -> Self {
+        let mut transactions: Vec<TransactionState> = ledger
+            .transactions()
+            .values()
+            .map(|tx| TransactionState {
+                tx_type: tx.tx_type,
+                client: tx.client,
+                tx: tx.tx_id,
+                amount: tx.amount.expect("System error, ledger shows a transaction with no amount"),
+                currency: tx.currency().to_owned(),
+                state: tx.state,
+                held: ledger.held(tx.tx_id),
+            })
+            .collect();
+        transactions.sort_by_key(|tx| tx.tx);
+        Self {
+            version: SNAPSHOT_VERSION,
+            clients: clients
+                .ordered_clients(ClientOrder::FirstSeen)
+                .into_iter()
+                .map(|client| ClientState {
+                    client: client.client,
+                    balances: client
+                        .currencies()
+                        .map(|(currency, balances)| BalanceState {
+                            currency: currency.to_owned(),
+                            available: balances.available(),
+                            held: balances.held(),
+                            total: balances.total(),
+                            fees: balances.fees(),
+                        })
+                        .collect(),
+                    locked: client.locked,
+                    closed: client.closed,
+                })
+                .collect(),
+            transactions,
+            journal: ledger.journal().to_vec(),
+        }
+    }
+
+    ///Rebuilds the clients and ledger held in the snapshot.
+    pub fn restore(self) -> (ClientRepo, Ledger) {
+        let mut clients = ClientRepo::new();
+        for state in self.clients {
+            let balances = state
+                .balances
+                .into_iter()
+                .map(|balance| {
+                    (
+                        balance.currency,
+                        Balances::new(balance.available, balance.held, balance.total, balance.fees),
+                    )
+                })
+                .collect();
+            clients.insert(Client::from_balances(state.client, balances, state.locked, state.closed));
+        }
+        let held = self.transactions.iter().filter_map(|tx| tx.held.map(|held| (tx.tx, held))).collect();
+        let transactions = self
+            .transactions
+            .into_iter()
+            .map(|tx| Transaction {
+                tx_type: tx.tx_type,
+                client: tx.client,
+                tx_id: tx.tx,
+                amount: Some(tx.amount),
+                reason: None,
+                currency: Some(tx.currency),
+                state: tx.state,
+            })
+            .collect();
+        (clients, Ledger::restore(transactions, held, self.journal))
+    }
+
+    ///Reads a snapshot from any reader, upgrading version 1 snapshots and refusing snapshots of
+    /// any other version.
+    pub fn from_reader<R: io::Read>(reader: R) -> Result<Self, error::Error> {
+        let value: serde_json::Value = serde_json::from_reader(reader)?;
+        let version: u32 = serde_json::from_value(value.get("version").cloned().unwrap_or_default())?;
+        match version {
+            SNAPSHOT_VERSION => Ok(serde_json::from_value(value)?),
+            1 => {
+                debug!("Upgrading version 1 snapshot");
+                Ok(serde_json::from_value::<SnapshotV1>(value)?.into())
+            }
+            version => Err(error::Error::UnsupportedSnapshot { version }),
+        }
+    }
+
+    ///Writes the snapshot to any writer.
+    pub fn to_writer<W: io::Write>(&self, writer: W) -> Result<(), error::Error> {
+        serde_json::to_writer_pretty(writer, self)?;
+        Ok(())
+    }
+
+    ///Reads a snapshot from a file.
+    pub fn read(file_path: &Path) -> Result<Self, error::Error> {
+        debug!("Loading snapshot from {:?}", file_path);
+        Self::from_reader(io::BufReader::new(fs::File::open(file_path)?))
+    }
+
+    ///Writes the snapshot to a file atomically, so a crashed run never leaves a partial snapshot
+    /// to be loaded by the next run.
+    pub fn write(&self, file_path: &Path) -> Result<(), error::Error> {
+        debug!("Saving snapshot to {:?}", file_path);
+        write_atomically(file_path, |out| self.to_writer(out))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error;
+    use crate::policy::{DisputeOverdraft, Policy};
+    use crate::processor::Processor;
+    use crate::snapshot::Snapshot;
+    use crate::test_support::transaction;
+    use crate::transaction::{TxTypes, DEFAULT_CURRENCY};
+    use crate::ClientOrder;
+    use rust_decimal::Decimal;
+
+    #[test]
+    fn capped_disputes_are_kept() {
+        let policy = Policy {
+            dispute_overdraft: DisputeOverdraft::Cap,
+            ..Default::default()
+        };
+        let mut processor = Processor::with_policy(policy.clone());
+        processor.apply(transaction(TxTypes::Deposit, 1, 1, Some(Decimal::new(20, 1)))).unwrap();
+        processor
+            .apply(transaction(TxTypes::Withdrawal, 1, 2, Some(Decimal::new(15, 1))))
+            .unwrap();
+        processor.apply(transaction(TxTypes::Dispute, 1, 1, None)).unwrap();
+
+        let mut buf = Vec::new();
+        processor.snapshot().to_writer(&mut buf).unwrap();
+        let mut restored = Processor::from_snapshot(Snapshot::from_reader(buf.as_slice()).unwrap(), policy);
+        assert_eq!(restored.ledger().held(1), Some(Decimal::new(5, 1)));
+        let outcome = restored.apply(transaction(TxTypes::Resolve, 1, 1, None)).unwrap();
+        assert_eq!(outcome.available, Decimal::new(5, 1));
+        assert_eq!(outcome.held, Decimal::ZERO);
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut processor = Processor::new();
+        processor.apply(transaction(TxTypes::Deposit, 2, 1, Some(Decimal::new(20, 1)))).unwrap();
+        processor
+            .apply(transaction(TxTypes::Deposit, 1, 2, Some(Decimal::new(12345, 4))))
+            .unwrap();
+        processor.apply(transaction(TxTypes::Dispute, 2, 1, None)).unwrap();
+
+        let mut buf = Vec::new();
+        processor.snapshot().to_writer(&mut buf).unwrap();
+        let snapshot = Snapshot::from_reader(buf.as_slice()).unwrap();
+        assert_eq!(snapshot, processor.snapshot());
+
+        let mut restored = Processor::from_snapshot(snapshot, Default::default());
+        let ids: Vec<u16> = restored
+            .clients()
+            .ordered_clients(ClientOrder::FirstSeen)
+            .iter()
+            .map(|c| c.client)
+            .collect();
+        assert_eq!(ids, vec![2, 1]);
+        assert_eq!(restored.client(1).unwrap().balances(DEFAULT_CURRENCY).total(), Decimal::new(12345, 4));
+        assert_eq!(restored.client(2).unwrap().balances(DEFAULT_CURRENCY).held(), Decimal::new(20, 1));
+        assert_eq!(restored.ledger().lifecycle(1).len(), 2);
+
+        //State carries over, so disputes can reference transactions from earlier runs
+        restored.apply(transaction(TxTypes::Resolve, 2, 1, None)).unwrap();
+        assert_eq!(restored.client(2).unwrap().balances(DEFAULT_CURRENCY).available(), Decimal::new(20, 1));
+        let result = restored.apply(transaction(TxTypes::Deposit, 1, 2, Some(Decimal::ONE)));
+        assert!(matches!(result, Err(error::Error::DuplicateTransaction { tx: 2 })));
+    }
+
+    #[test]
+    fn version_1_is_upgraded() {
+        let v1 = r#"{
+            "version": 1,
+            "clients": [{"client": 1, "available": "1.5", "held": "2", "total": "3.5", "locked": false, "closed": false}],
+            "transactions": [{"type": "deposit", "client": 1, "tx": 1, "amount": "2", "state": "disputed"}],
+            "journal": [
+                {"sequence": 0, "type": "deposit", "client": 1, "tx": 1, "amount": "2", "reason": null},
+                {"sequence": 1, "type": "dispute", "client": 1, "tx": 1, "amount": "2", "reason": null}
+            ]
+        }"#;
+        let snapshot = Snapshot::from_reader(v1.as_bytes()).unwrap();
+        assert_eq!(snapshot.version, 2);
+        assert_eq!(snapshot.clients[0].balances[0].currency, DEFAULT_CURRENCY);
+        assert_eq!(snapshot.transactions[0].currency, DEFAULT_CURRENCY);
+        assert_eq!(snapshot.journal[1].currency.as_deref(), Some(DEFAULT_CURRENCY));
+
+        let mut restored = Processor::from_snapshot(snapshot, Default::default());
+        restored.apply(transaction(TxTypes::Resolve, 1, 1, None)).unwrap();
+        assert_eq!(restored.client(1).unwrap().balances(DEFAULT_CURRENCY).available(), Decimal::new(35, 1));
+    }
+
+    #[test]
+    fn unsupported_version() {
+        let mut buf = Vec::new();
+        let mut snapshot = Processor::new().snapshot();
+        snapshot.version = 99;
+        snapshot.to_writer(&mut buf).unwrap();
+        let result = Snapshot::from_reader(buf.as_slice());
+        assert!(matches!(result, Err(error::Error::UnsupportedSnapshot { version: 99 })));
+    }
+}
@@ -0,0 +1,194 @@
+use crate::error;
+use crate::models::client::Client;
+use crate::processor::Processor;
+use crate::rejects::Rejection;
+use crate::rules::utc_day;
+use crate::transaction::Transaction;
+use log::trace;
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio_stream::{Stream, StreamExt};
+
+///Runs the Processor rules over an async stream of transactions, for embedding the engine in
+/// tokio services.
+///
+/// Records are pulled from the stream one at a time and applied before the next is requested, so
+/// a producer feeding the stream through a bounded channel, such as a
+/// [`tokio::sync::mpsc::channel`] wrapped in a `tokio_stream::wrappers::ReceiverStream`, waits
+/// whenever the engine falls behind.
+///
+/// Each record is applied on tokio's blocking thread pool, as it may sync the write-ahead log to
+/// disk and call subscribers, so the stream's task never blocks an executor thread.
+///
+/// The Processor is shared, so clones of a StreamingProcessor can read balances while another
+/// task runs a stream. The lock is only held while a single record is applied or balances are
+/// copied, never across an await point, though a read waits for the record being applied.
+#[derive(Debug, Clone, Default)]
+pub struct StreamingProcessor {
+    inner: Arc<Mutex<Processor>>,
+}
+
+impl StreamingProcessor {
+    ///Wraps a processor, keeping its clients, ledger, policy, subscribers and write-ahead log.
+    /// As streams run for as long as the service, daily volumes of rules are counted by UTC date
+    /// unless the processor was given a clock of its own.
+    pub fn new(mut processor: Processor) -> Self {
+        if !processor.has_clock() {
+            processor.set_clock(utc_day);
+        }
+        Self {
+            inner: Arc::new(Mutex::new(processor)),
+        }
+    }
+
+    ///Applies every transaction from a stream until it ends, following the same rules as
+    /// [`Processor::handle_transactions`]. Records are numbered from 1 in the order they arrive,
+    /// and that number is the line reported in rejections and logged to a write-ahead log.
+    ///# Errors
+    /// If on_reject returns an error, or a record couldn't be written to the write-ahead log.
+    /// Records applied before the error are kept.
+    pub async fn run<S, F>(&self, mut stream: S, mut on_reject: F) -> Result<(), error::Error>
+    where
+        S: Stream<Item = Result<Transaction, error::Error>> + Unpin,
+        F: FnMut(Rejection) -> Result<(), error::Error>,
+    {
+        let mut line = 0;
+        while let Some(transaction) = stream.next().await {
+            line += 1;
+            trace!("[!] streamed record {}", line);
+            let inner = self.inner.clone();
+            let rejection = tokio::task::spawn_blocking(move || lock(&inner).handle_record(line, transaction))
+                .await
+                .expect("System error, applying a streamed record panicked")?;
+            if let Some(rejection) = rejection {
+                on_reject(rejection)?;
+            }
+        }
+        Ok(())
+    }
+
+    ///Returns a copy of a client's current balances.
+    pub fn client(&self, id: u16) -> Option<Client> {
+        self.lock().client(id).cloned()
+    }
+
+    ///Returns a copy of every client's current balances, in ascending client id order.
+    pub fn clients(&self) -> Vec<Client> {
+        let processor = self.lock();
+        let mut clients: Vec<Client> = processor.clients().clients.values().cloned().collect();
+        clients.sort_by_key(|client| client.client);
+        clients
+    }
+
+    ///Runs a closure with shared access to the processor, such as to take a snapshot.
+    /// Streams wait to apply their next record until it returns.
+    pub fn with_processor<T, F: FnOnce(&Processor) -> T>(&self, f: F) -> T {
+        f(&self.lock())
+    }
+
+    ///Returns the processor once every other clone has been dropped, or the streaming
+    /// processor unchanged if any remain.
+    pub fn into_inner(self) -> Result<Processor, Self> {
+        match Arc::try_unwrap(self.inner) {
+            Ok(lock) => Ok(lock.into_inner().unwrap_or_else(|poisoned| poisoned.into_inner())),
+            Err(inner) => Err(Self { inner }),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Processor> {
+        lock(&self.inner)
+    }
+}
+
+fn lock(inner: &Mutex<Processor>) -> MutexGuard<'_, Processor> {
+    inner.lock().expect("System error, the processor lock was poisoned")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::RejectReason;
+    use crate::processor::Processor;
+    use crate::streaming::StreamingProcessor;
+    use crate::transaction::DEFAULT_CURRENCY;
+    use crate::transaction_feed::TransactionFeed;
+    use rust_decimal_macros::dec;
+    use tokio::sync::mpsc;
+
+    const INPUT: &str = "type,client,tx,amount\n\
+                         deposit,1,1,2.0\n\
+                         withdrawal,1,2,5.0\n\
+                         deposit,2,3,1.0\n\
+                         deposit,x,4,1.0\n\
+                         dispute,1,1,\n\
+                         deposit,1,3,1.0\n";
+
+    fn feed() -> TransactionFeed {
+        TransactionFeed::from_reader(String::from("test"), Box::new(INPUT.as_bytes())).unwrap()
+    }
+
+    #[tokio::test]
+    async fn rejections_match_serial() {
+        let mut expected_rejections = Vec::new();
+        let mut expected = Processor::new();
+        expected
+            .handle_transactions(feed(), |rejection| {
+                expected_rejections.push(rejection);
+                Ok(())
+            })
+            .unwrap();
+
+        let streaming = StreamingProcessor::default();
+        let mut rejections = Vec::new();
+        streaming
+            .run(tokio_stream::iter(feed()), |rejection| {
+                rejections.push(rejection);
+                Ok(())
+            })
+            .await
+            .unwrap();
+
+        //Streamed records are numbered without the csv header line
+        assert_eq!(rejections.iter().map(|rejection| rejection.line).collect::<Vec<_>>(), vec![2, 4, 6]);
+        for (rejection, expected) in rejections.iter_mut().zip(expected_rejections) {
+            rejection.line += 1;
+            assert_eq!(*rejection, expected);
+        }
+        let reasons: Vec<RejectReason> = rejections.iter().map(|rejection| rejection.reason).collect();
+        assert_eq!(
+            reasons,
+            vec![
+                RejectReason::InsufficientFunds,
+                RejectReason::Malformed,
+                RejectReason::DuplicateTransaction
+            ]
+        );
+        assert_eq!(streaming.into_inner().unwrap().snapshot(), expected.snapshot());
+    }
+
+    #[tokio::test]
+    async fn balances_are_readable_while_streaming() {
+        let streaming = StreamingProcessor::default();
+        let (sender, receiver) = mpsc::channel(1);
+        let engine = streaming.clone();
+        let task = tokio::spawn(async move { engine.run(tokio_stream::wrappers::ReceiverStream::new(receiver), |_| Ok(())).await });
+
+        let mut transactions = feed();
+        sender.send(transactions.next().unwrap()).await.unwrap();
+        while streaming.client(1).is_none() {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(streaming.client(1).unwrap().balances(DEFAULT_CURRENCY).available(), dec!(2.0));
+        assert!(streaming.client(2).is_none());
+
+        for transaction in transactions {
+            sender.send(transaction).await.unwrap();
+        }
+        drop(sender);
+        task.await.unwrap().unwrap();
+
+        let clients = streaming.clients();
+        assert_eq!(clients.iter().map(|client| client.client).collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(clients[0].balances(DEFAULT_CURRENCY).available(), dec!(0.0));
+        assert_eq!(clients[0].balances(DEFAULT_CURRENCY).held(), dec!(2.0));
+        assert_eq!(streaming.with_processor(|processor| processor.ledger().journal().len()), 3);
+    }
+}
@@ -0,0 +1,25 @@
+use crate::rules::{Check, Rule, RuleAction};
+use crate::transaction::{DisputeState, Transaction, TxTypes};
+use rust_decimal::Decimal;
+
+///A settled transaction in the default currency, without a reason.
+pub(crate) fn transaction(tx_type: TxTypes, client: u16, tx_id: u32, amount: Option<Decimal>) -> Transaction {
+    Transaction {
+        tx_type,
+        client,
+        tx_id,
+        amount,
+        reason: None,
+        currency: None,
+        state: DisputeState::Settled,
+    }
+}
+
+///A named rule taking the given action when its check is broken.
+pub(crate) fn rule(name: &str, check: Check, action: RuleAction) -> Rule {
+    Rule {
+        name: String::from(name),
+        check,
+        action,
+    }
+}
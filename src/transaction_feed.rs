@@ -1,22 +1,123 @@
 use crate::error;
 use crate::transaction::Transaction;
-use csv::{DeserializeRecordsIntoIter, Trim};
+use csv::{Reader, ReaderBuilder, StringRecord, Trim};
+use log::{debug, trace};
+use serde_json::Value;
 use std::ffi::OsString;
-use std::fs::File;
+use std::io;
+use std::io::BufRead;
+use std::path::Path;
+use std::str::FromStr;
+
+///Any source of transactions the Processor can consume. A source is an iterator of parsed
+/// transactions, plus metadata describing where the transactions are coming from so errors
+/// can be traced back to the offending record.
+pub trait TransactionSource: Iterator<Item = Result<Transaction, error::Error>> {
+    ///Human readable name of the source, such as a file path.
+    fn name(&self) -> &str;
+    ///Position of the most recently read record within the source, such as a line number.
+    /// Returns 0 if no record has been read yet.
+    fn position(&self) -> u64;
+}
+
+impl<S: TransactionSource + ?Sized> TransactionSource for Box<S> {
+    fn name(&self) -> &str {
+        (**self).name()
+    }
+
+    fn position(&self) -> u64 {
+        (**self).position()
+    }
+}
+
+///Supported formats for transaction input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputFormat {
+    ///Comma separated values with a header row.
+    Csv,
+    ///One json object per line, also known as NDJSON.
+    JsonLines,
+}
+
+impl InputFormat {
+    ///Guesses the input format from a file extension. Anything other than .jsonl, .ndjson or
+    /// .json is treated as csv.
+    pub fn from_path(file_path: &Path) -> InputFormat {
+        match file_path.extension().and_then(|ext| ext.to_str()) {
+            Some("jsonl") | Some("ndjson") | Some("json") => InputFormat::JsonLines,
+            _ => InputFormat::Csv,
+        }
+    }
+}
+
+impl FromStr for InputFormat {
+    type Err = error::Error;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "csv" => Ok(InputFormat::Csv),
+            //Accepted as .json files are read as json lines too
+            "jsonl" | "ndjson" | "json" => Ok(InputFormat::JsonLines),
+            _ => Err(error::Error::InvalidArgument(format!("Unknown input format: {}", format))),
+        }
+    }
+}
+
+///Opens a transaction source for the given path, or stdin if no path or "-" is given. If no
+/// format is given, it is guessed from the file extension, and stdin defaults to csv.
+pub fn open_transaction_source(file_path: Option<OsString>, format: Option<InputFormat>) -> Result<Box<dyn TransactionSource>, error::Error> {
+    match file_path {
+        Some(file_path) if file_path != "-" => {
+            let format = format.unwrap_or_else(|| InputFormat::from_path(Path::new(&file_path)));
+            debug!("Reading transactions from {:?} as {:?}", file_path, format);
+            match format {
+                InputFormat::Csv => Ok(Box::new(TransactionFeed::new(file_path)?)),
+                InputFormat::JsonLines => Ok(Box::new(JsonLinesFeed::new(file_path)?)),
+            }
+        }
+        _ => {
+            let format = format.unwrap_or(InputFormat::Csv);
+            debug!("No input file given, reading transactions from stdin as {:?}", format);
+            let name = String::from("stdin");
+            match format {
+                InputFormat::Csv => Ok(Box::new(TransactionFeed::from_reader(name, Box::new(io::stdin()))?)),
+                InputFormat::JsonLines => Ok(Box::new(JsonLinesFeed::from_reader(name, Box::new(io::stdin())))),
+            }
+        }
+    }
+}
 
 ///Provides a feed of transactions transparent to the user by implementing the Iterator trait.
-/// Holds ownership of csv's iterator for subsequent method calls.
+/// Holds ownership of csv's reader and the headers used to deserialize each record. Any reader
+/// may back the feed, such as a file or stdin.
 pub struct TransactionFeed {
-    iter: DeserializeRecordsIntoIter<File, Transaction>,
+    name: String,
+    rdr: Reader<Box<dyn io::Read>>,
+    headers: StringRecord,
+    record: StringRecord,
+    line: u64,
 }
 
 impl TransactionFeed {
     ///Reads csv's from a file path trimming all whitespace and ignoring missing or extra fields.
-    /// Takes ownership of the csv iterator.
+    /// Takes ownership of the csv reader.
     pub fn new(file_path: OsString) -> Result<TransactionFeed, error::Error> {
-        let rdr = csv::ReaderBuilder::new().flexible(true).trim(Trim::All).from_path(file_path)?;
+        let name = file_path.to_string_lossy().into_owned();
+        let file = std::fs::File::open(file_path)?;
+        Self::from_reader(name, Box::new(file))
+    }
+
+    ///Reads csv's from any reader with the same trimming and flexibility rules as a file.
+    /// The name is used to identify the source in logs.
+    pub fn from_reader(name: String, reader: Box<dyn io::Read>) -> Result<TransactionFeed, error::Error> {
+        let mut rdr = ReaderBuilder::new().flexible(true).trim(Trim::All).from_reader(reader);
+        let headers = rdr.headers()?.clone();
         Ok(Self {
-            iter: rdr.into_deserialize(),
+            name,
+            rdr,
+            headers,
+            record: StringRecord::new(),
+            line: 0,
         })
     }
 }
@@ -25,6 +126,244 @@ impl Iterator for TransactionFeed {
     type Item = Result<Transaction, error::Error>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.iter.next().map(|e| e.map_err(error::Error::Csv))
+        match self.rdr.read_record(&mut self.record) {
+            Err(err) => Some(Err(error::Error::Csv(err))),
+            Ok(false) => None,
+            Ok(true) => {
+                if let Some(position) = self.record.position() {
+                    self.line = position.line();
+                }
+                Some(self.record.deserialize(Some(&self.headers)).map_err(error::Error::Csv))
+            }
+        }
+    }
+}
+
+impl TransactionSource for TransactionFeed {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn position(&self) -> u64 {
+        self.line
+    }
+}
+
+///Provides a feed of transactions from json lines, one transaction object per line. Follows the
+/// same rules as the csv feed: keys and string values are trimmed of whitespace, extra fields
+/// are ignored, and blank lines are skipped. Numbers are read with full precision.
+pub struct JsonLinesFeed {
+    name: String,
+    rdr: io::BufReader<Box<dyn io::Read>>,
+    buf: String,
+    line: u64,
+}
+
+impl JsonLinesFeed {
+    ///Reads json lines from a file path.
+    pub fn new(file_path: OsString) -> Result<JsonLinesFeed, error::Error> {
+        let name = file_path.to_string_lossy().into_owned();
+        let file = std::fs::File::open(file_path)?;
+        Ok(Self::from_reader(name, Box::new(file)))
+    }
+
+    ///Reads json lines from any reader. The name is used to identify the source in logs.
+    pub fn from_reader(name: String, reader: Box<dyn io::Read>) -> JsonLinesFeed {
+        Self {
+            name,
+            rdr: io::BufReader::new(reader),
+            buf: String::new(),
+            line: 0,
+        }
+    }
+
+    ///Trims keys and string values, and passes amounts through as their original text so they
+    /// keep full precision rather than being read as floats.
+    fn normalize(value: Value) -> Value {
+        match value {
+            Value::Object(map) => Value::Object(
+                map.into_iter()
+                    .map(|(key, value)| {
+                        let key = key.trim().to_owned();
+                        match value {
+                            Value::String(s) => (key, Value::String(s.trim().to_owned())),
+                            Value::Number(n) if key == "amount" => (key, Value::String(n.to_string())),
+                            _ => (key, value),
+                        }
+                    })
+                    .collect(),
+            ),
+            _ => value,
+        }
+    }
+}
+
+impl Iterator for JsonLinesFeed {
+    type Item = Result<Transaction, error::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            self.buf.clear();
+            match self.rdr.read_line(&mut self.buf) {
+                Err(err) => return Some(Err(error::Error::Io(err))),
+                Ok(0) => return None,
+                Ok(_) => {
+                    self.line += 1;
+                    if self.buf.trim().is_empty() {
+                        trace!("Skipping blank line {} of {}", self.line, self.name);
+                        continue;
+                    }
+                    let transaction = serde_json::from_str(&self.buf)
+                        .and_then(|value| serde_json::from_value(Self::normalize(value)))
+                        .map_err(error::Error::Json);
+                    return Some(transaction);
+                }
+            }
+        }
+    }
+}
+
+impl TransactionSource for JsonLinesFeed {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn position(&self) -> u64 {
+        self.line
+    }
+}
+
+///Skips records of a source up to and including an offset, so processing can resume after the
+/// last record already applied, such as one recovered from a write-ahead log. Records that can't
+/// be read are never logged, so those before the offset are passed on to be reported again.
+pub struct ResumeFrom<S: TransactionSource> {
+    source: S,
+    offset: u64,
+}
+
+impl<S: TransactionSource> ResumeFrom<S> {
+    ///Wraps a source, skipping every record at or before the offset.
+    pub fn new(source: S, offset: u64) -> Self {
+        Self { source, offset }
+    }
+}
+
+impl<S: TransactionSource> Iterator for ResumeFrom<S> {
+    type Item = Result<Transaction, error::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let transaction = self.source.next()?;
+            if self.source.position() > self.offset || transaction.is_err() {
+                return Some(transaction);
+            }
+            trace!("Skipping record {} of {}, already applied", self.source.position(), self.source.name());
+        }
+    }
+}
+
+impl<S: TransactionSource> TransactionSource for ResumeFrom<S> {
+    fn name(&self) -> &str {
+        self.source.name()
+    }
+
+    fn position(&self) -> u64 {
+        self.source.position()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::transaction_feed::{InputFormat, JsonLinesFeed, ResumeFrom, TransactionFeed, TransactionSource};
+    use rust_decimal::Decimal;
+    use std::ffi::OsString;
+    use std::path::Path;
+    use std::str::FromStr;
+
+    #[test]
+    fn feed_reports_name_and_position() {
+        let mut feed = TransactionFeed::new(OsString::from("test-inputs/spaces-test.csv")).unwrap();
+        assert_eq!(feed.name(), "test-inputs/spaces-test.csv");
+        assert_eq!(feed.position(), 0);
+        assert!(feed.next().unwrap().is_ok());
+        assert_eq!(feed.position(), 2);
+    }
+
+    #[test]
+    fn feed_reads_all_records() {
+        let feed = TransactionFeed::new(OsString::from("test-inputs/spaces-test.csv")).unwrap();
+        assert_eq!(feed.filter(|tx| tx.is_ok()).count(), 5);
+    }
+
+    #[test]
+    fn feed_from_reader() {
+        let input = "type,client,tx,amount\ndeposit,1,1,1.0\ndispute,1,1,\n";
+        let feed = TransactionFeed::from_reader(String::from("test"), Box::new(input.as_bytes())).unwrap();
+        assert_eq!(feed.name(), "test");
+        assert_eq!(feed.filter(|tx| tx.is_ok()).count(), 2);
+    }
+
+    #[test]
+    fn resume_skips_applied_records() {
+        let feed = TransactionFeed::new(OsString::from("test-inputs/spaces-test.csv")).unwrap();
+        let mut feed = ResumeFrom::new(feed, 3);
+        let tx = feed.next().unwrap().unwrap();
+        assert_eq!((tx.tx_id, feed.position()), (3, 4));
+        assert_eq!(feed.count(), 2);
+    }
+
+    #[test]
+    fn resume_passes_on_unreadable_records() {
+        let input = "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,x,2,1.0\ndeposit,1,3,1.0\n";
+        let feed = TransactionFeed::from_reader(String::from("test"), Box::new(input.as_bytes())).unwrap();
+        let mut feed = ResumeFrom::new(feed, 3);
+        assert!(feed.next().unwrap().is_err());
+        assert_eq!(feed.position(), 3);
+        assert_eq!(feed.next().unwrap().unwrap().tx_id, 3);
+    }
+
+    #[test]
+    fn json_lines_keep_precision_and_trim() {
+        let input = "{\"type\": \" deposit \", \"client\": 1, \"tx\": 1, \"amount\": 1.123456789012345678}\n\n\
+                     {\"type\": \"dispute\", \"client\": 1, \"tx\": 1, \"extra\": true}\n";
+        let mut feed = JsonLinesFeed::from_reader(String::from("test"), Box::new(input.as_bytes()));
+        let deposit = feed.next().unwrap().unwrap();
+        assert_eq!(deposit.amount, Some(Decimal::from_str("1.123456789012345678").unwrap()));
+        assert_eq!(feed.position(), 1);
+        let dispute = feed.next().unwrap().unwrap();
+        assert_eq!(dispute.amount, None);
+        assert_eq!(feed.position(), 3);
+        assert!(feed.next().is_none());
+    }
+
+    #[test]
+    fn json_lines_string_amount() {
+        let input = "{\"type\": \"withdrawal\", \"client\": 2, \"tx\": 5, \"amount\": \"3.0\"}";
+        let mut feed = JsonLinesFeed::from_reader(String::from("test"), Box::new(input.as_bytes()));
+        assert_eq!(feed.next().unwrap().unwrap().amount, Some(Decimal::new(30, 1)));
+    }
+
+    #[test]
+    fn json_lines_invalid_record() {
+        let input = "{\"type\": \"transfer\", \"client\": 1, \"tx\": 1}\nnot json\n";
+        let feed = JsonLinesFeed::from_reader(String::from("test"), Box::new(input.as_bytes()));
+        assert_eq!(feed.filter(|tx| tx.is_err()).count(), 2);
+    }
+
+    #[test]
+    fn input_format_from_path() {
+        assert_eq!(InputFormat::from_path(Path::new("input.jsonl")), InputFormat::JsonLines);
+        assert_eq!(InputFormat::from_path(Path::new("input.ndjson")), InputFormat::JsonLines);
+        assert_eq!(InputFormat::from_path(Path::new("input.json")), InputFormat::JsonLines);
+        assert_eq!(InputFormat::from_path(Path::new("input.csv")), InputFormat::Csv);
+        assert_eq!(InputFormat::from_path(Path::new("stdin")), InputFormat::Csv);
+    }
+
+    #[test]
+    fn input_format_from_str() {
+        assert_eq!(InputFormat::from_str("jsonl").unwrap(), InputFormat::JsonLines);
+        assert_eq!(InputFormat::from_str("json").unwrap(), InputFormat::JsonLines);
+        assert_eq!(InputFormat::from_str("csv").unwrap(), InputFormat::Csv);
+        assert!(InputFormat::from_str("xml").is_err());
     }
 }
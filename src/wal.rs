@@ -0,0 +1,224 @@
+use crate::error;
+use crate::transaction::{DisputeState, Transaction, TxTypes};
+use log::{debug, warn};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+
+///A transaction recorded in the write-ahead log, along with its position in the input.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LogRecord {
+    ///Position of the transaction in its source, such as a line number. None if it was applied
+    /// directly rather than read from a source, so it has no bearing on where input resumes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub offset: Option<u64>,
+    ///Type of transaction.
+    #[serde(rename = "type")]
+    pub tx_type: TxTypes,
+    ///Client id.
+    pub client: u16,
+    ///Transaction id.
+    pub tx: u32,
+    ///Amount, if any.
+    pub amount: Option<Decimal>,
+    ///Audit reason, if any.
+    pub reason: Option<String>,
+    ///Currency, if any.
+    #[serde(default)]
+    pub currency: Option<String>,
+}
+
+impl LogRecord {
+    ///Records a transaction at a position in its source.
+    pub fn new(offset: u64, transaction: &Transaction) -> Self {
+        Self {
+            offset: Some(offset),
+            ..Self::unsourced(transaction)
+        }
+    }
+
+    ///Records a transaction applied directly rather than read from a source.
+    pub fn unsourced(transaction: &Transaction) -> Self {
+        Self {
+            offset: None,
+            tx_type: transaction.tx_type,
+            client: transaction.client,
+            tx: transaction.tx_id,
+            amount: transaction.amount,
+            reason: transaction.reason.clone(),
+            currency: transaction.currency.clone(),
+        }
+    }
+
+    ///The recorded transaction, ready to be applied again.
+    pub fn into_transaction(self) -> Transaction {
+        Transaction {
+            tx_type: self.tx_type,
+            client: self.client,
+            tx_id: self.tx,
+            amount: self.amount,
+            reason: self.reason,
+            currency: self.currency,
+            state: DisputeState::Settled,
+        }
+    }
+}
+
+///Append-only log of every transaction handed to the Processor, written and synced to disk
+/// before the transaction touches any client. If the process dies, replaying the log through
+/// the same rules rebuilds the exact state at the time of the crash, and the offset of the last
+/// record tells where in the input to resume. The log is one json record per line.
+#[derive(Debug)]
+pub struct WriteAheadLog {
+    path: PathBuf,
+    file: io::BufWriter<fs::File>,
+}
+
+impl WriteAheadLog {
+    ///Opens the log at the path for appending, creating it if needed, and returns any records
+    /// left by a previous run that didn't finish. A partially written final record, left by a
+    /// crash mid-write, is discarded and truncated from the file. Any other unreadable record is
+    /// an error, as the log can't be trusted.
+    pub fn open(file_path: &Path) -> Result<(WriteAheadLog, Vec<LogRecord>), error::Error> {
+        let (records, valid_len) = match fs::File::open(file_path) {
+            Ok(file) => Self::read_records(io::BufReader::new(file))?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => (Vec::new(), 0),
+            Err(err) => return Err(error::Error::Io(err)),
+        };
+        let file = fs::OpenOptions::new().create(true).append(true).open(file_path)?;
+        file.set_len(valid_len)?;
+        debug!("Opened write-ahead log {:?} with {} records to recover", file_path, records.len());
+        Ok((
+            Self {
+                path: file_path.to_path_buf(),
+                file: io::BufWriter::new(file),
+            },
+            records,
+        ))
+    }
+
+    fn read_records<R: BufRead>(mut reader: R) -> Result<(Vec<LogRecord>, u64), error::Error> {
+        let mut records = Vec::new();
+        let mut valid_len = 0;
+        let mut line = 0;
+        let mut buf = String::new();
+        loop {
+            buf.clear();
+            let read = reader.read_line(&mut buf)?;
+            if read == 0 {
+                break;
+            }
+            line += 1;
+            match serde_json::from_str(&buf) {
+                Ok(record) if buf.ends_with('\n') => {
+                    records.push(record);
+                    valid_len += read as u64;
+                }
+                _ => {
+                    //Only the last record can be torn, as every record is synced before the next
+                    if reader.fill_buf()?.is_empty() {
+                        warn!("Discarding partially written record at line {} of the write-ahead log", line);
+                        break;
+                    }
+                    return Err(error::Error::CorruptLog { line });
+                }
+            }
+        }
+        Ok((records, valid_len))
+    }
+
+    ///Records a transaction, syncing it to disk before returning.
+    pub fn append(&mut self, record: &LogRecord) -> Result<(), error::Error> {
+        serde_json::to_writer(&mut self.file, record)?;
+        self.file.write_all(b"\n")?;
+        self.file.flush()?;
+        self.file.get_ref().sync_data()?;
+        Ok(())
+    }
+
+    ///Removes the log once a run has completed and its results are safely written, so the next
+    /// run starts fresh.
+    pub fn remove(self) -> Result<(), error::Error> {
+        drop(self.file);
+        debug!("Removing write-ahead log {:?}", self.path);
+        fs::remove_file(&self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error;
+    use crate::test_support::transaction;
+    use crate::transaction::TxTypes;
+    use crate::wal::{LogRecord, WriteAheadLog};
+    use rust_decimal::Decimal;
+    use std::fs;
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    fn log_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("transaction-processor-wal-{}-{}.jsonl", name, std::process::id()))
+    }
+
+    #[test]
+    fn records_survive_reopening() {
+        let file_path = log_path("reopen");
+        let (mut wal, records) = WriteAheadLog::open(&file_path).unwrap();
+        assert!(records.is_empty());
+        wal.append(&LogRecord::new(2, &transaction(TxTypes::Deposit, 1, 1, Some(Decimal::new(15, 1)))))
+            .unwrap();
+        wal.append(&LogRecord::new(3, &transaction(TxTypes::Deposit, 1, 2, Some(Decimal::new(15, 1)))))
+            .unwrap();
+        drop(wal);
+
+        let (wal, records) = WriteAheadLog::open(&file_path).unwrap();
+        assert_eq!(
+            records.iter().map(|record| record.offset).collect::<Vec<Option<u64>>>(),
+            vec![Some(2), Some(3)]
+        );
+        assert_eq!(records[1].clone().into_transaction().amount, Some(Decimal::new(15, 1)));
+        wal.remove().unwrap();
+        assert!(!file_path.exists());
+    }
+
+    #[test]
+    fn torn_record_is_discarded() {
+        let file_path = log_path("torn");
+        let (mut wal, _) = WriteAheadLog::open(&file_path).unwrap();
+        wal.append(&LogRecord::new(2, &transaction(TxTypes::Deposit, 1, 1, Some(Decimal::new(15, 1)))))
+            .unwrap();
+        drop(wal);
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&file_path)
+            .unwrap()
+            .write_all(b"{\"offset\":3,\"ty")
+            .unwrap();
+
+        let (mut wal, records) = WriteAheadLog::open(&file_path).unwrap();
+        assert_eq!(records.len(), 1);
+        wal.append(&LogRecord::new(3, &transaction(TxTypes::Deposit, 1, 2, Some(Decimal::new(15, 1)))))
+            .unwrap();
+        drop(wal);
+        let (wal, records) = WriteAheadLog::open(&file_path).unwrap();
+        assert_eq!(records.len(), 2);
+        wal.remove().unwrap();
+    }
+
+    #[test]
+    fn corrupt_record_is_an_error() {
+        let file_path = log_path("corrupt");
+        fs::write(
+            &file_path,
+            "not json\n{\"offset\":2,\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":\"1\",\"reason\":null}\n",
+        )
+        .unwrap();
+        let result = WriteAheadLog::open(&file_path);
+        assert!(matches!(result, Err(error::Error::CorruptLog { line: 1 })));
+        fs::remove_file(&file_path).unwrap();
+    }
+}
@@ -1,14 +1,262 @@
+use crate::client_repo::ClientOrder;
+use crate::models::client::{Balances, Client};
+use crate::transaction::DEFAULT_CURRENCY;
 use crate::{error, ClientRepo};
-use log::trace;
+use log::{debug, trace};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::ffi::OsString;
+use std::fs;
 use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
 
-/// Creates a writer to serialize all Client data to csv and output to stdout.
-pub fn write_client_data(repo: &ClientRepo) -> Result<(), error::Error> {
-    let mut wtr = csv::Writer::from_writer(io::stdout());
-    for (client_id, client_record) in &repo.clients {
-        trace!("Writing record for client #{}", client_id);
-        wtr.serialize(client_record)?;
+///Supported formats for client balance output.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    ///Comma separated values with a header row.
+    #[default]
+    Csv,
+    ///A single json array of client objects.
+    Json,
+    ///One json client object per line, also known as NDJSON.
+    JsonLines,
+}
+
+impl FromStr for OutputFormat {
+    type Err = error::Error;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "csv" => Ok(OutputFormat::Csv),
+            "json" => Ok(OutputFormat::Json),
+            "jsonl" | "ndjson" => Ok(OutputFormat::JsonLines),
+            _ => Err(error::Error::InvalidArgument(format!("Unknown output format: {}", format))),
+        }
+    }
+}
+
+///A client's balances in one currency, written as one row of output.
+#[derive(Debug, Serialize)]
+struct ClientRow<'a> {
+    client: u16,
+    currency: &'a str,
+    available: Decimal,
+    held: Decimal,
+    total: Decimal,
+    locked: bool,
+    fees: Decimal,
+}
+
+impl<'a> ClientRow<'a> {
+    fn new(client: &Client, currency: &'a str, balances: &Balances) -> Self {
+        Self {
+            client: client.client,
+            currency,
+            available: balances.available(),
+            held: balances.held(),
+            total: balances.total(),
+            locked: client.locked,
+            fees: balances.fees(),
+        }
+    }
+}
+
+///Rows for every currency the client holds, in currency order. A client that has never held
+/// funds gets a single empty row in DEFAULT_CURRENCY so it still appears in the output.
+fn client_rows(client: &Client) -> Vec<ClientRow<'_>> {
+    let rows: Vec<ClientRow> = client
+        .currencies()
+        .map(|(currency, balances)| ClientRow::new(client, currency, balances))
+        .collect();
+    if rows.is_empty() {
+        vec![ClientRow::new(client, DEFAULT_CURRENCY, &Balances::default())]
+    } else {
+        rows
+    }
+}
+
+/// Creates a writer to serialize all Client data in the given format and order, and output to
+/// any writer such as stdout. Each client is written as one row per currency, in currency order.
+/// Json amounts are written as strings so no precision is lost to floats.
+pub fn write_client_data<W: io::Write>(repo: &ClientRepo, format: OutputFormat, order: ClientOrder, out: W) -> Result<(), error::Error> {
+    let rows = repo
+        .ordered_clients(order)
+        .into_iter()
+        .inspect(|client_record| trace!("Writing record for client #{}", client_record.client))
+        .flat_map(client_rows);
+    write_rows(rows, format, out)
+}
+
+///Serializes a single client in the given format, with the same rows write_client_data writes
+/// for it.
+pub fn write_client<W: io::Write>(client: &Client, format: OutputFormat, out: W) -> Result<(), error::Error> {
+    write_rows(client_rows(client).into_iter(), format, out)
+}
+
+fn write_rows<'a, W: io::Write>(rows: impl Iterator<Item = ClientRow<'a>>, format: OutputFormat, out: W) -> Result<(), error::Error> {
+    match format {
+        OutputFormat::Csv => write_csv(rows, out),
+        OutputFormat::Json => write_json(rows, out),
+        OutputFormat::JsonLines => write_json_lines(rows, out),
+    }
+}
+
+/// Writes all Client data to a file atomically. Data is written to a temporary file beside the
+/// destination, synced to disk, and then renamed over the destination, so a crashed run never
+/// leaves a partially written file behind. The temporary file is removed if writing fails.
+pub fn write_client_data_to_path(repo: &ClientRepo, format: OutputFormat, order: ClientOrder, file_path: &Path) -> Result<(), error::Error> {
+    debug!("Writing client data to {:?}", file_path);
+    write_atomically(file_path, |out| write_client_data(repo, format, order, out))
+}
+
+///Writes a file atomically through a temporary file, as described in write_client_data_to_path.
+pub(crate) fn write_atomically<F>(file_path: &Path, write: F) -> Result<(), error::Error>
+where
+    F: FnOnce(&mut io::BufWriter<fs::File>) -> Result<(), error::Error>,
+{
+    let temp_path = temp_path_for(file_path);
+    trace!("Writing {:?} via {:?}", file_path, temp_path);
+    let result = fs::File::create(&temp_path).map_err(error::Error::Io).and_then(|file| {
+        let mut out = io::BufWriter::new(file);
+        write(&mut out)?;
+        let file = out.into_inner().map_err(|err| error::Error::Io(err.into_error()))?;
+        file.sync_all()?;
+        fs::rename(&temp_path, file_path)?;
+        Ok(())
+    });
+    if result.is_err() {
+        //Best effort clean up, the original error is the one worth reporting.
+        let _ = fs::remove_file(&temp_path);
+    }
+    result
+}
+
+///Temporary files live in the destination's directory so the final rename never crosses filesystems.
+fn temp_path_for(file_path: &Path) -> PathBuf {
+    let mut file_name = file_path.file_name().map(OsString::from).unwrap_or_default();
+    file_name.push(format!(".{}.tmp", process::id()));
+    file_path.with_file_name(file_name)
+}
+
+fn write_csv<'a, W: io::Write>(clients: impl Iterator<Item = ClientRow<'a>>, out: W) -> Result<(), error::Error> {
+    let mut wtr = csv::Writer::from_writer(out);
+    for client_record in clients {
+        wtr.serialize(&client_record)?;
     }
     wtr.flush()?;
     Ok(())
 }
+
+fn write_json<'a, W: io::Write>(clients: impl Iterator<Item = ClientRow<'a>>, mut out: W) -> Result<(), error::Error> {
+    let clients: Vec<ClientRow> = clients.collect();
+    serde_json::to_writer(&mut out, &clients)?;
+    writeln!(out)?;
+    out.flush()?;
+    Ok(())
+}
+
+fn write_json_lines<'a, W: io::Write>(clients: impl Iterator<Item = ClientRow<'a>>, mut out: W) -> Result<(), error::Error> {
+    for client_record in clients {
+        serde_json::to_writer(&mut out, &client_record)?;
+        writeln!(out)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::client_repo::ClientOrder;
+    use crate::models::client::Client;
+    use crate::transaction::DEFAULT_CURRENCY as USD;
+    use crate::writer::{client_rows, temp_path_for, write_client_data_to_path, write_csv, write_json, write_json_lines, OutputFormat};
+    use crate::ClientRepo;
+    use rust_decimal::Decimal;
+    use std::fs;
+    use std::path::Path;
+    use std::str::FromStr;
+
+    fn clients() -> Vec<Client> {
+        let mut first = Client::new(1);
+        first.deposit(USD, Decimal::new(15000, 4));
+        let mut second = Client::new(2);
+        second.deposit(USD, Decimal::new(2, 0));
+        vec![first, second]
+    }
+
+    #[test]
+    fn csv_output() {
+        let mut out = Vec::new();
+        write_csv(clients().iter().flat_map(client_rows), &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "client,currency,available,held,total,locked,fees\n1,USD,1.5000,0,1.5000,false,0\n2,USD,2,0,2,false,0\n"
+        );
+    }
+
+    #[test]
+    fn json_output() {
+        let mut out = Vec::new();
+        write_json(clients().iter().flat_map(client_rows), &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "[{\"client\":1,\"currency\":\"USD\",\"available\":\"1.5000\",\"held\":\"0\",\"total\":\"1.5000\",\"locked\":false,\"fees\":\"0\"},\
+             {\"client\":2,\"currency\":\"USD\",\"available\":\"2\",\"held\":\"0\",\"total\":\"2\",\"locked\":false,\"fees\":\"0\"}]\n"
+        );
+    }
+
+    #[test]
+    fn json_lines_output() {
+        let mut out = Vec::new();
+        write_json_lines(clients().iter().flat_map(client_rows), &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "{\"client\":1,\"currency\":\"USD\",\"available\":\"1.5000\",\"held\":\"0\",\"total\":\"1.5000\",\"locked\":false,\"fees\":\"0\"}\n\
+             {\"client\":2,\"currency\":\"USD\",\"available\":\"2\",\"held\":\"0\",\"total\":\"2\",\"locked\":false,\"fees\":\"0\"}\n"
+        );
+    }
+
+    #[test]
+    fn one_row_per_currency() {
+        let mut client = Client::new(1);
+        client.deposit("GBP", Decimal::new(3, 0));
+        client.deposit("EUR", Decimal::new(1, 0));
+        let mut out = Vec::new();
+        write_csv([client, Client::new(2)].iter().flat_map(client_rows), &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "client,currency,available,held,total,locked,fees\n1,EUR,1,0,1,false,0\n1,GBP,3,0,3,false,0\n2,USD,0,0,0,false,0\n"
+        );
+    }
+
+    #[test]
+    fn output_format_from_str() {
+        assert_eq!(OutputFormat::from_str("json").unwrap(), OutputFormat::Json);
+        assert_eq!(OutputFormat::from_str("ndjson").unwrap(), OutputFormat::JsonLines);
+        assert!(OutputFormat::from_str("xml").is_err());
+    }
+
+    #[test]
+    fn write_to_path() {
+        let mut repo = ClientRepo::new();
+        repo.get_client(1).unwrap().deposit(USD, Decimal::new(2, 0));
+        let file_path = std::env::temp_dir().join(format!("transaction-processor-writer-{}.csv", std::process::id()));
+        write_client_data_to_path(&repo, OutputFormat::Csv, ClientOrder::Ascending, &file_path).unwrap();
+        assert_eq!(
+            fs::read_to_string(&file_path).unwrap(),
+            "client,currency,available,held,total,locked,fees\n1,USD,2,0,2,false,0\n"
+        );
+        assert!(!temp_path_for(&file_path).exists());
+        fs::remove_file(&file_path).unwrap();
+    }
+
+    #[test]
+    fn write_to_missing_directory() {
+        let repo = ClientRepo::new();
+        let file_path = Path::new("no-such-directory/output.csv");
+        assert!(write_client_data_to_path(&repo, OutputFormat::Csv, ClientOrder::Ascending, file_path).is_err());
+        assert!(!temp_path_for(file_path).exists());
+    }
+}